@@ -0,0 +1,43 @@
+#!/usr/bin/env bash
+# Starts a bootstrap node and two nodes with mdns disabled that
+# only know the bootstrap node, passes once those two have found
+# and connected to each other through the dht.
+#
+#   cargo build && scripts/discovery.sh
+set -euo pipefail
+
+BIN=${ESSEX_BIN:-target/debug/blockchain}
+PORT=${ESSEX_BOOT_PORT:-47001}
+WORK=$(mktemp -d)
+export RUST_LOG=${RUST_LOG:-warn}
+
+# nodes flush their files on the way out, let them finish first
+trap 'kill $(jobs -p) 2>/dev/null || true; wait; rm -rf "$WORK"' EXIT
+
+boot_id=$("$BIN" --data-dir "$WORK/boot" node identity)
+a_id=$("$BIN" --data-dir "$WORK/a" node identity)
+b_id=$("$BIN" --data-dir "$WORK/b" node identity)
+boot_addr="/ip4/127.0.0.1/tcp/$PORT/p2p/$boot_id"
+
+"$BIN" --data-dir "$WORK/boot" node run --no-mdns \
+  --listen "/ip4/127.0.0.1/tcp/$PORT" > "$WORK/boot.log" 2>&1 < /dev/null &
+sleep 1
+for n in a b; do
+  "$BIN" --data-dir "$WORK/$n" node run --no-mdns \
+    --listen /ip4/127.0.0.1/tcp/0 --bootstrap "$boot_addr" > "$WORK/$n.log" 2>&1 < /dev/null &
+done
+
+for _ in $(seq 1 60); do
+  if grep -q "connected to peer $b_id" "$WORK/a.log" && grep -q "connected to peer $a_id" "$WORK/b.log"; then
+    echo "ok: $a_id and $b_id connected through $boot_id"
+    exit 0
+  fi
+  sleep 1
+done
+
+echo "nodes did not find each other"
+for n in boot a b; do
+  echo "--- $n"
+  tail -n 20 "$WORK/$n.log"
+done
+exit 1
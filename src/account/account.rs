@@ -1,15 +1,57 @@
 use anyhow::{Error, Ok, Result};
 use rand::rngs::OsRng;
-use secp256k1::{ecdsa::Signature, hashes::sha256, Message, PublicKey, SecretKey};
+use secp256k1::hashes::{sha256, Hash, HashEngine};
+use secp256k1::{ecdsa::Signature, Message, PublicKey, SecretKey};
+use std::fmt;
+
+use crate::address::address::Address;
+
+// signing domains, a signature made for one
+// of them never verifies under another one
+pub const MESSAGE_DOMAIN: &str = "Essex Signed Message";
+pub const BLOCK_DOMAIN: &str = "Essex Block";
+pub const TX_DOMAIN: &str = "Essex Transaction";
+pub const ATTESTATION_DOMAIN: &str = "Essex Validator Attestation";
 
-#[derive(Debug)]
 pub struct Account {
     pub acc_private: SecretKey,
     pub acc_public: PublicKey,
-    pub acc_signed: Signature,
     pub acc_balance: u32,
 }
 
+/// Digest every essex signature is made over:
+/// sha256(len(domain) || domain || len(payload) || payload)
+/// with lengths as big endian u64, prefixing the lengths
+/// keeps domain and payload from running into each other
+pub fn signing_digest(domain: &str, payload: &[u8]) -> Message {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&(domain.len() as u64).to_be_bytes());
+    engine.input(domain.as_bytes());
+    engine.input(&(payload.len() as u64).to_be_bytes());
+    engine.input(payload);
+    let digest = sha256::Hash::from_engine(engine);
+    Message::from_digest(digest.to_byte_array())
+}
+
+// the secret key is never printed, accounts end up
+// in logs through Debug on transactions and blocks
+impl fmt::Debug for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Account")
+            .field("acc_private", &"<redacted>")
+            .field("acc_public", &self.acc_public)
+            .field("acc_balance", &self.acc_balance)
+            .finish()
+    }
+}
+
+// wipe the secret key once the account goes away
+impl Drop for Account {
+    fn drop(&mut self) {
+        self.acc_private.non_secure_erase();
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ANode {
     pub values: Vec<i32>,
@@ -123,21 +165,83 @@ impl ANode {
 }
 
 impl Account {
-    // user should store the msg
-    // msg would be needed to create block
-    pub fn create(msg: &str) -> Result<Account, Error> {
+    pub fn address(&self) -> Address {
+        Address::from_public_key(&self.acc_public)
+    }
+
+    pub fn create() -> Result<Account, Error> {
+        let secp = secp256k1::Secp256k1::new();
+        let (secret, _) = secp.generate_keypair(&mut OsRng);
+        let new_acc = Account::from_secret(secret)?;
+        log::info!("new-acc created: {}", new_acc.address());
+        Ok(new_acc)
+    }
+
+    // restores an account from a key loaded
+    // out of the keystore or derived by a wallet
+    pub fn from_secret(secret: SecretKey) -> Result<Account, Error> {
         let secp = secp256k1::Secp256k1::new();
-        let (secret, public) = secp.generate_keypair(&mut OsRng);
-        log::info!("secret: {:?} public: {:?}", secret, public);
-        let mess = Message::from_hashed_data::<sha256::Hash>(msg.as_bytes());
-        let sig = secp.sign_ecdsa(&mess, &secret);
-        let new_acc = Account {
+        let public = PublicKey::from_secret_key(&secp, &secret);
+        Ok(Account {
             acc_private: secret,
             acc_public: public,
-            acc_signed: sig,
             acc_balance: 0,
-        };
-        log::info!("new-acc created: {:?}", new_acc);
-        Ok(new_acc)
+        })
+    }
+
+    // sign an arbitrary user message
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        self.sign_with_domain(MESSAGE_DOMAIN, message)
+    }
+
+    pub fn verify(message: &[u8], sig: &Signature, public: &PublicKey) -> Result<()> {
+        Account::verify_with_domain(MESSAGE_DOMAIN, message, sig, public)
+    }
+
+    // blocks and transactions sign their own payload
+    // under their own domain, see signing_digest
+    pub fn sign_with_domain(&self, domain: &str, payload: &[u8]) -> Signature {
+        let secp = secp256k1::Secp256k1::signing_only();
+        secp.sign_ecdsa(&signing_digest(domain, payload), &self.acc_private)
+    }
+
+    pub fn verify_with_domain(
+        domain: &str,
+        payload: &[u8],
+        sig: &Signature,
+        public: &PublicKey,
+    ) -> Result<()> {
+        let secp = secp256k1::Secp256k1::verification_only();
+        secp.verify_ecdsa(&signing_digest(domain, payload), sig, public)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_stay_in_their_domain() {
+        let acc = Account::create().unwrap();
+        let sig = acc.sign(b"hello");
+        Account::verify(b"hello", &sig, &acc.acc_public).unwrap();
+        assert!(Account::verify(b"hullo", &sig, &acc.acc_public).is_err());
+        assert!(Account::verify_with_domain(TX_DOMAIN, b"hello", &sig, &acc.acc_public).is_err());
+        let other = Account::create().unwrap();
+        assert!(Account::verify(b"hello", &sig, &other.acc_public).is_err());
+    }
+
+    #[test]
+    fn digest_lengths_keep_domain_and_payload_apart() {
+        assert_ne!(signing_digest("ab", b"c"), signing_digest("a", b"bc"));
+    }
+
+    #[test]
+    fn restoring_a_secret_gives_the_same_account() {
+        let acc = Account::create().unwrap();
+        let again = Account::from_secret(acc.acc_private).unwrap();
+        assert_eq!(again.address(), acc.address());
+        assert!(!format!("{:?}", acc).contains(&hex::encode(acc.acc_private.secret_bytes())));
     }
 }
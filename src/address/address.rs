@@ -0,0 +1,137 @@
+use anyhow::{bail, Result};
+use bech32::{primitives::decode::CheckedHrpstring, Bech32m, Hrp};
+use secp256k1::hashes::{hash160, Hash};
+use secp256k1::PublicKey;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+// human readable part every essex address starts with
+pub const ADDRESS_HRP: &str = "essex";
+pub const ADDRESS_LEN: usize = 20;
+
+/// Account identifier, hash160 of the compressed public
+/// key written as bech32m so a mistyped character is
+/// caught by the checksum instead of sending funds away
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Address([u8; ADDRESS_LEN]);
+
+impl Address {
+    pub fn from_public_key(public: &PublicKey) -> Address {
+        let digest = hash160::Hash::hash(&public.serialize());
+        Address(digest.to_byte_array())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Address> {
+        if bytes.len() != ADDRESS_LEN {
+            bail!("address must be {} bytes, got {}", ADDRESS_LEN, bytes.len());
+        }
+        let mut addr = [0u8; ADDRESS_LEN];
+        addr.copy_from_slice(bytes);
+        Ok(Address(addr))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; ADDRESS_LEN] {
+        &self.0
+    }
+
+    // true when `public` is the key behind this address
+    pub fn matches(&self, public: &PublicKey) -> bool {
+        Address::from_public_key(public) == *self
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hrp = Hrp::parse_unchecked(ADDRESS_HRP);
+        bech32::encode_lower_to_fmt::<Bech32m, _>(f, hrp, &self.0).map_err(|_| fmt::Error)
+    }
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Address({})", self)
+    }
+}
+
+impl FromStr for Address {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        // bech32 allows all upper case, we only ever write
+        // lower case so anything else is treated as a typo
+        if s.chars().any(|c| c.is_ascii_uppercase()) {
+            bail!("address must be lower case: {}", s);
+        }
+        let checked = CheckedHrpstring::new::<Bech32m>(s)
+            .map_err(|e| anyhow::anyhow!("invalid address {}: {}", s, e))?;
+        if checked.hrp().as_str() != ADDRESS_HRP {
+            bail!("address {} does not start with {}", s, ADDRESS_HRP);
+        }
+        let data: Vec<u8> = checked.byte_iter().collect();
+        Address::from_bytes(&data)
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Address::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Address {
+        Address::from_bytes(&[7u8; ADDRESS_LEN]).unwrap()
+    }
+
+    #[test]
+    fn round_trips_through_its_string_form() {
+        let addr = sample();
+        let text = addr.to_string();
+        assert!(text.starts_with("essex1"));
+        assert_eq!(text.parse::<Address>().unwrap(), addr);
+        let json = serde_json::to_string(&addr).unwrap();
+        assert_eq!(serde_json::from_str::<Address>(&json).unwrap(), addr);
+    }
+
+    #[test]
+    fn checksum_catches_a_mistyped_character() {
+        let text = sample().to_string();
+        for (i, c) in text.char_indices().skip(ADDRESS_HRP.len() + 1) {
+            let swapped = if c == 'q' { 'p' } else { 'q' };
+            let typo = format!("{}{}{}", &text[..i], swapped, &text[i + 1..]);
+            assert!(typo.parse::<Address>().is_err(), "accepted {}", typo);
+        }
+    }
+
+    #[test]
+    fn rejects_upper_case_other_prefixes_and_bad_lengths() {
+        let text = sample().to_string();
+        assert!(text.to_uppercase().parse::<Address>().is_err());
+        let hrp = Hrp::parse_unchecked("other");
+        let foreign = bech32::encode::<Bech32m>(hrp, &[7u8; ADDRESS_LEN]).unwrap();
+        assert!(foreign.parse::<Address>().is_err());
+        let short =
+            bech32::encode::<Bech32m>(Hrp::parse_unchecked(ADDRESS_HRP), &[7u8; 19]).unwrap();
+        assert!(short.parse::<Address>().is_err());
+        assert!(Address::from_bytes(&[0u8; 21]).is_err());
+    }
+
+    #[test]
+    fn belongs_to_one_public_key() {
+        let secp = secp256k1::Secp256k1::new();
+        let (_, public) = secp.generate_keypair(&mut rand::rngs::OsRng);
+        let (_, other) = secp.generate_keypair(&mut rand::rngs::OsRng);
+        let addr = Address::from_public_key(&public);
+        assert!(addr.matches(&public));
+        assert!(!addr.matches(&other));
+    }
+}
@@ -1,18 +1,20 @@
-use std::{
-    io::Write,
-    mem,
-    time::{Duration, SystemTime},
-};
+use std::{io::Write, mem};
 
-use anyhow::{Ok, Result};
+use anyhow::{bail, Ok, Result};
 
-use secp256k1::hashes::sha256;
-use secp256k1::{Message, Secp256k1};
+use secp256k1::hashes::{sha256, Hash};
+use secp256k1::{ecdsa::Signature, PublicKey};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    account::account::Account,
-    sec8::sec8::{self, sec8_block_id_hash},
+    account::account::{Account, BLOCK_DOMAIN},
+    address::address::Address,
+    chainspec::chainspec::ChainSpec,
+    codec::codec,
+    sec8::sec8::sec8_block_id_hash,
+    timestamp::timestamp::Timestamp,
+    transaction::transaction::BlockEntry,
+    weight::weight,
 };
 const VMAX: u32 = 30;
 
@@ -22,8 +24,13 @@ const VMAX: u32 = 30;
 /// Validate block data implementation
 pub trait _BlockT {
     fn new() -> Self;
-    fn create_essex_block(block: Block, acc: Account, msg: &str) -> Result<Block>;
-    fn validate_block(prevblock: Block) -> Result<bool>;
+    fn create_essex_block(
+        block: Block,
+        acc: Account,
+        pending: &mut Vec<BlockEntry>,
+        spec: &ChainSpec,
+    ) -> Result<Block>;
+    fn validate_block(prevblock: Block, spec: &ChainSpec) -> Result<bool>;
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,52 +40,131 @@ pub struct Block {
     // hash of the previous block
     pub prev_hash: String,
     // who validated this block ?
-    pub validator: String,
+    pub validator: Address,
+    // public key behind the validator address
+    #[serde(default)]
+    pub validator_key: String,
     // Block signature data 
     pub signature: String,
     // block information
-    pub block_data: Vec<String>,
+    pub block_data: Vec<BlockEntry>,
+    // position of the block in the chain
+    #[serde(default)]
+    pub height: u64,
     // is block valid ?
     pub valid: bool,
     // Block creation time
-    pub timestamp: SystemTime,
+    pub timestamp: Timestamp,
 }
  
+/// The part of a block the hash commits to and the
+/// validator signs, block_data is committed through
+/// data_root so a header is small to pass around
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub prev_hash: String,
+    pub validator: Address,
+    pub validator_key: String,
+    pub data_root: String,
+    pub height: u64,
+    pub timestamp: Timestamp,
+}
+
 impl Default for Block {
     fn default() -> Self {
+        // signed by a throwaway key, the first block of
+        // a chain is Block::genesis
         let sec8_ks = sec8_block_id_hash().unwrap();
-        let sec8_mess = Message::from_hashed_data::<sha256::Hash>("".as_bytes());
-        let sec8_sg = secp256k1::Secp256k1::sign_ecdsa(&Secp256k1::new(), &sec8_mess, &sec8_ks.0);
-        Block {
-            block_hash: sec8_ks.0.display_secret().to_string(),
-            prev_hash: sec8_ks.0.display_secret().to_string(),
+        let sec8_acc = Account::from_secret(sec8_ks.0).unwrap();
+        let mut block = Block {
+            block_hash: String::new(),
+            prev_hash: sha256::Hash::hash(&[]).to_string(),
             block_data: Vec::new(),
-            validator: sec8_ks.1.to_string(),
-            signature: sec8_sg.to_string(),
+            height: 0,
+            validator: Address::from_public_key(&sec8_ks.1),
+            validator_key: String::new(),
+            signature: String::new(),
             valid: false,
-            timestamp: SystemTime::now(),
-        }
-        .to_owned()
+            timestamp: Timestamp::now(),
+        };
+        block.sign(&sec8_acc);
+        block
     }
 }
 
-impl _BlockT for Block {
-    fn new() -> Self {
-        Block::default()
+impl Block {
+    // the first block of the chain `spec` describes, the
+    // same on every node, nobody signs it and its hash
+    // commits to the chain id
+    pub fn genesis(spec: &ChainSpec) -> Block {
+        let mut block = Block {
+            block_hash: String::new(),
+            prev_hash: sha256::Hash::hash(&[]).to_string(),
+            validator: Address::default(),
+            validator_key: String::new(),
+            signature: String::new(),
+            block_data: vec![BlockEntry::record(&spec.chain_id)],
+            height: 0,
+            valid: true,
+            timestamp: spec.genesis_timestamp,
+        };
+        block.block_hash = block.compute_hash();
+        block
+    }
+
+    pub fn is_genesis(&self, spec: &ChainSpec) -> bool {
+        self.height == 0
+            && self.block_hash == self.compute_hash()
+            && self.block_hash == Block::genesis(spec).block_hash
+    }
+
+    pub fn data_root(&self) -> String {
+        sha256::Hash::hash(&codec::to_bytes(&self.block_data)).to_string()
     }
 
-    // pass same message used in acc creation ...
-    fn create_essex_block(mut block: Block, acc: Account, msg: &str) -> Result<Block> {
-        // let's assume the genesis block was passed in the fn
-        let check_validated = Self::validate_block(mem::take(&mut block)).is_ok();
-        // by default the genesis block should pass this logic
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            prev_hash: self.prev_hash.clone(),
+            validator: self.validator,
+            validator_key: self.validator_key.clone(),
+            data_root: self.data_root(),
+            height: self.height,
+            timestamp: self.timestamp,
+        }
+    }
+
+    // canonical encoding of the header
+    pub fn signing_payload(&self) -> Vec<u8> {
+        codec::to_bytes(&self.header())
+    }
+
+    pub fn compute_hash(&self) -> String {
+        sha256::Hash::hash(&self.signing_payload()).to_string()
+    }
+
+    // binds the block to `acc`, fills in the hash and a
+    // fresh signature over the payload as it is now
+    pub fn sign(&mut self, acc: &Account) {
+        self.validator = acc.address();
+        self.validator_key = acc.acc_public.to_string();
+        self.block_hash = self.compute_hash();
+        self.signature = acc
+            .sign_with_domain(BLOCK_DOMAIN, &self.signing_payload())
+            .to_string();
+    }
+
+    // the block `acc` signs on top of `block`, taking
+    // what fits from `pending`
+    pub fn next(mut block: Block, acc: &Account, pending: &mut Vec<BlockEntry>, spec: &ChainSpec) -> Result<Block> {
+        let height = block.height + 1;
+        let prev_hash = block.block_hash.clone();
+        // blocks made within the same ms still move time on
+        let timestamp = Timestamp::now().max(block.timestamp.saturating_add(1));
+        // Block::genesis when the chain has just started
+        let check_validated = Self::validate_block(mem::take(&mut block), spec).unwrap_or(false);
         if !check_validated {
             log::error!("invalid previous block");
         }
-        // secp256k1 algorithm is to be used for signatures
-        let scp = secp256k1::Secp256k1::new();
-        // msg is same as the one user had during acc creation
-        let mex = Message::from_hashed_data::<sha256::Hash>(msg.as_bytes());
         let ubal = acc.acc_balance;
         // 0x1E min val a validator should have to create block
         let vok = ubal > VMAX;
@@ -86,20 +172,52 @@ impl _BlockT for Block {
         if !vok {
             log::error!("insufficient balance");
         }
-        let essex_secret = sec8::sec8_block_id_hash()
-            .unwrap()
-            .0
-            .display_secret()
-            .to_string();
-        let block = Block {
-            block_hash: essex_secret,
-            prev_hash: block.block_hash,
-            validator: acc.acc_public.to_string(),
-            signature: acc.acc_signed.to_string(),
-            block_data: vec![],
+        // fill the block up to the weight limit at this
+        // height, whatever doesn't fit stays pending
+        let block_data = weight::pack_block_data(pending, spec.max_block_weight(height));
+        let mut block = Block {
+            block_hash: String::new(),
+            prev_hash,
+            validator: acc.address(),
+            validator_key: String::new(),
+            signature: String::new(),
+            block_data,
+            height,
             valid: true,
-            timestamp: SystemTime::now(),
+            timestamp,
         };
+        // secp256k1 signature over the block payload
+        block.sign(acc);
+        // verification on the validator data
+        block.verify_signature()?;
+        Ok(block)
+    }
+
+    pub fn verify_signature(&self) -> Result<()> {
+        let public: PublicKey = self.validator_key.parse()?;
+        if !self.validator.matches(&public) {
+            bail!("validator key does not match {}", self.validator);
+        }
+        if self.block_hash != self.compute_hash() {
+            bail!("block hash does not match block contents");
+        }
+        let sig: Signature = self.signature.parse()?;
+        Account::verify_with_domain(BLOCK_DOMAIN, &self.signing_payload(), &sig, &public)
+    }
+}
+
+impl _BlockT for Block {
+    fn new() -> Self {
+        Block::default()
+    }
+
+    fn create_essex_block(
+        block: Block,
+        acc: Account,
+        pending: &mut Vec<BlockEntry>,
+        spec: &ChainSpec,
+    ) -> Result<Block> {
+        let block = Block::next(block, &acc, pending, spec)?;
         let data_store = std::fs::File::options().append(true).open("block.txt");
         match data_store {
             core::result::Result::Ok(mut data) => {
@@ -107,6 +225,7 @@ impl _BlockT for Block {
                 match serialise_block {
                     core::result::Result::Ok(resb) => {
                         data.write_all(resb.as_bytes())?;
+                        data.sync_data()?;
                         return Ok(block);
                     }
                     Err(e) => {
@@ -119,29 +238,87 @@ impl _BlockT for Block {
                 return Ok(block.clone());
             }
         }
-        // verification on the validator data
-        scp.verify_ecdsa(&mex, &acc.acc_signed, &acc.acc_public)
-            .unwrap();
         Ok(block)
     }
 
-    fn validate_block(prevblock: Block) -> Result<bool> {
+    fn validate_block(prevblock: Block, spec: &ChainSpec) -> Result<bool> {
+        if prevblock.height == 0 {
+            return Ok(prevblock.is_genesis(spec));
+        }
         let prev_valid = prevblock.valid;
         if prev_valid {
-            // if previous block is valid get creation time
-            let block_creation_time = prevblock.timestamp;
-            // check_add to see that block is > 0x02 hours
-            let ox02 = SystemTime::now()
-                .checked_add(Duration::from_secs(7200))
-                .ok_or("invalid time")
-                .unwrap();
-            // fetch the duration since the block creation time
-            let block_time_diff = ox02.duration_since(block_creation_time)?;
-            // if the block difference is < 0x02 hours validate!
-            if block_time_diff < Duration::from_secs(7200) {
-                return Ok(true);
-            }
+            // the validator has to have signed exactly this block
+            prevblock.verify_signature()?;
+            // a block heavier than the limit at its height
+            // is never valid no matter who produced it
+            let limit = spec.max_block_weight(prevblock.height);
+            weight::check_block_weight(&prevblock.block_data, limit)?;
+            // times need the chain and network time, see
+            // timestamp::check_block_time
+            return Ok(true);
         }
         Ok(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_block(spec: &ChainSpec) -> Block {
+        let acc = Account::create().unwrap();
+        let mut pending = vec![BlockEntry::record("hello"), BlockEntry::record("world")];
+        Block::next(Block::genesis(spec), &acc, &mut pending, spec).unwrap()
+    }
+
+    #[test]
+    fn a_signed_block_verifies() {
+        let spec = ChainSpec::new();
+        let block = signed_block(&spec);
+        assert_eq!(block.height, 1);
+        assert_eq!(block.prev_hash, Block::genesis(&spec).block_hash);
+        block.verify_signature().unwrap();
+        assert!(Block::validate_block(block, &spec).unwrap());
+    }
+
+    #[test]
+    fn the_hash_commits_to_the_data() {
+        let spec = ChainSpec::new();
+        let mut block = signed_block(&spec);
+        block.block_data.push(BlockEntry::record("smuggled"));
+        assert!(block.verify_signature().is_err());
+        // rehashing doesn't help without the validator key
+        block.block_hash = block.compute_hash();
+        assert!(block.verify_signature().is_err());
+    }
+
+    #[test]
+    fn another_key_cannot_claim_the_block() {
+        let spec = ChainSpec::new();
+        let mut block = signed_block(&spec);
+        let other = Account::create().unwrap();
+        block.validator_key = other.acc_public.to_string();
+        assert!(block.verify_signature().is_err());
+    }
+
+    #[test]
+    fn payload_encoding_is_stable() {
+        let block = signed_block(&ChainSpec::new());
+        let copy: Block = serde_json::from_str(&serde_json::to_string(&block).unwrap()).unwrap();
+        assert_eq!(copy.signing_payload(), block.signing_payload());
+        assert_eq!(copy.compute_hash(), block.block_hash);
+    }
+
+    #[test]
+    fn genesis_depends_on_the_chain_id() {
+        let spec = ChainSpec::new();
+        let genesis = Block::genesis(&spec);
+        assert_eq!(genesis.block_hash, Block::genesis(&spec).block_hash);
+        assert!(genesis.is_genesis(&spec));
+        assert!(Block::validate_block(genesis.clone(), &spec).unwrap());
+        // another chain id is another chain
+        let other = ChainSpec { chain_id: "other-net".to_string(), ..ChainSpec::new() };
+        assert!(!genesis.is_genesis(&other));
+        assert!(!Block::validate_block(genesis, &other).unwrap());
+    }
+}
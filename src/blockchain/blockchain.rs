@@ -1,24 +1,117 @@
 use crate::block;
 use anyhow::{self, Ok, Result};
 use rand::rngs::OsRng;
-use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
 use secp256k1::{hashes::sha256, Message};
 use serde::{Deserialize, Serialize};
-use std::{time::SystemTime, io::Write};
+
+use crate::block::block::_BlockT;
+use crate::chainspec::chainspec::ChainSpec;
+use crate::state::state::State;
+use crate::storage::storage;
+use crate::timestamp::timestamp::{self, Timestamp};
+use crate::transaction::transaction::{BlockEntry, Transaction};
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::path::Path;
 
 type Block8 = block::block::Block;
 
-#[derive(Serialize, Deserialize, Debug)]
+// blocks off the chain we hold on to, oldest go first
+pub const MAX_SIDE_BLOCKS: usize = 256;
+
+/// Where a transaction sits on the chain, `position`
+/// is its index in the block's block_data
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxLocation {
+    pub block_hash: String,
+    pub height: u64,
+    pub position: usize,
+}
+
+/// Blocks that don't follow the head, kept in case their
+/// branch outgrows the chain, see Blockchain::fork_choice
+#[derive(Debug, Default, Clone)]
+pub struct SideBlocks {
+    blocks: HashMap<String, Block8>,
+    // hashes in the order they came in
+    order: VecDeque<String>,
+}
+
+impl SideBlocks {
+    pub fn insert(&mut self, blk: Block8) {
+        if self.blocks.contains_key(&blk.block_hash) {
+            return;
+        }
+        self.order.push_back(blk.block_hash.clone());
+        self.blocks.insert(blk.block_hash.clone(), blk);
+        while self.order.len() > MAX_SIDE_BLOCKS {
+            if let Some(old) = self.order.pop_front() {
+                self.blocks.remove(&old);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, hash: &str) -> Option<Block8> {
+        self.order.retain(|x| x != hash);
+        self.blocks.remove(hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+/// State as of the last final block, a reorg never goes
+/// under it so it replays from here instead of genesis
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    // blocks applied, the next one is at this height
+    blocks: u64,
+    state: State,
+}
+
+impl Checkpoint {
+    pub fn genesis(spec: &ChainSpec) -> Result<Checkpoint> {
+        Ok(Checkpoint {
+            blocks: 0,
+            state: State::genesis(spec)?,
+        })
+    }
+
+    // catches up with the blocks of `chain` that are final
+    pub fn advance(&mut self, chain: &Blockchain, spec: &ChainSpec) -> Result<()> {
+        let upto = match chain.head() {
+            Some(_) => chain.finalized_height(spec) + 1,
+            None => 0,
+        };
+        while self.blocks < upto {
+            let blk = &chain.chain[self.blocks as usize];
+            self.state.apply_block(blk)?;
+            self.blocks += 1;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Blockchain {
     pub chain: Vec<Block8>,
-    pub timestamp: SystemTime,
+    pub timestamp: Timestamp,
 }
 
 impl Default for Blockchain {
     fn default() -> Self {
         Blockchain {
             chain: vec![],
-            timestamp: SystemTime::now(),
+            timestamp: Timestamp::now(),
         }
     }
 }
@@ -27,6 +120,185 @@ impl Blockchain {
     pub fn new() -> Self {
         Blockchain::default()
     }
+
+    // a chain holding only the genesis block of `spec`
+    pub fn genesis(spec: &ChainSpec) -> Self {
+        Blockchain {
+            chain: vec![Block8::genesis(spec)],
+            timestamp: Timestamp::now(),
+        }
+    }
+
+    pub fn median_time_past(&self, spec: &ChainSpec) -> Option<Timestamp> {
+        timestamp::median_time_past(&self.chain, spec.median_time_span)
+    }
+
+    // time rules for a block extending this chain, `now`
+    // should come from the node's NetworkClock
+    pub fn check_block_time(&self, block: &Block8, now: Timestamp, spec: &ChainSpec) -> Result<()> {
+        timestamp::check_block_time(block, &self.chain, now, spec)
+    }
+
+    pub fn head(&self) -> Option<&Block8> {
+        self.chain.last()
+    }
+
+    // blocks at or under this height are never reverted,
+    // however long a branch that leaves the chain there
+    pub fn finalized_height(&self, spec: &ChainSpec) -> u64 {
+        self.head()
+            .map(|x| x.height.saturating_sub(spec.finality_depth))
+            .unwrap_or(0)
+    }
+
+    // a missing file is a chain that has only just
+    // started, anything that doesn't parse is an error
+    pub fn load<P: AsRef<Path>>(path: P, spec: &ChainSpec) -> Result<Blockchain> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Blockchain::genesis(spec));
+        }
+        let raw = std::fs::read_to_string(path)?;
+        let chain = serde_json::from_str(&raw)
+            .map_err(|e| anyhow::anyhow!("invalid chain file {:?}: {}", path, e))?;
+        Ok(chain)
+    }
+
+    // a crash never leaves half a chain behind,
+    // see storage::write_atomic
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        storage::write_atomic(path, serde_json::to_string(self)?.as_bytes())
+    }
+
+    pub fn block_by_height(&self, height: u64) -> Option<&Block8> {
+        self.chain.get(height as usize).filter(|x| x.height == height)
+    }
+
+    pub fn block_by_hash(&self, hash: &str) -> Option<&Block8> {
+        self.chain.iter().find(|x| x.block_hash == hash)
+    }
+
+    // the transfer a location points at, if the block
+    // is still on the chain
+    pub fn transaction_at(&self, location: &TxLocation) -> Option<Transaction> {
+        let blk = self.block_by_height(location.height)?;
+        if blk.block_hash != location.block_hash {
+            return None;
+        }
+        match blk.block_data.get(location.position)? {
+            BlockEntry::Transfer(tx) => Some(*tx.clone()),
+            _ => None,
+        }
+    }
+
+    // checks every block against the one before it and
+    // replays the state, returns the state at the head,
+    // block times were held against network time when the
+    // blocks arrived so only their order is checked here
+    pub fn verify(&self, spec: &ChainSpec) -> Result<State> {
+        let mut state = State::genesis(spec)?;
+        for (i, blk) in self.chain.iter().enumerate() {
+            check_next(&self.chain[..i], blk, None, spec)?;
+            state
+                .apply_block(blk)
+                .map_err(|e| anyhow::anyhow!("block {}: {}", i, e))?;
+        }
+        Ok(state)
+    }
+
+    // appends `blk` if it is the valid next block, with
+    // `state` being the state at the current head
+    pub fn extend(&mut self, blk: Block8, state: &mut State, spec: &ChainSpec, now: Timestamp) -> Result<()> {
+        check_next(&self.chain, &blk, Some(now), spec)?;
+        let mut next = state.clone();
+        next.apply_block(&blk)
+            .map_err(|e| anyhow::anyhow!("block {}: {}", blk.height, e))?;
+        *state = next;
+        self.chain.push(blk);
+        Ok(())
+    }
+
+    // the side branch from where it leaves the chain up
+    // to `tip`, None when it doesn't lead back to the chain
+    pub fn branch(&self, tip: &Block8, side: &SideBlocks) -> Option<Vec<Block8>> {
+        let mut branch = vec![tip.clone()];
+        loop {
+            let first = branch.last()?;
+            let parent_height = first.height.checked_sub(1)?;
+            if let Some(parent) = self.block_by_height(parent_height) {
+                if parent.block_hash == first.prev_hash {
+                    branch.reverse();
+                    return Some(branch);
+                }
+            }
+            let parent = side.blocks.get(&first.prev_hash)?;
+            if parent.height != parent_height {
+                return None;
+            }
+            branch.push(parent.clone());
+        }
+    }
+
+    // the longest chain wins, the branch in `side` that
+    // would make a longer chain than this one if any, ties
+    // go to the lower tip hash so every node picks the same,
+    // branches that would revert a final block don't count
+    pub fn fork_choice(&self, side: &SideBlocks, spec: &ChainSpec) -> Option<Vec<Block8>> {
+        let len = self.chain.len() as u64;
+        let finalized = self.finalized_height(spec);
+        side.blocks
+            .values()
+            .filter(|x| x.height >= len)
+            .filter_map(|tip| self.branch(tip, side))
+            .filter(|x| x[0].height > finalized)
+            .max_by(|a, b| {
+                let (a, b) = (a.last().unwrap(), b.last().unwrap());
+                a.height.cmp(&b.height).then_with(|| b.block_hash.cmp(&a.block_hash))
+            })
+    }
+
+    // moves the head onto `branch`, the chain is left as
+    // it was unless every block of the branch applies,
+    // hands back the state at the new head and the blocks
+    // taken off from the top down, `from` is the checkpoint
+    // of this chain the state is replayed from
+    pub fn switch_to(
+        &mut self,
+        branch: Vec<Block8>,
+        from: &Checkpoint,
+        spec: &ChainSpec,
+        now: Timestamp,
+    ) -> Result<(State, Vec<Block8>)> {
+        let fork = match branch.first() {
+            Some(first) if first.height > 0 && first.height <= self.chain.len() as u64 => first.height,
+            _ => anyhow::bail!("branch does not fork off the chain"),
+        };
+        let finalized = self.finalized_height(spec);
+        if fork <= finalized {
+            anyhow::bail!("branch forks off at {} under final block {}", fork - 1, finalized);
+        }
+        if from.blocks > fork {
+            anyhow::bail!("checkpoint at {} is past the fork at {}", from.blocks, fork - 1);
+        }
+        let fork = fork as usize;
+        // blocks under the fork were checked on the way in,
+        // only their state is needed
+        let mut state = from.state.clone();
+        for blk in &self.chain[from.blocks as usize..fork] {
+            state.apply_block(blk)?;
+        }
+        let mut next = Blockchain {
+            chain: self.chain[..fork].to_vec(),
+            timestamp: self.timestamp,
+        };
+        for blk in branch {
+            next.extend(blk, &mut state, spec, now)?;
+        }
+        let reverted = self.chain.drain(fork..).rev().collect();
+        self.chain = next.chain;
+        Ok((state, reverted))
+    }
+
     pub fn _add_block_to_chain(block: Block8) -> Self {
         // check to see if block is valid
         // pass in custom built function to check
@@ -47,10 +319,10 @@ impl Blockchain {
             if blck_ver.is_ok() {
                 let mut chain = Vec::new();
                 chain.push(block);
-                // we're using system time based on locale
+                // consensus time, ms since the epoch
                 let bchain = Blockchain {
                     chain,
-                    timestamp: SystemTime::now(),
+                    timestamp: Timestamp::now(),
                 };
                 // save this data to the local blockchain
                 // database stored in user's system
@@ -82,28 +354,283 @@ impl Blockchain {
         Blockchain::default()
     }
 
+    // private payloads are already sealed for their
+    // recipients, see payload::PrivatePayload::seal_entry
     pub fn add_block(block: Block8) -> Result<Blockchain> {
         let mut blocks: Vec<Block8> = Vec::new();
-        let block_valid = block.valid.clone();
-        if block_valid {
-            let block_data = block.block_data.get(0).unwrap().as_str().as_bytes();
-
-            dbg!("block data", &block.block_data);
-            let block_bytes = String::from_utf8(Vec::from(block_data))?;
-            let mut rng = rand::thread_rng();
-            let bits = 2048;
-            let secret = RsaPrivateKey::new(&mut rng, bits)?;
-            let public = RsaPublicKey::from(&secret);
-            let enc_block = public.encrypt(&mut rng, Pkcs1v15Encrypt, block_bytes.as_bytes())?;
-            let dec_block = secret.decrypt(Pkcs1v15Encrypt, &enc_block)?;
-            assert_eq!(&block_data[..], &dec_block[..]);
-            blocks.push(block.clone());
+        if block.valid {
+            blocks.push(block);
             let new_blockchain = Blockchain {
                 chain: blocks,
-                timestamp: SystemTime::now(),
+                timestamp: Timestamp::now(),
             };
             return Ok(new_blockchain);
         }
         Ok(Blockchain::default())
     }
 }
+
+// the transfers in `blk` in block order, other
+// entries are skipped
+pub fn transfers(blk: &Block8) -> Vec<(TxLocation, Transaction)> {
+    let mut transfers = vec![];
+    for (position, entry) in blk.block_data.iter().enumerate() {
+        if let BlockEntry::Transfer(tx) = entry {
+            let location = TxLocation {
+                block_hash: blk.block_hash.clone(),
+                height: blk.height,
+                position,
+            };
+            transfers.push((location, *tx.clone()));
+        }
+    }
+    transfers
+}
+
+// whether `blk` may follow `ancestors`, everything
+// but the state it leads to, the future drift is only
+// checked when there is a network time to check it by
+fn check_next(ancestors: &[Block8], blk: &Block8, now: Option<Timestamp>, spec: &ChainSpec) -> Result<()> {
+    let i = ancestors.len();
+    if blk.height != i as u64 {
+        anyhow::bail!("block {} has height {}", i, blk.height);
+    }
+    // unsigned and fixed by the spec
+    if i == 0 {
+        if !blk.is_genesis(spec) {
+            anyhow::bail!("block 0 is not the genesis of {}", spec.chain_id);
+        }
+        return Ok(());
+    }
+    if let Some(prev) = ancestors.last() {
+        if blk.prev_hash != prev.block_hash {
+            anyhow::bail!("block {} does not link to block {}", i, i - 1);
+        }
+    }
+    blk.verify_signature()
+        .map_err(|e| anyhow::anyhow!("block {}: {}", i, e))?;
+    if !block::block::Block::validate_block(blk.clone(), spec)? {
+        anyhow::bail!("block {} is not valid", i);
+    }
+    let checked = match now {
+        Some(now) => timestamp::check_block_time(blk, ancestors, now, spec),
+        None => timestamp::check_median_time(blk, ancestors, spec),
+    };
+    checked.map_err(|e| anyhow::anyhow!("block {}: {}", i, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::account::Account;
+
+    fn funded(spec: &mut ChainSpec, amount: u32) -> Account {
+        let acc = Account::create().unwrap();
+        spec.allocations.insert(acc.address(), amount);
+        acc
+    }
+
+    #[test]
+    fn produces_extends_and_verifies_a_chain() {
+        let mut spec = ChainSpec::default();
+        let alice = funded(&mut spec, 100);
+        let bob = Account::create().unwrap();
+        let validator = Account::create().unwrap();
+        let mut chain = Blockchain::genesis(&spec);
+        let mut state = chain.verify(&spec).unwrap();
+
+        let head = chain.head().cloned().unwrap();
+        let tx = Transaction::new(&alice, bob.address(), head.block_hash.clone(), 40, 0).unwrap();
+        let mut pending = vec![BlockEntry::Transfer(Box::new(tx))];
+        let blk = Block8::next(head, &validator, &mut pending, &spec).unwrap();
+        assert!(pending.is_empty());
+        chain.extend(blk, &mut state, &spec, Timestamp::now()).unwrap();
+        let head = chain.head().cloned().unwrap();
+        let blk = Block8::next(head, &validator, &mut vec![], &spec).unwrap();
+        chain.extend(blk, &mut state, &spec, Timestamp::now()).unwrap();
+
+        assert_eq!(chain.chain.len(), 3);
+        assert_eq!(state.balance(&bob.address()), 40);
+        let replayed = chain.verify(&spec).unwrap();
+        assert_eq!(replayed.balance(&alice.address()), 60);
+        assert_eq!(replayed.balance(&bob.address()), 40);
+    }
+
+    #[test]
+    fn genesis_is_fixed_by_the_spec() {
+        let spec = ChainSpec::default();
+        assert_eq!(Block8::genesis(&spec).block_hash, Block8::genesis(&spec).block_hash);
+        let other = ChainSpec {
+            chain_id: "other-net".to_string(),
+            ..ChainSpec::default()
+        };
+        assert!(!Block8::genesis(&other).is_genesis(&spec));
+        let chain = Blockchain::genesis(&other);
+        assert!(chain.verify(&spec).is_err());
+        // the old throwaway first block doesn't pass either
+        let chain = Blockchain {
+            chain: vec![Block8::default()],
+            timestamp: Timestamp::now(),
+        };
+        assert!(chain.verify(&spec).is_err());
+    }
+
+    #[test]
+    fn extend_rejects_blocks_that_do_not_follow_the_head() {
+        let spec = ChainSpec::default();
+        let validator = Account::create().unwrap();
+        let mut chain = Blockchain::genesis(&spec);
+        let mut state = chain.verify(&spec).unwrap();
+        let genesis = chain.head().cloned().unwrap();
+        let first = Block8::next(genesis.clone(), &validator, &mut vec![], &spec).unwrap();
+        let mut skipped = first.clone();
+        skipped.height = 2;
+        skipped.sign(&validator);
+        assert!(chain.extend(skipped, &mut state, &spec, Timestamp::now()).is_err());
+        let mut forked = first.clone();
+        forked.prev_hash = first.block_hash.clone();
+        forked.sign(&validator);
+        assert!(chain.extend(forked, &mut state, &spec, Timestamp::now()).is_err());
+        let mut tampered = first.clone();
+        tampered.block_data.push(BlockEntry::record("extra"));
+        assert!(chain.extend(tampered, &mut state, &spec, Timestamp::now()).is_err());
+        chain.extend(first, &mut state, &spec, Timestamp::now()).unwrap();
+        assert_eq!(chain.chain.len(), 2);
+    }
+
+    // genesis and then one branch per validator, each
+    // `len` blocks long
+    fn forked(spec: &ChainSpec, lens: &[usize]) -> (Blockchain, Vec<Vec<Block8>>) {
+        let chain = Blockchain::genesis(spec);
+        let genesis = chain.head().cloned().unwrap();
+        let branches = lens
+            .iter()
+            .map(|len| {
+                let validator = Account::create().unwrap();
+                let mut branch: Vec<Block8> = vec![];
+                for _ in 0..*len {
+                    let prev = branch.last().cloned().unwrap_or_else(|| genesis.clone());
+                    branch.push(Block8::next(prev, &validator, &mut vec![], spec).unwrap());
+                }
+                branch
+            })
+            .collect();
+        (chain, branches)
+    }
+
+    #[test]
+    fn fork_choice_follows_the_longest_branch() {
+        let spec = ChainSpec::default();
+        let (mut chain, branches) = forked(&spec, &[2, 2, 3]);
+        let mut state = chain.verify(&spec).unwrap();
+        for blk in &branches[0] {
+            chain.extend(blk.clone(), &mut state, &spec, Timestamp::now()).unwrap();
+        }
+        let mut side = SideBlocks::default();
+        for blk in &branches[1] {
+            side.insert(blk.clone());
+        }
+        // as long as the chain isn't enough
+        assert!(chain.fork_choice(&side, &spec).is_none());
+        assert_eq!(chain.branch(&branches[1][1], &side).unwrap().len(), 2);
+        // the tip of a branch whose start is missing leads nowhere
+        side.insert(branches[2][2].clone());
+        side.insert(branches[2][1].clone());
+        assert!(chain.fork_choice(&side, &spec).is_none());
+        side.insert(branches[2][0].clone());
+        let branch = chain.fork_choice(&side, &spec).unwrap();
+        assert_eq!(branch.len(), 3);
+
+        let from = Checkpoint::genesis(&spec).unwrap();
+        let (_, reverted) = chain.switch_to(branch, &from, &spec, Timestamp::now()).unwrap();
+        assert_eq!(reverted[0].block_hash, branches[0][1].block_hash);
+        assert_eq!(reverted[1].block_hash, branches[0][0].block_hash);
+        assert_eq!(chain.head().unwrap().block_hash, branches[2][2].block_hash);
+        chain.verify(&spec).unwrap();
+    }
+
+    #[test]
+    fn a_bad_branch_leaves_the_chain_alone() {
+        let spec = ChainSpec::default();
+        let (mut chain, branches) = forked(&spec, &[1, 2]);
+        let mut state = chain.verify(&spec).unwrap();
+        chain.extend(branches[0][0].clone(), &mut state, &spec, Timestamp::now()).unwrap();
+        let mut bad = branches[1].clone();
+        bad[1].block_data.push(BlockEntry::record("not signed"));
+        let from = Checkpoint::genesis(&spec).unwrap();
+        assert!(chain.switch_to(bad, &from, &spec, Timestamp::now()).is_err());
+        assert_eq!(chain.chain.len(), 2);
+        assert_eq!(chain.head().unwrap().block_hash, branches[0][0].block_hash);
+    }
+
+    #[test]
+    fn final_blocks_are_never_reverted() {
+        let spec = ChainSpec {
+            finality_depth: 2,
+            ..ChainSpec::default()
+        };
+        let (mut chain, branches) = forked(&spec, &[3, 5]);
+        let mut state = chain.verify(&spec).unwrap();
+        for blk in &branches[0] {
+            chain.extend(blk.clone(), &mut state, &spec, Timestamp::now()).unwrap();
+        }
+        // head at 3 makes block 1 final, the longer branch
+        // forks off under it at genesis
+        assert_eq!(chain.finalized_height(&spec), 1);
+        let mut side = SideBlocks::default();
+        for blk in &branches[1] {
+            side.insert(blk.clone());
+        }
+        assert!(chain.fork_choice(&side, &spec).is_none());
+        let mut from = Checkpoint::genesis(&spec).unwrap();
+        from.advance(&chain, &spec).unwrap();
+        assert_eq!(from.blocks, 2);
+        let res = chain.switch_to(branches[1].clone(), &from, &spec, Timestamp::now());
+        assert!(res.is_err());
+        assert_eq!(chain.head().unwrap().block_hash, branches[0][2].block_hash);
+    }
+
+    #[test]
+    fn a_reorg_replays_from_the_checkpoint() {
+        let spec = ChainSpec {
+            finality_depth: 2,
+            ..ChainSpec::default()
+        };
+        let (mut chain, branches) = forked(&spec, &[3]);
+        let mut state = chain.verify(&spec).unwrap();
+        for blk in &branches[0] {
+            chain.extend(blk.clone(), &mut state, &spec, Timestamp::now()).unwrap();
+        }
+        let mut from = Checkpoint::genesis(&spec).unwrap();
+        from.advance(&chain, &spec).unwrap();
+        // a longer branch off block 1, the last final one
+        let validator = Account::create().unwrap();
+        let first = Block8::next(branches[0][0].clone(), &validator, &mut vec![], &spec).unwrap();
+        let second = Block8::next(first.clone(), &validator, &mut vec![], &spec).unwrap();
+        let third = Block8::next(second.clone(), &validator, &mut vec![], &spec).unwrap();
+        let mut side = SideBlocks::default();
+        for blk in [&first, &second, &third] {
+            side.insert(blk.clone());
+        }
+        let branch = chain.fork_choice(&side, &spec).unwrap();
+        assert_eq!(branch.len(), 3);
+        let (next, reverted) = chain.switch_to(branch, &from, &spec, Timestamp::now()).unwrap();
+        assert_eq!(reverted.len(), 2);
+        assert_eq!(chain.head().unwrap().block_hash, third.block_hash);
+        let replayed = chain.verify(&spec).unwrap();
+        assert_eq!(next.balances, replayed.balances);
+    }
+
+    #[test]
+    fn side_blocks_forget_the_oldest_first() {
+        let spec = ChainSpec::default();
+        let (_, branches) = forked(&spec, &[MAX_SIDE_BLOCKS + 1]);
+        let mut side = SideBlocks::default();
+        for blk in &branches[0] {
+            side.insert(blk.clone());
+        }
+        assert_eq!(side.len(), MAX_SIDE_BLOCKS);
+        assert!(!side.contains(&branches[0][0].block_hash));
+        assert!(side.contains(&branches[0][MAX_SIDE_BLOCKS].block_hash));
+    }
+}
@@ -0,0 +1,140 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::address::address::Address;
+use crate::timestamp::timestamp::Timestamp;
+use crate::weight::weight::MAX_BLOCK_WEIGHT;
+
+pub const MAX_FUTURE_DRIFT_MS: u64 = 2 * 60 * 1000;
+pub const MEDIAN_TIME_SPAN: usize = 11;
+pub const FINALITY_DEPTH: u64 = 12;
+
+/// Consensus parameters shared by every node on a chain,
+/// values that change over time are keyed by the block
+/// height from which they take effect
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainSpec {
+    pub chain_id: String,
+    // activation height -> max block weight
+    pub weight_limits: BTreeMap<u64, u64>,
+    // how far ahead of network time a block may be
+    #[serde(default = "default_future_drift")]
+    pub max_future_drift_ms: u64,
+    // blocks the median time past is taken over
+    #[serde(default = "default_median_span")]
+    pub median_time_span: usize,
+    // balances the chain starts out with
+    #[serde(default)]
+    pub allocations: BTreeMap<Address, u32>,
+    // blocks this far under the head are taken as final
+    #[serde(default = "default_finality_depth")]
+    pub finality_depth: u64,
+    // time of the genesis block, part of its hash
+    #[serde(default)]
+    pub genesis_timestamp: Timestamp,
+}
+
+fn default_future_drift() -> u64 {
+    MAX_FUTURE_DRIFT_MS
+}
+
+fn default_median_span() -> usize {
+    MEDIAN_TIME_SPAN
+}
+
+fn default_finality_depth() -> u64 {
+    FINALITY_DEPTH
+}
+
+impl Default for ChainSpec {
+    fn default() -> Self {
+        let mut weight_limits = BTreeMap::new();
+        weight_limits.insert(0, MAX_BLOCK_WEIGHT);
+        ChainSpec {
+            chain_id: "test-net".to_string(),
+            weight_limits,
+            max_future_drift_ms: MAX_FUTURE_DRIFT_MS,
+            median_time_span: MEDIAN_TIME_SPAN,
+            allocations: BTreeMap::new(),
+            finality_depth: FINALITY_DEPTH,
+            genesis_timestamp: Timestamp::default(),
+        }
+    }
+}
+
+impl ChainSpec {
+    pub fn new() -> Self {
+        ChainSpec::default()
+    }
+
+    pub fn load(path: &str) -> Result<ChainSpec> {
+        let raw = std::fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+        let spec = serde_json::from_str(&raw)?;
+        Ok(spec)
+    }
+
+    // raise or lower the block limit from `height` onwards
+    pub fn set_weight_limit(&mut self, height: u64, limit: u64) {
+        self.weight_limits.insert(height, limit);
+    }
+
+    pub fn max_block_weight(&self, height: u64) -> u64 {
+        self.weight_limits
+            .range(..=height)
+            .next_back()
+            .map(|(_, limit)| *limit)
+            .unwrap_or(MAX_BLOCK_WEIGHT)
+    }
+
+    // the biggest limit the chain ever allows, used
+    // where the height of a message isn't known yet
+    pub fn peak_block_weight(&self) -> u64 {
+        self.weight_limits
+            .values()
+            .copied()
+            .max()
+            .unwrap_or(MAX_BLOCK_WEIGHT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_apply_from_their_activation_height() {
+        let mut spec = ChainSpec::new();
+        spec.set_weight_limit(10, MAX_BLOCK_WEIGHT * 2);
+        spec.set_weight_limit(20, MAX_BLOCK_WEIGHT / 2);
+        assert_eq!(spec.max_block_weight(0), MAX_BLOCK_WEIGHT);
+        assert_eq!(spec.max_block_weight(9), MAX_BLOCK_WEIGHT);
+        assert_eq!(spec.max_block_weight(10), MAX_BLOCK_WEIGHT * 2);
+        assert_eq!(spec.max_block_weight(19), MAX_BLOCK_WEIGHT * 2);
+        assert_eq!(spec.max_block_weight(20), MAX_BLOCK_WEIGHT / 2);
+        assert_eq!(spec.peak_block_weight(), MAX_BLOCK_WEIGHT * 2);
+    }
+
+    #[test]
+    fn no_limits_fall_back_to_the_default() {
+        let mut spec = ChainSpec::new();
+        spec.weight_limits.clear();
+        assert_eq!(spec.max_block_weight(5), MAX_BLOCK_WEIGHT);
+        assert_eq!(spec.peak_block_weight(), MAX_BLOCK_WEIGHT);
+    }
+
+    #[test]
+    fn a_minimal_spec_file_loads_with_defaults() {
+        let path = std::env::temp_dir().join(format!("spec-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"chain_id":"dev","weight_limits":{"0":100,"5":200}}"#)
+            .unwrap();
+        let spec = ChainSpec::load(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(spec.chain_id, "dev");
+        assert_eq!(spec.max_block_weight(4), 100);
+        assert_eq!(spec.max_block_weight(5), 200);
+        assert_eq!(spec.finality_depth, FINALITY_DEPTH);
+        assert_eq!(spec.median_time_span, MEDIAN_TIME_SPAN);
+        assert!(ChainSpec::load("/nonexistent/spec.json").is_err());
+    }
+}
@@ -0,0 +1,686 @@
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
+use libp2p::Multiaddr;
+use secp256k1::PublicKey;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::select;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    account::account::Account,
+    address::address::Address,
+    block::block::Block,
+    blockchain::blockchain::Blockchain,
+    chainspec::chainspec::ChainSpec,
+    config::config::{LockInfo, LogConfig, NodeConfig, NodeInfo, NodeRole},
+    dynamic,
+    identity::identity::{load_or_create_node_key, ValidatorAttestation},
+    indexer::indexer::Indexer,
+    keystore::keystore::Keystore,
+    node::node::Node,
+    rpc::rpc,
+    storage::storage::DirLock,
+    transaction::transaction::{Transaction, TransactionPool},
+    wallet::wallet::Wallet,
+};
+
+// read by hand rather than through clap, which would also
+// offer it as a flag
+const PASSPHRASE_ENV: &str = "ESSEX_PASSPHRASE";
+
+#[derive(Debug, Parser)]
+#[command(name = "essex", version, about = "Essex chain node and tools")]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+/// Options every subcommand understands, each one falls
+/// back from flag to env to the config file to a default
+#[derive(Debug, Args)]
+pub struct GlobalArgs {
+    /// TOML file with defaults for any of these options
+    #[arg(long, env = "ESSEX_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    #[arg(long, env = "ESSEX_DATA_DIR", global = true)]
+    pub data_dir: Option<PathBuf>,
+    /// JSON chain spec, the built in test-net spec if unset
+    #[arg(long, env = "ESSEX_CHAIN_SPEC", global = true)]
+    pub chain_spec: Option<PathBuf>,
+    /// Chain the node expects to be on
+    #[arg(long, env = "ESSEX_CHAIN_ID", global = true)]
+    pub chain_id: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run a node
+    #[command(subcommand)]
+    Node(NodeCmd),
+    /// Manage keys and send funds
+    #[command(subcommand)]
+    Wallet(WalletCmd),
+    /// Inspect and move the local chain
+    #[command(subcommand)]
+    Chain(ChainCmd),
+    /// Look up addresses and keys
+    #[command(subcommand)]
+    Keys(KeysCmd),
+    /// Run the crypto and block self checks
+    #[command(hide = true)]
+    Selftest,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum NodeCmd {
+    Run(RunArgs),
+    /// Show the configuration a node would run with
+    Status,
+    /// Print the node's PeerId, creating its key if needed
+    Identity,
+    /// Bind a keystore account to this node's PeerId
+    Attest {
+        /// Public key of the validator account
+        #[arg(long)]
+        validator: String,
+        #[command(flatten)]
+        passphrase: PassphraseArgs,
+    },
+    /// Check an attestation, the node's own if no file is given
+    VerifyAttestation { file: Option<PathBuf> },
+}
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// Multiaddrs to listen on, repeat or comma separate
+    #[arg(long, env = "ESSEX_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<Multiaddr>,
+    /// Peers dialed on startup
+    #[arg(long, env = "ESSEX_BOOTSTRAP", value_delimiter = ',')]
+    pub bootstrap: Vec<Multiaddr>,
+    /// Only find peers through bootstrap and the DHT
+    #[arg(long, env = "ESSEX_NO_MDNS")]
+    pub no_mdns: bool,
+    /// Gossip topic the node joins
+    #[arg(long, env = "ESSEX_TOPIC")]
+    pub topic: Option<String>,
+    #[arg(long, env = "ESSEX_ROLE", value_enum)]
+    pub role: Option<NodeRole>,
+    /// Tracing filter, e.g. info or essex=debug
+    #[arg(long, env = "ESSEX_LOG")]
+    pub log_level: Option<String>,
+    /// Serve JSON-RPC on the configured address
+    #[arg(long, env = "ESSEX_RPC")]
+    pub rpc: bool,
+    /// Serve JSON-RPC on this address, implies --rpc
+    #[arg(long, env = "ESSEX_RPC_LISTEN")]
+    pub rpc_listen: Option<SocketAddr>,
+    /// Bearer token for admin RPC methods
+    #[arg(long, env = "ESSEX_RPC_TOKEN", hide_env_values = true)]
+    pub rpc_token: Option<String>,
+    /// Leave the explorer pages off the RPC server
+    #[arg(long, env = "ESSEX_NO_EXPLORER")]
+    pub no_explorer: bool,
+    /// Seconds a stopping node gets to flush and disconnect
+    #[arg(long, env = "ESSEX_SHUTDOWN_DEADLINE")]
+    pub shutdown_deadline: Option<u64>,
+    /// Unlocks the attested validator of an authority
+    #[command(flatten)]
+    pub passphrase: PassphraseArgs,
+}
+
+impl RunArgs {
+    fn apply(self, config: &mut NodeConfig) {
+        if !self.listen.is_empty() {
+            config.listen = self.listen;
+        }
+        if !self.bootstrap.is_empty() {
+            config.bootstrap = self.bootstrap;
+        }
+        if self.no_mdns {
+            config.discovery.mdns = false;
+        }
+        if let Some(topic) = self.topic {
+            config.gossip.topic = topic;
+        }
+        if let Some(role) = self.role {
+            config.role = role;
+        }
+        if let Some(level) = self.log_level {
+            config.log.level = level;
+        }
+        if self.rpc {
+            config.rpc.enabled = true;
+        }
+        if let Some(addr) = self.rpc_listen {
+            config.rpc.enabled = true;
+            config.rpc.listen = addr;
+        }
+        if let Some(token) = self.rpc_token {
+            config.rpc.admin_token = Some(token);
+        }
+        if self.no_explorer {
+            config.rpc.explorer = false;
+        }
+        if let Some(secs) = self.shutdown_deadline {
+            config.shutdown_deadline_secs = secs;
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum WalletCmd {
+    /// Create a mnemonic wallet and store its first account
+    New {
+        #[arg(long, default_value_t = 12)]
+        words: usize,
+        #[command(flatten)]
+        passphrase: PassphraseArgs,
+    },
+    /// List keys in the keystore
+    List,
+    Balance {
+        address: Address,
+    },
+    /// Sign a transfer and queue it, through the node when one is running
+    Send {
+        /// Public key of a keystore account
+        #[arg(long)]
+        from: String,
+        #[arg(long)]
+        to: Address,
+        #[arg(long)]
+        amount: u32,
+        #[command(flatten)]
+        passphrase: PassphraseArgs,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ChainCmd {
+    Export { file: PathBuf },
+    /// Verify a chain file and make it the local chain
+    Import { file: PathBuf },
+    Verify,
+    Info,
+    /// Rebuild the transaction and address index from the chain
+    Reindex,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeysCmd {
+    /// Show what an address, public key or keystore id is
+    Inspect { key: String },
+}
+
+/// Resolved options shared by the subcommands
+pub struct Ctx {
+    pub config: NodeConfig,
+    pub spec: ChainSpec,
+}
+
+impl Ctx {
+    fn new(global: &GlobalArgs) -> Result<Ctx> {
+        let mut config = match &global.config {
+            Some(path) => NodeConfig::load(path)?,
+            None => NodeConfig::default(),
+        };
+        if let Some(dir) = &global.data_dir {
+            config.data_dir = dir.clone();
+        }
+        if let Some(path) = &global.chain_spec {
+            config.chain_spec = Some(path.clone());
+        }
+        if let Some(id) = &global.chain_id {
+            config.chain_id = Some(id.clone());
+        }
+        let spec = config.chain_spec()?;
+        Ok(Ctx { config, spec })
+    }
+
+    pub fn keystore(&self) -> Result<Keystore> {
+        Keystore::open(self.config.keystore_dir())
+    }
+
+    // the account `node attest` bound to this node, an
+    // authority signs its blocks with it
+    fn validator(&self, passphrase: &PassphraseArgs) -> Result<Account> {
+        let att = ValidatorAttestation::load(self.config.attestation_path())
+            .context("an authority needs a validator, see `node attest`")?;
+        self.keystore()?.load(&att.validator_key, &passphrase.read(false)?)
+    }
+
+    fn ensure_data_dir(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.config.data_dir)
+            .with_context(|| format!("creating data dir {:?}", self.config.data_dir))
+    }
+
+    // for commands that write files a running node owns
+    fn lock_data_dir(&self) -> Result<DirLock> {
+        self.ensure_data_dir()?;
+        match DirLock::try_acquire(self.config.lock_path())? {
+            Some(lock) => Ok(lock),
+            None => bail!("a node is running on {:?}, stop it first", self.config.data_dir),
+        }
+    }
+}
+
+/// Where a keystore passphrase comes from, never the
+/// command line where `ps` and shell history show it
+#[derive(Debug, Clone, Args)]
+pub struct PassphraseArgs {
+    /// File whose first line is the keystore passphrase
+    #[arg(long, env = "ESSEX_PASSPHRASE_FILE")]
+    pub passphrase_file: Option<PathBuf>,
+}
+
+impl PassphraseArgs {
+    // ESSEX_PASSPHRASE first, then the file, then a prompt
+    // when there is a terminal to ask on, `confirm` asks
+    // twice for a passphrase that is being set
+    fn read(&self, confirm: bool) -> Result<String> {
+        if let Ok(p) = std::env::var(PASSPHRASE_ENV) {
+            if !p.is_empty() {
+                return Ok(p);
+            }
+        }
+        if let Some(path) = &self.passphrase_file {
+            return passphrase_from_file(path);
+        }
+        if !std::io::stdin().is_terminal() {
+            bail!(
+                "a keystore passphrase is needed, set {} or pass --passphrase-file",
+                PASSPHRASE_ENV
+            );
+        }
+        let p = rpassword::prompt_password("keystore passphrase: ")?;
+        if p.is_empty() {
+            bail!("empty keystore passphrase");
+        }
+        if confirm && rpassword::prompt_password("again: ")? != p {
+            bail!("passphrases do not match");
+        }
+        Ok(p)
+    }
+}
+
+fn passphrase_from_file(path: &Path) -> Result<String> {
+    let raw = std::fs::read_to_string(path).with_context(|| format!("reading {:?}", path))?;
+    match raw.lines().next() {
+        Some(p) if !p.is_empty() => Ok(p.to_string()),
+        _ => bail!("no passphrase in {:?}", path),
+    }
+}
+
+pub fn run(cli: Cli) -> Result<()> {
+    let ctx = Ctx::new(&cli.global)?;
+    match cli.command {
+        Command::Node(cmd) => node_cmd(&ctx, cmd),
+        Command::Wallet(cmd) => wallet_cmd(&ctx, cmd),
+        Command::Chain(cmd) => chain_cmd(&ctx, cmd),
+        Command::Keys(cmd) => keys_cmd(&ctx, cmd),
+        Command::Selftest => {
+            crate::_check_rsa("essex");
+            crate::_check_secp256k1(&[7u8; 32]);
+            crate::_tsmain();
+            println!("selftest ok");
+            Ok(())
+        }
+    }
+}
+
+fn node_cmd(ctx: &Ctx, cmd: NodeCmd) -> Result<()> {
+    match cmd {
+        NodeCmd::Run(args) => {
+            let mut config = ctx.config.clone();
+            let passphrase = args.passphrase.clone();
+            args.apply(&mut config);
+            let mut node = Node::new(config, ctx.spec.clone())?;
+            if node.config().role == NodeRole::Authority {
+                node = node.with_validator(ctx.validator(&passphrase)?);
+            }
+            let rt = tokio::runtime::Runtime::new()?;
+            let res = rt.block_on(run_node(node));
+            // stdin is read on a blocking thread that won't
+            // come back until the next line, don't wait for it
+            rt.shutdown_background();
+            res
+        }
+        NodeCmd::Status => {
+            println!("{}", NodeInfo::detect(&ctx.config, &ctx.spec));
+            let chain = Blockchain::load(ctx.config.chain_path(), &ctx.spec)?;
+            match chain.head() {
+                Some(head) => println!("head:         {} at height {}", head.block_hash, head.height),
+                None => println!("head:         none"),
+            }
+            Ok(())
+        }
+        NodeCmd::Identity => {
+            ctx.ensure_data_dir()?;
+            let key = load_or_create_node_key(ctx.config.node_key_path())?;
+            println!("{}", key.public().to_peer_id());
+            Ok(())
+        }
+        NodeCmd::Attest {
+            validator,
+            passphrase,
+        } => {
+            let passphrase = passphrase.read(false)?;
+            let acc = ctx.keystore()?.load(&validator, &passphrase)?;
+            ctx.ensure_data_dir()?;
+            let key = load_or_create_node_key(ctx.config.node_key_path())?;
+            let att = ValidatorAttestation::new(&acc, &key)?;
+            att.save(ctx.config.attestation_path())?;
+            println!("{}", serde_json::to_string_pretty(&att)?);
+            Ok(())
+        }
+        NodeCmd::VerifyAttestation { file } => {
+            let path = file.unwrap_or_else(|| ctx.config.attestation_path());
+            let att = ValidatorAttestation::load(&path)?;
+            println!("{} is bound to {}", att.validator, att.peer_id);
+            Ok(())
+        }
+    }
+}
+
+// runs the node in the foreground, lines on stdin are
+// gossiped as text until ctrl-c or SIGTERM stops it
+async fn run_node(node: Node) -> Result<()> {
+    init_logging(&node.config().log)?;
+    let handle = node.start().await?;
+    dynamic::print_banner(handle.info());
+    if let Some(head) = handle.head() {
+        println!("🌈 Local head: {} at height {}", head.block_hash, head.height);
+    }
+    println!("any messages sent would be sent to peers");
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdin_open = true;
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    loop {
+        select! {
+            line = stdin.next_line(), if stdin_open => match line {
+                Ok(Some(line)) => {
+                    if let Err(e) = handle.publish_text(line).await {
+                        println!("{e}")
+                    }
+                }
+                // no more input, keep running as a plain node
+                _ => stdin_open = false,
+            },
+            res = &mut signal => {
+                res?;
+                break;
+            }
+            _ = handle.stopped() => return Ok(()),
+        }
+    }
+    handle.shutdown().await
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<()> {
+    let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    select! {
+        res = tokio::signal::ctrl_c() => res?,
+        _ = term.recv() => {}
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<()> {
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+fn init_logging(log: &LogConfig) -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(&log.level))?;
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(log.ansi)
+        .try_init();
+    Ok(())
+}
+
+fn wallet_cmd(ctx: &Ctx, cmd: WalletCmd) -> Result<()> {
+    match cmd {
+        WalletCmd::New { words, passphrase } => {
+            let passphrase = passphrase.read(true)?;
+            let wallet = Wallet::generate(words, "")?;
+            let acc = wallet.derive_account(0)?;
+            ctx.keystore()?.save(&acc, &passphrase)?;
+            println!("address:    {}", acc.address());
+            println!("public key: {}", acc.acc_public);
+            println!("write down these words, they are the only backup of this wallet:");
+            println!("{}", wallet.phrase().as_str());
+            Ok(())
+        }
+        WalletCmd::List => {
+            for id in ctx.keystore()?.list()? {
+                match id.parse::<PublicKey>() {
+                    Ok(public) => println!("{}  {}", Address::from_public_key(&public), id),
+                    Err(_) => println!("{}  (unreadable key id)", id),
+                }
+            }
+            Ok(())
+        }
+        WalletCmd::Balance { address } => {
+            let state = Blockchain::load(ctx.config.chain_path(), &ctx.spec)?.verify(&ctx.spec)?;
+            println!("{} {}", address, state.balance(&address));
+            Ok(())
+        }
+        WalletCmd::Send {
+            from,
+            to,
+            amount,
+            passphrase,
+        } => {
+            let passphrase = passphrase.read(false)?;
+            let acc = ctx.keystore()?.load(&from, &passphrase)?;
+            ctx.ensure_data_dir()?;
+            // a running node owns the mempool file
+            let Some(_lock) = DirLock::try_acquire(ctx.config.lock_path())? else {
+                return send_through_node(ctx, &acc, to, amount);
+            };
+            let chain = Blockchain::load(ctx.config.chain_path(), &ctx.spec)?;
+            let state = chain.verify(&ctx.spec)?;
+            let mut pool = TransactionPool::load(ctx.config.mempool_path())?;
+            let nonce = pool.next_nonce(&state, &acc.address());
+            let head = chain.head().cloned().unwrap_or_else(|| Block::genesis(&ctx.spec));
+            let tx = Transaction::new(&acc, to, head.block_hash, amount, nonce)?;
+            let id = tx.tx_header.transaction_id.clone();
+            pool.admit(&state, tx)?;
+            println!("tx {} queued", id);
+            pool.save(ctx.config.mempool_path())
+        }
+    }
+}
+
+// the tx goes in through the RPC of the node holding
+// the data dir, with its head and pending nonce
+fn send_through_node(ctx: &Ctx, acc: &Account, to: Address, amount: u32) -> Result<()> {
+    let holder = LockInfo::load(ctx.config.lock_path())?;
+    let Some(addr) = holder.rpc else {
+        bail!(
+            "node {} is running on {:?} without RPC, stop it or enable RPC to send",
+            holder.pid,
+            ctx.config.data_dir
+        );
+    };
+    let nonce: u64 = serde_json::from_value(rpc::call(addr, "account_getNonce", json!([acc.address(), true]))?)?;
+    let head: Option<Block> = serde_json::from_value(rpc::call(addr, "chain_getHead", json!([]))?)?;
+    let head = head.unwrap_or_else(|| Block::genesis(&ctx.spec));
+    let tx = Transaction::new(acc, to, head.block_hash, amount, nonce)?;
+    let id = rpc::call(addr, "tx_submit", json!([tx]))?;
+    println!("tx {} submitted to node {} at {}", id.as_str().unwrap_or_default(), holder.pid, addr);
+    Ok(())
+}
+
+fn chain_cmd(ctx: &Ctx, cmd: ChainCmd) -> Result<()> {
+    match cmd {
+        ChainCmd::Export { file } => {
+            let chain = Blockchain::load(ctx.config.chain_path(), &ctx.spec)?;
+            chain.save(&file)?;
+            println!("exported {} blocks to {:?}", chain.chain.len(), file);
+            Ok(())
+        }
+        ChainCmd::Import { file } => {
+            if !file.exists() {
+                bail!("no chain file at {:?}", file);
+            }
+            let chain = Blockchain::load(&file, &ctx.spec)?;
+            chain.verify(&ctx.spec)?;
+            let _lock = ctx.lock_data_dir()?;
+            chain.save(ctx.config.chain_path())?;
+            println!("imported {} blocks from {:?}", chain.chain.len(), file);
+            Ok(())
+        }
+        ChainCmd::Verify => {
+            let chain = Blockchain::load(ctx.config.chain_path(), &ctx.spec)?;
+            chain.verify(&ctx.spec)?;
+            println!("chain ok, {} blocks", chain.chain.len());
+            Ok(())
+        }
+        ChainCmd::Reindex => {
+            let chain = Blockchain::load(ctx.config.chain_path(), &ctx.spec)?;
+            let index = Indexer::build(&chain)?;
+            // a running node reindexes through admin_reindex
+            let _lock = ctx.lock_data_dir()?;
+            index.save(ctx.config.index_path())?;
+            println!(
+                "indexed {} blocks, {} txs, {} addresses",
+                index.height,
+                index.txs.len(),
+                index.addresses.len()
+            );
+            Ok(())
+        }
+        ChainCmd::Info => {
+            let chain = Blockchain::load(ctx.config.chain_path(), &ctx.spec)?;
+            println!("chain id:   {}", ctx.spec.chain_id);
+            println!("data dir:   {:?}", ctx.config.data_dir);
+            println!("blocks:     {}", chain.chain.len());
+            match chain.head() {
+                Some(head) => {
+                    println!("height:     {}", head.height);
+                    println!("head:       {}", head.block_hash);
+                    println!("validator:  {}", head.validator);
+                }
+                None => println!("head:       none"),
+            }
+            if let Some(mtp) = chain.median_time_past(&ctx.spec) {
+                println!("median time past: {}", mtp);
+            }
+            println!("max block weight: {}", ctx.spec.max_block_weight(
+                chain.head().map(|x| x.height + 1).unwrap_or(0),
+            ));
+            Ok(())
+        }
+    }
+}
+
+fn keys_cmd(ctx: &Ctx, cmd: KeysCmd) -> Result<()> {
+    match cmd {
+        KeysCmd::Inspect { key } => {
+            if let Ok(addr) = key.parse::<Address>() {
+                println!("valid address {}", addr);
+                return Ok(());
+            }
+            let public: PublicKey = key
+                .parse()
+                .map_err(|_| anyhow::anyhow!("{} is neither an address nor a public key", key))?;
+            println!("public key: {}", public);
+            println!("address:    {}", Address::from_public_key(&public));
+            match ctx.keystore()?.read_keyfile(&key) {
+                Ok(keyfile) => {
+                    println!("keystore:   yes (version {})", keyfile.version);
+                    println!(
+                        "kdf:        scrypt log_n={} r={} p={}",
+                        keyfile.kdf.log_n, keyfile.kdf.r, keyfile.kdf.p
+                    );
+                }
+                Err(_) => println!("keystore:   no"),
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("essex").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn the_command_tree_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn run_flags_override_the_config() {
+        let cli = parse(&[
+            "--data-dir",
+            "/tmp/essex-x",
+            "node",
+            "run",
+            "--listen",
+            "/ip4/127.0.0.1/tcp/4001,/ip4/127.0.0.1/udp/4001/quic-v1",
+            "--no-mdns",
+            "--rpc-listen",
+            "127.0.0.1:9944",
+            "--role",
+            "authority",
+        ])
+        .unwrap();
+        assert_eq!(cli.global.data_dir, Some(PathBuf::from("/tmp/essex-x")));
+        let Command::Node(NodeCmd::Run(args)) = cli.command else {
+            panic!("parsed into the wrong command");
+        };
+        let mut config = NodeConfig::default();
+        args.apply(&mut config);
+        assert_eq!(config.listen.len(), 2);
+        assert!(!config.discovery.mdns);
+        assert!(config.rpc.enabled);
+        assert_eq!(config.rpc.listen, "127.0.0.1:9944".parse().unwrap());
+        assert_eq!(config.role, NodeRole::Authority);
+        // flags left out keep what the config had
+        assert_eq!(config.gossip.topic, NodeConfig::default().gossip.topic);
+    }
+
+    #[test]
+    fn send_checks_the_address_before_anything_runs() {
+        let to = Address::from_bytes(&[1u8; 20]).unwrap().to_string();
+        assert!(parse(&["wallet", "send", "--from", "ab", "--to", &to, "--amount", "5"]).is_ok());
+        let last = if to.ends_with('q') { 'p' } else { 'q' };
+        let typo = format!("{}{}", &to[..to.len() - 1], last);
+        assert!(parse(&["wallet", "send", "--from", "ab", "--to", &typo, "--amount", "5"]).is_err());
+        assert!(parse(&["wallet", "send", "--from", "ab", "--to", &to, "--amount", "-1"]).is_err());
+    }
+
+    #[test]
+    fn passphrases_never_come_off_the_command_line() {
+        assert!(parse(&["wallet", "new", "--passphrase", "hunter2"]).is_err());
+        let path = std::env::temp_dir().join(format!("essex-pass-{:016x}", rand::random::<u64>()));
+        std::fs::write(&path, "hunter2\nignored\n").unwrap();
+        let cli = parse(&["wallet", "new", "--passphrase-file", path.to_str().unwrap()]).unwrap();
+        let Command::Wallet(WalletCmd::New { passphrase, .. }) = cli.command else {
+            panic!("parsed into the wrong command");
+        };
+        let file = passphrase.passphrase_file.unwrap();
+        assert_eq!(passphrase_from_file(&file).unwrap(), "hunter2");
+        std::fs::write(&path, "\n").unwrap();
+        assert!(passphrase_from_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,616 @@
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    address::address::Address,
+    block::block::{Block, BlockHeader},
+    multisig::multisig::{MultisigAccount, PartialSignature},
+    payload::payload::PrivatePayload,
+    sec8::sec8::{Sec8Envelope, Sec8WrappedKey},
+    timestamp::timestamp::Timestamp,
+    transaction::transaction::{BlockEntry, Transaction, TxHeader},
+};
+
+// bumped whenever the wire format changes,
+// every encoding starts with this byte
+pub const CODEC_VERSION: u8 = 1;
+// no single field may claim more than this
+pub const MAX_FIELD_LEN: usize = 16 * 1024 * 1024;
+
+/// Canonical binary encoding, integers are fixed width big
+/// endian, variable length fields carry a u32 length and
+/// struct fields are written in declaration order, so the
+/// same value always encodes to the same bytes
+pub trait Encode {
+    fn encode(&self, enc: &mut Encoder);
+}
+
+/// Strict decoding, anything the encoder would never have
+/// produced is an error rather than being normalised
+pub trait Decode: Sized {
+    fn decode(dec: &mut Decoder) -> Result<Self>;
+}
+
+#[derive(Debug, Default)]
+pub struct Encoder {
+    pub buf: Vec<u8>,
+}
+
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder::default()
+    }
+    pub fn put_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+    pub fn put_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+    pub fn put_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+    pub fn put_bytes(&mut self, v: &[u8]) {
+        self.put_u32(v.len() as u32);
+        self.buf.extend_from_slice(v);
+    }
+    pub fn put<T: Encode + ?Sized>(&mut self, v: &T) {
+        v.encode(self);
+    }
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Decoder { data, pos: 0 }
+    }
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            bail!("unexpected end of input at byte {}", self.pos);
+        }
+        let out = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+    pub fn get_u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn get_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+    pub fn get_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+    pub fn get_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.get_u32()? as usize;
+        if len > MAX_FIELD_LEN {
+            bail!("field of {} bytes exceeds {}", len, MAX_FIELD_LEN);
+        }
+        self.take(len)
+    }
+    pub fn get<T: Decode>(&mut self) -> Result<T> {
+        T::decode(self)
+    }
+    pub fn finish(self) -> Result<()> {
+        if self.pos != self.data.len() {
+            bail!("{} trailing bytes", self.data.len() - self.pos);
+        }
+        Ok(())
+    }
+}
+
+pub fn to_bytes<T: Encode + ?Sized>(v: &T) -> Vec<u8> {
+    let mut enc = Encoder::new();
+    enc.put_u8(CODEC_VERSION);
+    enc.put(v);
+    enc.buf
+}
+
+pub fn from_bytes<T: Decode>(data: &[u8]) -> Result<T> {
+    let mut dec = Decoder::new(data);
+    let version = dec.get_u8()?;
+    if version != CODEC_VERSION {
+        bail!("unsupported codec version {}", version);
+    }
+    let v = dec.get()?;
+    dec.finish()?;
+    Ok(v)
+}
+
+impl Encode for u8 {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put_u8(*self)
+    }
+}
+impl Decode for u8 {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        dec.get_u8()
+    }
+}
+impl Encode for u32 {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put_u32(*self)
+    }
+}
+impl Decode for u32 {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        dec.get_u32()
+    }
+}
+impl Encode for u64 {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put_u64(*self)
+    }
+}
+impl Decode for u64 {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        dec.get_u64()
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put_u8(*self as u8)
+    }
+}
+impl Decode for bool {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        match dec.get_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            x => bail!("non canonical bool {}", x),
+        }
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put_bytes(self.as_bytes())
+    }
+}
+impl Encode for String {
+    fn encode(&self, enc: &mut Encoder) {
+        self.as_str().encode(enc)
+    }
+}
+impl Decode for String {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        let raw = dec.get_bytes()?;
+        Ok(std::str::from_utf8(raw).context("invalid utf-8")?.to_string())
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put_u32(self.len() as u32);
+        for x in self {
+            x.encode(enc);
+        }
+    }
+}
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, enc: &mut Encoder) {
+        self.as_slice().encode(enc)
+    }
+}
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        let len = dec.get_u32()? as usize;
+        // every element takes at least a byte, a bigger
+        // count can only be a lie about the length
+        let remaining = dec.data.len() - dec.pos;
+        if len > remaining {
+            bail!("list of {} items longer than input", len);
+        }
+        let mut out = Vec::with_capacity(capacity_for::<T>(len, remaining));
+        for _ in 0..len {
+            out.push(dec.get()?);
+        }
+        Ok(out)
+    }
+}
+
+// room for `len` items up front, never more memory than
+// the input they claim to come from, big items grow the
+// list as they turn up
+fn capacity_for<T>(len: usize, remaining: usize) -> usize {
+    len.min(remaining / std::mem::size_of::<T>().max(1))
+}
+
+impl Encode for Address {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.buf.extend_from_slice(self.as_bytes())
+    }
+}
+impl Decode for Address {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        Address::from_bytes(dec.take(crate::address::address::ADDRESS_LEN)?)
+    }
+}
+
+impl Encode for Timestamp {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put_u64(self.as_millis())
+    }
+}
+impl Decode for Timestamp {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        Ok(Timestamp::from_millis(dec.get_u64()?))
+    }
+}
+
+impl Encode for BlockHeader {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put(&self.prev_hash);
+        enc.put(&self.validator);
+        enc.put(&self.validator_key);
+        enc.put(&self.data_root);
+        enc.put(&self.height);
+        enc.put(&self.timestamp);
+    }
+}
+impl Decode for BlockHeader {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        Ok(BlockHeader {
+            prev_hash: dec.get()?,
+            validator: dec.get()?,
+            validator_key: dec.get()?,
+            data_root: dec.get()?,
+            height: dec.get()?,
+            timestamp: dec.get()?,
+        })
+    }
+}
+
+impl Encode for Block {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put(&self.block_hash);
+        enc.put(&self.prev_hash);
+        enc.put(&self.validator);
+        enc.put(&self.validator_key);
+        enc.put(&self.signature);
+        enc.put(&self.block_data);
+        enc.put(&self.height);
+        enc.put(&self.valid);
+        enc.put(&self.timestamp);
+    }
+}
+impl Decode for Block {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        Ok(Block {
+            block_hash: dec.get()?,
+            prev_hash: dec.get()?,
+            validator: dec.get()?,
+            validator_key: dec.get()?,
+            signature: dec.get()?,
+            block_data: dec.get()?,
+            height: dec.get()?,
+            valid: dec.get()?,
+            timestamp: dec.get()?,
+        })
+    }
+}
+
+impl Encode for TxHeader {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put(&self.transaction_id);
+        enc.put(&self.transaction_signature);
+        enc.put(&self.transaction_valid);
+    }
+}
+impl Decode for TxHeader {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        Ok(TxHeader {
+            transaction_id: dec.get()?,
+            transaction_signature: dec.get()?,
+            transaction_valid: dec.get()?,
+        })
+    }
+}
+
+impl Encode for PartialSignature {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put(&self.public);
+        enc.put(&self.signature);
+    }
+}
+impl Decode for PartialSignature {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        Ok(PartialSignature {
+            public: dec.get()?,
+            signature: dec.get()?,
+        })
+    }
+}
+
+impl Encode for Transaction {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put(&self.tx_from);
+        enc.put(&self.tx_to);
+        enc.put(&self.tx_public);
+        enc.put(&self.tx_nonce);
+        enc.put(&self.timestamp);
+        enc.put(&self.tx_header);
+        enc.put(&self.tx_block_hash);
+        enc.put(&self.tx_amount);
+        enc.put(&self.tx_signatures);
+    }
+}
+impl Decode for Transaction {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        Ok(Transaction {
+            tx_from: dec.get()?,
+            tx_to: dec.get()?,
+            tx_public: dec.get()?,
+            tx_nonce: dec.get()?,
+            timestamp: dec.get()?,
+            tx_header: dec.get()?,
+            tx_block_hash: dec.get()?,
+            tx_amount: dec.get()?,
+            tx_signatures: dec.get()?,
+        })
+    }
+}
+
+impl Encode for MultisigAccount {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put(&self.threshold);
+        enc.put(&self.keys);
+    }
+}
+impl Decode for MultisigAccount {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        let ms = MultisigAccount {
+            threshold: dec.get()?,
+            keys: dec.get()?,
+        };
+        // sorted keys, so one key set has one encoding
+        ms.check()?;
+        Ok(ms)
+    }
+}
+
+impl Encode for Sec8WrappedKey {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put(&self.public);
+        enc.put(&self.ephemeral);
+        enc.put(&self.nonce);
+        enc.put(&self.wrapped);
+    }
+}
+impl Decode for Sec8WrappedKey {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        Ok(Sec8WrappedKey {
+            public: dec.get()?,
+            ephemeral: dec.get()?,
+            nonce: dec.get()?,
+            wrapped: dec.get()?,
+        })
+    }
+}
+
+impl Encode for Sec8Envelope {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put(&self.version);
+        enc.put(&self.recipients);
+        enc.put(&self.nonce);
+        enc.put(&self.ciphertext);
+    }
+}
+impl Decode for Sec8Envelope {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        Ok(Sec8Envelope {
+            version: dec.get()?,
+            recipients: dec.get()?,
+            nonce: dec.get()?,
+            ciphertext: dec.get()?,
+        })
+    }
+}
+
+impl Encode for PrivatePayload {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.put(&self.commitment);
+        enc.put(&self.envelope);
+    }
+}
+impl Decode for PrivatePayload {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        Ok(PrivatePayload {
+            commitment: dec.get()?,
+            envelope: dec.get()?,
+        })
+    }
+}
+
+impl Encode for BlockEntry {
+    fn encode(&self, enc: &mut Encoder) {
+        match self {
+            BlockEntry::Transfer(x) => {
+                enc.put_u8(0);
+                enc.put(x.as_ref());
+            }
+            BlockEntry::RegisterMultisig(x) => {
+                enc.put_u8(1);
+                enc.put(x);
+            }
+            BlockEntry::Private(x) => {
+                enc.put_u8(2);
+                enc.put(x);
+            }
+            BlockEntry::Record { data } => {
+                enc.put_u8(3);
+                enc.put(data);
+            }
+        }
+    }
+}
+impl Decode for BlockEntry {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        match dec.get_u8()? {
+            0 => Ok(BlockEntry::Transfer(Box::new(dec.get()?))),
+            1 => Ok(BlockEntry::RegisterMultisig(dec.get()?)),
+            2 => Ok(BlockEntry::Private(dec.get()?)),
+            3 => Ok(BlockEntry::Record { data: dec.get()? }),
+            x => bail!("unknown block entry tag {}", x),
+        }
+    }
+}
+
+/// Everything nodes gossip to each other
+#[derive(Debug, Clone)]
+pub enum NetMessage {
+    Text(String),
+    Block(Box<Block>),
+    Transaction(Box<Transaction>),
+    // sender's clock, feeds the network adjusted time
+    Clock(Timestamp),
+    // sender's best block, fills in the peer table
+    Status { best_height: u64, best_hash: String },
+}
+
+impl NetMessage {
+    // the variant tag of an encoded message, read off
+    // the front without decoding the rest
+    pub fn peek_tag(data: &[u8]) -> Option<u8> {
+        match data {
+            [CODEC_VERSION, tag, ..] => Some(*tag),
+            _ => None,
+        }
+    }
+}
+
+impl Encode for NetMessage {
+    fn encode(&self, enc: &mut Encoder) {
+        match self {
+            NetMessage::Text(x) => {
+                enc.put_u8(0);
+                enc.put(x);
+            }
+            NetMessage::Block(x) => {
+                enc.put_u8(1);
+                enc.put(x.as_ref());
+            }
+            NetMessage::Transaction(x) => {
+                enc.put_u8(2);
+                enc.put(x.as_ref());
+            }
+            NetMessage::Clock(x) => {
+                enc.put_u8(3);
+                enc.put(x);
+            }
+            NetMessage::Status {
+                best_height,
+                best_hash,
+            } => {
+                enc.put_u8(4);
+                enc.put(best_height);
+                enc.put(best_hash);
+            }
+        }
+    }
+}
+impl Decode for NetMessage {
+    fn decode(dec: &mut Decoder) -> Result<Self> {
+        match dec.get_u8()? {
+            0 => Ok(NetMessage::Text(dec.get()?)),
+            1 => Ok(NetMessage::Block(Box::new(dec.get()?))),
+            2 => Ok(NetMessage::Transaction(Box::new(dec.get()?))),
+            3 => Ok(NetMessage::Clock(dec.get()?)),
+            4 => Ok(NetMessage::Status {
+                best_height: dec.get()?,
+                best_hash: dec.get()?,
+            }),
+            x => bail!("unknown message tag {}", x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::account::Account;
+
+    fn block_with_every_entry() -> Block {
+        let alice = Account::create().unwrap();
+        let bob = Account::create().unwrap();
+        let tx = Transaction::new(&alice, bob.address(), "head".to_string(), 5, 0).unwrap();
+        let ms = MultisigAccount::new(2, &[alice.acc_public, bob.acc_public]).unwrap();
+        let private = PrivatePayload::seal(&[bob.acc_public], b"for bob").unwrap();
+        let mut blk = Block {
+            block_data: vec![
+                BlockEntry::Transfer(Box::new(tx)),
+                BlockEntry::RegisterMultisig(ms),
+                BlockEntry::Private(private),
+                BlockEntry::record("hello"),
+            ],
+            height: 7,
+            ..Block::default()
+        };
+        blk.sign(&alice);
+        blk
+    }
+
+    #[test]
+    fn blocks_round_trip_to_the_same_bytes() {
+        let blk = block_with_every_entry();
+        let bytes = to_bytes(&blk);
+        let back: Block = from_bytes(&bytes).unwrap();
+        assert_eq!(to_bytes(&back), bytes);
+        assert_eq!(back.data_root(), blk.data_root());
+        back.verify_signature().unwrap();
+        let msg = NetMessage::Block(Box::new(blk));
+        let back: NetMessage = from_bytes(&to_bytes(&msg)).unwrap();
+        assert!(matches!(back, NetMessage::Block(x) if x.height == 7));
+    }
+
+    #[test]
+    fn data_root_commits_to_every_entry() {
+        let mut blk = block_with_every_entry();
+        let root = blk.data_root();
+        blk.block_data[3] = BlockEntry::record("hellp");
+        assert_ne!(blk.data_root(), root);
+        assert!(blk.verify_signature().is_err());
+    }
+
+    #[test]
+    fn rejects_what_the_encoder_never_produces() {
+        let bytes = to_bytes(&block_with_every_entry());
+        // trailing bytes
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(from_bytes::<Block>(&longer).is_err());
+        // cut short
+        assert!(from_bytes::<Block>(&bytes[..bytes.len() - 1]).is_err());
+        // another codec version
+        let mut version = bytes.clone();
+        version[0] = CODEC_VERSION + 1;
+        assert!(from_bytes::<Block>(&version).is_err());
+        // a bool that isn't 0 or 1
+        assert!(from_bytes::<bool>(&[CODEC_VERSION, 2]).is_err());
+        // an entry tag nobody knows
+        assert!(from_bytes::<BlockEntry>(&[CODEC_VERSION, 9]).is_err());
+        // a list claiming more items than there are bytes
+        assert!(from_bytes::<Vec<u8>>(&[CODEC_VERSION, 0, 0, 0, 9, 1]).is_err());
+        // a count as long as the input is no reason to reserve
+        // room for that many entries
+        let mut lying = vec![CODEC_VERSION, 0, 0, 0x10, 0];
+        lying.extend(vec![0u8; 0x1000]);
+        assert!(from_bytes::<Vec<BlockEntry>>(&lying).is_err());
+        let room = capacity_for::<BlockEntry>(0x1000, 0x1000);
+        assert!(room * std::mem::size_of::<BlockEntry>() <= 0x1000);
+        assert_eq!(capacity_for::<u8>(10, 0x1000), 10);
+    }
+
+    #[test]
+    fn rejects_multisig_keys_out_of_order() {
+        let a = Account::create().unwrap().acc_public;
+        let b = Account::create().unwrap().acc_public;
+        let mut ms = MultisigAccount::new(1, &[a, b]).unwrap();
+        ms.keys.reverse();
+        let entry = BlockEntry::RegisterMultisig(ms);
+        assert!(from_bytes::<BlockEntry>(&to_bytes(&entry)).is_err());
+    }
+}
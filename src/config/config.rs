@@ -0,0 +1,576 @@
+use anyhow::{bail, Context, Result};
+use libp2p::identity::Keypair;
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::address::address::Address;
+use crate::chainspec::chainspec::ChainSpec;
+use crate::identity::identity::{ValidatorAttestation, ATTESTATION_FILE, NODE_KEY_FILE};
+use crate::transaction::transaction::{MAX_POOL_TXS, MAX_POOL_TXS_PER_SENDER};
+
+pub const DEFAULT_DATA_DIR: &str = ".essex";
+pub const DEFAULT_TOPIC: &str = "test-net";
+pub const CHAIN_FILE: &str = "blockchain.json";
+pub const MEMPOOL_FILE: &str = "mempool.json";
+pub const KEYSTORE_DIR: &str = "keystore";
+pub const BANS_FILE: &str = "bans.json";
+pub const PEERS_FILE: &str = "peers.json";
+pub const INDEX_FILE: &str = "index.json";
+// held by the node running on the data dir
+pub const LOCK_FILE: &str = "LOCK";
+
+/// What the node does on the network, only an
+/// authority produces blocks, sealing its mempool
+/// every `block_interval_secs` with its validator key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeRole {
+    Authority,
+    #[default]
+    Full,
+}
+
+impl fmt::Display for NodeRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeRole::Authority => write!(f, "authority"),
+            NodeRole::Full => write!(f, "full"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GossipConfig {
+    pub topic: String,
+    pub heartbeat_ms: u64,
+    // target, low and high watermarks of the mesh
+    pub mesh_n: usize,
+    pub mesh_n_low: usize,
+    pub mesh_n_high: usize,
+    // heartbeats a message id is remembered for
+    pub history_length: usize,
+    // largest message accepted, derived from the
+    // chain spec block weight when unset
+    pub max_transmit_size: Option<usize>,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        GossipConfig {
+            topic: DEFAULT_TOPIC.to_string(),
+            heartbeat_ms: 10_000,
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            history_length: 5,
+            max_transmit_size: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // tracing filter directive, RUST_LOG wins when set
+    pub level: String,
+    pub ansi: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_string(),
+            ansi: true,
+        }
+    }
+}
+
+/// The JSON-RPC server, off unless asked for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    pub enabled: bool,
+    pub listen: SocketAddr,
+    // admin methods are refused unless this is set and
+    // sent as `Authorization: Bearer <token>`
+    pub admin_token: Option<String>,
+    // notifications queued for a websocket client
+    // before it is dropped as too slow
+    pub ws_buffer: usize,
+    pub ws_max_subscriptions: usize,
+    // html pages under /explorer
+    pub explorer: bool,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        RpcConfig {
+            enabled: false,
+            listen: "127.0.0.1:8645".parse().unwrap(),
+            admin_token: None,
+            ws_buffer: 256,
+            ws_max_subscriptions: 16,
+            explorer: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    // local network discovery, off for nodes that
+    // should only learn peers from bootstrap and dht
+    pub mdns: bool,
+    // seconds between kademlia random walks
+    pub random_walk_secs: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            mdns: true,
+            random_walk_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReputationConfig {
+    // scores are clamped to at most this
+    pub max_score: f64,
+    pub disconnect_threshold: f64,
+    pub ban_threshold: f64,
+    // a score halves towards zero every half life
+    pub half_life_secs: u64,
+    // first ban, repeat offenders get twice the last
+    pub ban_secs: u64,
+    pub max_ban_secs: u64,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        ReputationConfig {
+            max_score: 100.0,
+            disconnect_threshold: -40.0,
+            ban_threshold: -80.0,
+            half_life_secs: 600,
+            ban_secs: 3600,
+            max_ban_secs: 24 * 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateConfig {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+impl RateConfig {
+    pub fn new(per_sec: f64, burst: f64) -> Self {
+        RateConfig { per_sec, burst }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_incoming: u32,
+    pub max_outgoing: u32,
+    pub max_pending_incoming: u32,
+    pub max_pending_outgoing: u32,
+    pub max_per_peer: u32,
+    pub max_per_ip: u32,
+    // gossip waiting for chain processing, when
+    // full new messages are dropped unforwarded
+    pub queue_capacity: usize,
+    // per peer budgets for each kind of gossip
+    pub blocks: RateConfig,
+    pub transactions: RateConfig,
+    pub messages: RateConfig,
+    pub control: RateConfig,
+    // txs the mempool holds in all and from one sender,
+    // see TransactionPool::admit
+    pub max_pool_txs: usize,
+    pub max_pool_txs_per_sender: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_incoming: 64,
+            max_outgoing: 32,
+            max_pending_incoming: 32,
+            max_pending_outgoing: 32,
+            max_per_peer: 2,
+            max_per_ip: 8,
+            queue_capacity: 256,
+            blocks: RateConfig::new(2.0, 20.0),
+            transactions: RateConfig::new(50.0, 200.0),
+            messages: RateConfig::new(5.0, 20.0),
+            control: RateConfig::new(1.0, 10.0),
+            max_pool_txs: MAX_POOL_TXS,
+            max_pool_txs_per_sender: MAX_POOL_TXS_PER_SENDER,
+        }
+    }
+}
+
+/// Everything a node needs to start, read from a TOML
+/// file where every key is optional
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    // must match the spec, taken from it when unset
+    pub chain_id: Option<String>,
+    // JSON chain spec, the built in test-net when unset
+    pub chain_spec: Option<PathBuf>,
+    pub role: NodeRole,
+    // how often an authority seals what is queued
+    pub block_interval_secs: u64,
+    pub data_dir: PathBuf,
+    pub listen: Vec<Multiaddr>,
+    pub bootstrap: Vec<Multiaddr>,
+    pub discovery: DiscoveryConfig,
+    pub gossip: GossipConfig,
+    pub reputation: ReputationConfig,
+    pub limits: LimitsConfig,
+    pub rpc: RpcConfig,
+    pub log: LogConfig,
+    // how long a stopping node gets to finish its work,
+    // flush to disk and say goodbye to its peers
+    pub shutdown_deadline_secs: u64,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            chain_id: None,
+            chain_spec: None,
+            role: NodeRole::default(),
+            block_interval_secs: 5,
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            listen: vec![
+                "/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap(),
+                "/ip4/0.0.0.0/tcp/0".parse().unwrap(),
+            ],
+            bootstrap: vec![],
+            discovery: DiscoveryConfig::default(),
+            gossip: GossipConfig::default(),
+            reputation: ReputationConfig::default(),
+            limits: LimitsConfig::default(),
+            rpc: RpcConfig::default(),
+            log: LogConfig::default(),
+            shutdown_deadline_secs: 10,
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<NodeConfig> {
+        let path = path.as_ref();
+        let raw = std::fs::read_to_string(path).with_context(|| format!("reading {:?}", path))?;
+        let config: NodeConfig =
+            toml::from_str(&raw).with_context(|| format!("parsing {:?}", path))?;
+        config.check()?;
+        Ok(config)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        let g = &self.gossip;
+        if !(g.mesh_n_low <= g.mesh_n && g.mesh_n <= g.mesh_n_high) {
+            bail!(
+                "gossip mesh needs mesh_n_low <= mesh_n <= mesh_n_high, got {} {} {}",
+                g.mesh_n_low,
+                g.mesh_n,
+                g.mesh_n_high
+            );
+        }
+        if g.heartbeat_ms == 0 {
+            bail!("gossip heartbeat_ms must be above zero");
+        }
+        if g.topic.is_empty() {
+            bail!("gossip topic must not be empty");
+        }
+        if self.discovery.random_walk_secs == 0 {
+            bail!("discovery random_walk_secs must be above zero");
+        }
+        let r = &self.reputation;
+        if !(r.ban_threshold < r.disconnect_threshold && r.disconnect_threshold < 0.0) {
+            bail!("reputation needs ban_threshold < disconnect_threshold < 0");
+        }
+        if r.max_score <= 0.0 || r.ban_secs == 0 || r.max_ban_secs < r.ban_secs {
+            bail!("reputation needs max_score > 0 and 0 < ban_secs <= max_ban_secs");
+        }
+        let l = &self.limits;
+        if l.queue_capacity == 0 || l.max_per_peer == 0 || l.max_per_ip == 0 {
+            bail!("limits need queue_capacity, max_per_peer and max_per_ip above zero");
+        }
+        if l.max_pool_txs_per_sender == 0 || l.max_pool_txs < l.max_pool_txs_per_sender {
+            bail!("limits need 0 < max_pool_txs_per_sender <= max_pool_txs");
+        }
+        for rate in [&l.blocks, &l.transactions, &l.messages, &l.control] {
+            if rate.per_sec <= 0.0 || rate.burst < 1.0 {
+                bail!("rate limits need per_sec > 0 and burst >= 1");
+            }
+        }
+        if self.rpc.admin_token.as_deref() == Some("") {
+            bail!("rpc admin_token must not be empty, leave it out to turn admin methods off");
+        }
+        if self.rpc.ws_buffer == 0 || self.rpc.ws_max_subscriptions == 0 {
+            bail!("rpc ws_buffer and ws_max_subscriptions must be above zero");
+        }
+        if self.shutdown_deadline_secs == 0 {
+            bail!("shutdown_deadline_secs must be above zero");
+        }
+        if self.block_interval_secs == 0 {
+            bail!("block_interval_secs must be above zero");
+        }
+        Ok(())
+    }
+
+    // the spec the config points at, refusing one
+    // whose chain id disagrees with the config
+    pub fn chain_spec(&self) -> Result<ChainSpec> {
+        let spec = match &self.chain_spec {
+            Some(path) => ChainSpec::load(&path.to_string_lossy())?,
+            None => {
+                let mut spec = ChainSpec::default();
+                if let Some(id) = &self.chain_id {
+                    spec.chain_id = id.clone();
+                }
+                spec
+            }
+        };
+        if let Some(id) = &self.chain_id {
+            if *id != spec.chain_id {
+                bail!("config chain id {} does not match spec chain id {}", id, spec.chain_id);
+            }
+        }
+        Ok(spec)
+    }
+
+    pub fn chain_path(&self) -> PathBuf {
+        self.data_dir.join(CHAIN_FILE)
+    }
+
+    pub fn mempool_path(&self) -> PathBuf {
+        self.data_dir.join(MEMPOOL_FILE)
+    }
+
+    pub fn keystore_dir(&self) -> PathBuf {
+        self.data_dir.join(KEYSTORE_DIR)
+    }
+
+    pub fn bans_path(&self) -> PathBuf {
+        self.data_dir.join(BANS_FILE)
+    }
+
+    pub fn peers_path(&self) -> PathBuf {
+        self.data_dir.join(PEERS_FILE)
+    }
+
+    pub fn index_path(&self) -> PathBuf {
+        self.data_dir.join(INDEX_FILE)
+    }
+
+    pub fn lock_path(&self) -> PathBuf {
+        self.data_dir.join(LOCK_FILE)
+    }
+
+    pub fn node_key_path(&self) -> PathBuf {
+        self.data_dir.join(NODE_KEY_FILE)
+    }
+
+    pub fn attestation_path(&self) -> PathBuf {
+        self.data_dir.join(ATTESTATION_FILE)
+    }
+}
+
+/// What a running node leaves in the LOCK file of its
+/// data dir, so the CLI can reach it instead of writing
+/// files the node owns
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub pid: u32,
+    pub rpc: Option<SocketAddr>,
+}
+
+impl LockInfo {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<LockInfo> {
+        let raw = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("reading {:?}", path.as_ref()))?;
+        Ok(serde_json::from_str(&raw)?)
+    }
+}
+
+/// What a node is running with, as configured and as
+/// detected from the host, for the banner and `status`
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeInfo {
+    pub version: String,
+    pub chain_id: String,
+    pub chain_spec: String,
+    pub role: NodeRole,
+    pub data_dir: PathBuf,
+    pub chain_file: PathBuf,
+    pub listen: Vec<Multiaddr>,
+    pub bootstrap: Vec<Multiaddr>,
+    pub mdns: bool,
+    pub topic: String,
+    pub rpc: Option<SocketAddr>,
+    pub explorer: bool,
+    pub log_level: String,
+    pub os: String,
+    pub arch: String,
+    pub cpus: usize,
+    // only known once the node key exists
+    pub peer_id: Option<String>,
+    pub validator: Option<Address>,
+}
+
+impl NodeInfo {
+    pub fn detect(config: &NodeConfig, spec: &ChainSpec) -> NodeInfo {
+        NodeInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            chain_id: spec.chain_id.clone(),
+            chain_spec: match &config.chain_spec {
+                Some(path) => path.display().to_string(),
+                None => "built in".to_string(),
+            },
+            role: config.role,
+            data_dir: config.data_dir.clone(),
+            chain_file: config.chain_path(),
+            listen: config.listen.clone(),
+            bootstrap: config.bootstrap.clone(),
+            mdns: config.discovery.mdns,
+            topic: config.gossip.topic.clone(),
+            rpc: config.rpc.enabled.then_some(config.rpc.listen),
+            explorer: config.rpc.enabled && config.rpc.explorer,
+            log_level: std::env::var("RUST_LOG").unwrap_or_else(|_| config.log.level.clone()),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            cpus: std::thread::available_parallelism()
+                .map(|x| x.get())
+                .unwrap_or(1),
+            peer_id: std::fs::read(config.node_key_path())
+                .ok()
+                .and_then(|raw| Keypair::from_protobuf_encoding(&raw).ok())
+                .map(|key| key.public().to_peer_id().to_string()),
+            validator: ValidatorAttestation::load(config.attestation_path())
+                .ok()
+                .map(|att| att.validator),
+        }
+    }
+}
+
+fn join_addrs(addrs: &[Multiaddr]) -> String {
+    if addrs.is_empty() {
+        return "none".to_string();
+    }
+    addrs.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+impl fmt::Display for NodeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version:      {}", self.version)?;
+        writeln!(f, "chain id:     {}", self.chain_id)?;
+        writeln!(f, "chain spec:   {}", self.chain_spec)?;
+        writeln!(f, "role:         {}", self.role)?;
+        writeln!(f, "data dir:     {}", self.data_dir.display())?;
+        writeln!(f, "chain file:   {}", self.chain_file.display())?;
+        writeln!(f, "listen:       {}", join_addrs(&self.listen))?;
+        writeln!(f, "bootstrap:    {}", join_addrs(&self.bootstrap))?;
+        writeln!(f, "mdns:         {}", if self.mdns { "on" } else { "off" })?;
+        writeln!(f, "topic:        {}", self.topic)?;
+        match &self.rpc {
+            Some(addr) => writeln!(f, "rpc:          {}", addr)?,
+            None => writeln!(f, "rpc:          off")?,
+        }
+        writeln!(f, "explorer:     {}", if self.explorer { "on" } else { "off" })?;
+        writeln!(f, "log level:    {}", self.log_level)?;
+        writeln!(f, "os:           {}", self.os)?;
+        writeln!(f, "architecture: {}", self.arch)?;
+        writeln!(f, "cpus:         {}", self.cpus)?;
+        match &self.peer_id {
+            Some(id) => writeln!(f, "peer id:      {}", id)?,
+            None => writeln!(f, "peer id:      not generated yet")?,
+        }
+        match &self.validator {
+            Some(addr) => write!(f, "validator:    {}", addr),
+            None => write!(f, "validator:    none"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_file(contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("essex-config-{:016x}.toml", rand::random::<u64>()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn a_partial_file_keeps_the_other_defaults() {
+        let path = scratch_file("role = \"authority\"\n[gossip]\ntopic = \"dev-net\"\n[rpc]\nenabled = true\n");
+        let config = NodeConfig::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(config.role, NodeRole::Authority);
+        assert_eq!(config.gossip.topic, "dev-net");
+        assert_eq!(config.gossip.mesh_n, GossipConfig::default().mesh_n);
+        assert!(config.rpc.enabled);
+        assert_eq!(config.data_dir, PathBuf::from(DEFAULT_DATA_DIR));
+    }
+
+    #[test]
+    fn unknown_keys_and_bad_values_are_refused() {
+        let bad_files = [
+            "colour = \"blue\"\n",
+            "[gossip]\nmesh_n = 20\n",
+            "shutdown_deadline_secs = 0\n",
+            "block_interval_secs = 0\n",
+            "[rpc]\nadmin_token = \"\"\n",
+        ];
+        for bad in bad_files {
+            let path = scratch_file(bad);
+            assert!(NodeConfig::load(&path).is_err(), "accepted {:?}", bad);
+            std::fs::remove_file(path).unwrap();
+        }
+        NodeConfig::default().check().unwrap();
+    }
+
+    #[test]
+    fn chain_id_has_to_agree_with_the_spec() {
+        let mut config = NodeConfig {
+            chain_id: Some("essex-dev".to_string()),
+            ..NodeConfig::default()
+        };
+        assert_eq!(config.chain_spec().unwrap().chain_id, "essex-dev");
+        let spec = ChainSpec::default();
+        let path = std::env::temp_dir().join(format!("essex-spec-{:016x}.json", rand::random::<u64>()));
+        std::fs::write(&path, serde_json::to_string(&spec).unwrap()).unwrap();
+        config.chain_spec = Some(path.clone());
+        let res = config.chain_spec();
+        std::fs::remove_file(path).unwrap();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn banner_reports_what_the_node_runs_with() {
+        let mut config = NodeConfig::default();
+        config.rpc.enabled = true;
+        let info = NodeInfo::detect(&config, &ChainSpec::default());
+        assert_eq!(info.rpc, Some(config.rpc.listen));
+        assert_eq!(info.chain_spec, "built in");
+        assert!(info.cpus >= 1);
+        assert!(info.to_string().contains(&info.chain_id));
+    }
+}
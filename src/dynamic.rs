@@ -1,73 +1,159 @@
-use futures::stream::StreamExt;
-use libp2p::{gossipsub, mdns, noise, swarm::NetworkBehaviour, swarm::SwarmEvent, tcp, yamux};
-use libp2p::{Multiaddr, PeerId, Swarm};
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::{allow_block_list, connection_limits, gossipsub, identify, kad, mdns, noise, ping, swarm::NetworkBehaviour, tcp, yamux};
+use libp2p::{Multiaddr, PeerId, StreamProtocol, Swarm};
 use log::info;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashSet;
-use std::env;
 use std::hash::{Hash, Hasher};
+use std::path::Path;
 use std::time::Duration;
-use tokio::io::{self, AsyncBufReadExt};
-use tokio::select;
-use tracing_subscriber::EnvFilter;
 
-use crate::account::account;
-use crate::block::block::{self, _BlockT};
-use crate::blockchain::blockchain;
+use crate::chainspec::chainspec::ChainSpec;
+use crate::config::config::{NodeConfig, NodeInfo};
+use crate::limits::limits::{self, IpLimits};
+use crate::peers::peers::{NodeProtocol, PeerTable};
+use crate::reputation::reputation::{self, PeerEvent, Reputation, Verdict};
+use crate::codec::codec::{self, NetMessage};
+use crate::timestamp::timestamp::Timestamp;
+use crate::weight::weight;
 
 #[derive(NetworkBehaviour)]
 pub struct EssexBehaviour {
+    // banned peers, checked before anything else
+    pub blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
+    pub limits: connection_limits::Behaviour,
+    pub ip_limits: IpLimits,
     pub gossipsub: gossipsub::Behaviour,
-    pub mdns: mdns::tokio::Behaviour,
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
+    pub kad: kad::Behaviour<kad::store::MemoryStore>,
+    // agent, chain and listen addresses of each peer
+    pub identify: identify::Behaviour,
+    pub ping: ping::Behaviour,
 }
 
-pub fn block_handler() {
-    let genesis = block::Block::new();
-    let account = account::Account::create("hello").unwrap();
-    let cb = <block::Block as _BlockT>::create_essex_block(genesis, account, "hello").unwrap();
-    let bk = blockchain::Blockchain::_add_block_to_chain(cb.clone());
-    if bk.chain.len() > 0 {
-        // this calls the latest block hash
-        // on the first block on the chain
-        println!(
-            "🌈 Latest known block: {}",
-            bk.chain.get(0).unwrap().block_hash
-        );
+pub fn agent_version() -> String {
+    format!("essex-rs/{}", env!("CARGO_PKG_VERSION"))
+}
+
+// nodes on different chains keep separate dhts
+pub fn kad_protocol(chain_id: &str) -> Result<StreamProtocol, libp2p::swarm::InvalidProtocol> {
+    StreamProtocol::try_from_owned(format!("/essex/{}/kad/1.0.0", chain_id))
+}
+
+// bootstrap addresses end in /p2p/<peer id>, kademlia
+// wants the id and the address in front of it apart
+pub fn split_peer_id(addr: &Multiaddr) -> Option<(PeerId, Multiaddr)> {
+    let mut base = addr.clone();
+    match base.pop() {
+        Some(Protocol::P2p(peer_id)) => Some((peer_id, base)),
+        _ => None,
     }
 }
 
-pub fn _get_nodes(swarm: &Swarm<EssexBehaviour>) -> Vec<String> {
-    let nodes = swarm.behaviour().mdns.discovered_nodes();
-    let mut unique_nodes = HashSet::new();
-    for leafs in nodes {
-        unique_nodes.insert(leafs);
+// gossip from peers is size checked before decoding and
+// blocks are weight checked against the limit at their
+// height before anything else looks at them
+pub fn decode_gossip_message(data: &[u8], spec: &ChainSpec) -> anyhow::Result<NetMessage> {
+    let max_size = weight::max_encoded_block_size(spec.peak_block_weight());
+    if data.len() > max_size {
+        anyhow::bail!("gossip message of {} bytes exceeds {}", data.len(), max_size);
     }
-    unique_nodes.iter().map(|x| x.to_string()).collect()
+    let msg: NetMessage = codec::from_bytes(data)?;
+    if let NetMessage::Block(gblock) = &msg {
+        weight::check_block_weight(&gblock.block_data, spec.max_block_weight(gblock.height))?;
+    }
+    Ok(msg)
+}
+
+pub fn _get_nodes(peers: &PeerTable) -> Vec<String> {
+    peers.connected().map(|x| x.to_string()).collect()
 }
 
-pub fn _print_nodes(swarm: &Swarm<EssexBehaviour>) {
-    let nodes = _get_nodes(swarm);
+pub fn _print_nodes(peers: &PeerTable) {
+    let nodes = _get_nodes(peers);
     nodes.iter().for_each(|x| info!("{}\n", x))
 }
 
-pub fn _blacklist_invalid_tx(leafval: i32, node: &PeerId, swarm: &mut Swarm<EssexBehaviour>) {
-    let min_val = 5;
-    if leafval < min_val {
-        info!("blacklisted: {}\n", node);
-        swarm.behaviour_mut().gossipsub.blacklist_peer(node);
+pub fn ban_peer(swarm: &mut Swarm<EssexBehaviour>, peer: &PeerId) {
+    swarm.behaviour_mut().blocked.block_peer(*peer);
+    swarm.behaviour_mut().gossipsub.blacklist_peer(peer);
+}
+
+pub fn unban_peer(swarm: &mut Swarm<EssexBehaviour>, peer: &PeerId) {
+    swarm.behaviour_mut().blocked.unblock_peer(*peer);
+    swarm.behaviour_mut().gossipsub.remove_blacklisted_peer(peer);
+}
+
+// scores `event` against the peer and acts on the
+// verdict, a new ban is saved to `bans_path` at once
+pub fn report_peer(
+    swarm: &mut Swarm<EssexBehaviour>,
+    reputation: &mut Reputation,
+    peer: &PeerId,
+    event: PeerEvent,
+    bans_path: &Path,
+) -> Verdict {
+    let now = Timestamp::now();
+    let verdict = reputation.report(peer, event, now);
+    swarm
+        .behaviour_mut()
+        .gossipsub
+        .set_application_score(peer, reputation.score(peer, now));
+    match verdict {
+        Verdict::Keep => {}
+        Verdict::Disconnect => {
+            info!("disconnecting peer {} after {:?}", peer, event);
+            let _ = swarm.disconnect_peer_id(*peer);
+        }
+        Verdict::Ban(until) => {
+            println!("banned peer {peer} until {until}");
+            ban_peer(swarm, peer);
+            if let Err(e) = reputation.save(bans_path) {
+                println!("cannot save ban list: {e}");
+            }
+        }
     }
+    verdict
 }
 
-#[tokio::main]
-pub async fn _essex_sim<'a>(
-    enode_topic: &'a str,
-    enode_addr: Multiaddr,
-    enode_addr_2: Multiaddr,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .try_init();
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+pub fn print_banner(node: &NodeInfo) {
+    println!("🆚 Chain Verx: v{}", node.version);
+    println!("👨🏾‍💻 Chain Devx: Jim Nnamdi");
+    println!("🚀 Chain Specs: {} ({})", node.chain_id, node.chain_spec);
+    println!("🧰 Chain Role: {}", node.role);
+    println!("🛢 Chain DBX: {}", node.chain_file.display());
+    println!("🎱 Operating system: {}", node.os);
+    println!("🧶 Architecture: {}", node.arch);
+    if let Some(id) = &node.peer_id {
+        println!("🆔 Peer Id: {}", id);
+    }
+    if let Some(addr) = &node.validator {
+        println!("🔏 Validator: {}", addr);
+    }
+    if let Some(addr) = &node.rpc {
+        println!("🔌 RPC: http://{}", addr);
+        if node.explorer {
+            println!("🔎 Explorer: http://{}/explorer", addr);
+        }
+    }
+}
+
+// the full behaviour stack for `config`, announcing
+// `local_protocol` to peers over identify
+pub fn build_swarm(
+    config: &NodeConfig,
+    spec: &ChainSpec,
+    node_key: libp2p::identity::Keypair,
+    local_protocol: &NodeProtocol,
+) -> anyhow::Result<Swarm<EssexBehaviour>> {
+    let gossip = config.gossip.clone();
+    let max_transmit = gossip
+        .max_transmit_size
+        .unwrap_or_else(|| weight::max_encoded_block_size(spec.peak_block_weight()));
+    let gtopic = gossipsub::IdentTopic::new(&gossip.topic);
+    let discovery = config.discovery.clone();
+    let kad_proto = kad_protocol(&spec.chain_id)?;
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(node_key)
         .with_tokio()
         .with_tcp(
             tcp::Config::default(),
@@ -82,76 +168,80 @@ pub async fn _essex_sim<'a>(
                 gossipsub::MessageId::from(s.finish().to_string())
             };
             let goss_config = gossipsub::ConfigBuilder::default()
-                .heartbeat_interval(Duration::from_secs(10))
+                .heartbeat_interval(Duration::from_millis(gossip.heartbeat_ms))
+                .mesh_n(gossip.mesh_n)
+                .mesh_n_low(gossip.mesh_n_low)
+                .mesh_n_high(gossip.mesh_n_high)
+                .history_length(gossip.history_length)
                 .validation_mode(gossipsub::ValidationMode::Strict)
+                // nothing is forwarded until we have checked it
+                .validate_messages()
                 .message_id_fn(msg_fn_id)
+                .max_transmit_size(max_transmit)
                 .build()
                 .map_err(|x| print!("{}", x))
                 .unwrap();
-            let gossipsub = gossipsub::Behaviour::new(
+            let mut gossipsub = gossipsub::Behaviour::new(
                 gossipsub::MessageAuthenticity::Signed(key.clone()),
                 goss_config,
             )?;
-            let mdns =
-                mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())
-                    .unwrap();
-            Ok(EssexBehaviour { gossipsub, mdns })
+            let (score_params, score_thresholds) =
+                reputation::gossip_score_params(&config.reputation, &gtopic);
+            gossipsub.with_peer_score(score_params, score_thresholds)?;
+            let local_id = key.public().to_peer_id();
+            let mdns = if discovery.mdns {
+                Some(mdns::tokio::Behaviour::new(mdns::Config::default(), local_id)?)
+            } else {
+                None
+            };
+            let mut kad_config = kad::Config::default();
+            kad_config.set_protocol_names(vec![kad_proto.clone()]);
+            let mut kad = kad::Behaviour::with_config(
+                local_id,
+                kad::store::MemoryStore::new(local_id),
+                kad_config,
+            );
+            // answer dht queries even before we know our
+            // external address, nodes behind bootstrap need it
+            kad.set_mode(Some(kad::Mode::Server));
+            let identify = identify::Behaviour::new(
+                identify::Config::new(local_protocol.to_string(), key.public())
+                    .with_agent_version(agent_version()),
+            );
+            let ping = ping::Behaviour::new(ping::Config::new());
+            Ok(EssexBehaviour {
+                blocked: Default::default(),
+                limits: connection_limits::Behaviour::new(limits::connection_limits(&config.limits)),
+                ip_limits: IpLimits::new(config.limits.max_per_ip),
+                gossipsub,
+                mdns: mdns.into(),
+                kad,
+                identify,
+                ping,
+            })
         })?
         .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
         .build();
-    let gtopic = gossipsub::IdentTopic::new(enode_topic);
-    let _ = swarm.behaviour_mut().gossipsub.subscribe(&gtopic);
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-    swarm.listen_on(enode_addr)?;
-    swarm.listen_on(enode_addr_2)?;
-    println!("any messages sent would be sent to peers");
-
-    loop {
-        select! {
-            Ok(Some(line)) = stdin.next_line() => {
-                // impl Topic<IdHash> && impl Into<Vec<u8>>
-                if let Err(e) = swarm.behaviour_mut().gossipsub.publish(gtopic.clone(),line.as_bytes()){
-                    println!("{e}")
-                }
-            }
-            event = swarm.select_next_some() => match event {
-                SwarmEvent::Behaviour(EssexBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
-                    for(peer_id, _multiaddr) in list {
-                        println!("discovered peer {peer_id}");
-                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
-                    }
-                },
-                SwarmEvent::Behaviour(EssexBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
-                    for(peer_id, _multiaddr) in list {
-                        println!("mdns discover expired: {peer_id}");
-                        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
-                    }
-                },
-                SwarmEvent::Behaviour(EssexBehaviourEvent::Gossipsub(gossipsub::Event::Message{
-                    propagation_source:peer_id,
-                    message_id:id,
-                    message
-                })) => {
-                    if message.data == "createchain".as_bytes() {
-                        // we assume createchain is a command
-                        // to simulate & prepare a new chain
-                        // in the blockchain : _ts_demo()-x
-                        block_handler();
-                    }
-                    println!("Got message {:?} with id: {id} from peer: {peer_id}", String::from_utf8_lossy(&message.data))
-                },
-                SwarmEvent::NewListenAddr {address, ..} => {
-                    println!("🆚 Chain Verx: {}", "v1.0.0");
-                    println!("👨🏾‍💻 Chain Devx: {}", "Jim Nnamdi");
-                    println!("🚀 Chain Specs: {}", "random specs");
-                    println!("🧰 Chain Role: {}", "authority");
-                    println!("🛢 Chain DBX: {}", "/blockchain.json");
-                    println!("🎱 Operating system: {}", env::consts::OS);
-                    println!("🧶 Architecture: {}", "amd 64 intel");
-                    println!("🌈 Node Listener: {}", address);
-                }
-                _ => {},
-            }
-        }
+    Ok(swarm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bootstrap_addresses_split_into_peer_and_address() {
+        let peer = PeerId::random();
+        let addr: Multiaddr = format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", peer).parse().unwrap();
+        let (id, base) = split_peer_id(&addr).unwrap();
+        assert_eq!(id, peer);
+        assert_eq!(base, "/ip4/10.0.0.1/tcp/4001".parse::<Multiaddr>().unwrap());
+        assert!(split_peer_id(&base).is_none());
+    }
+
+    #[test]
+    fn each_chain_gets_its_own_dht() {
+        assert_ne!(kad_protocol("test-net").unwrap(), kad_protocol("main-net").unwrap());
+        assert_eq!(kad_protocol("test-net").unwrap().as_ref(), "/essex/test-net/kad/1.0.0");
     }
 }
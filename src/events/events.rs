@@ -0,0 +1,232 @@
+use libp2p::{Multiaddr, PeerId};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::block::block::Block;
+use crate::timestamp::timestamp::Timestamp;
+use crate::transaction::transaction::Transaction;
+
+/// Something that happened in a running node
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    Listening(Multiaddr),
+    // checked and passed on, not necessarily applied
+    BlockReceived { peer: PeerId, hash: String, height: u64 },
+    BlockApplied(Arc<Block>),
+    // the head moved to another branch, `reverted` are
+    // the old blocks from the top down and `applied` the
+    // new ones from the fork point up
+    Reorg { reverted: Vec<Arc<Block>>, applied: Vec<Arc<Block>> },
+    TransactionAccepted(Arc<Transaction>),
+    // left the mempool without making it into a block
+    TransactionDropped { id: String, reason: String },
+    PeerConnected(PeerId),
+    PeerDisconnected(PeerId),
+    PeerDiscovered(PeerId),
+    PeerBanned { peer: PeerId, until: Timestamp },
+    Message { peer: PeerId, text: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Listening,
+    BlockReceived,
+    BlockApplied,
+    Reorg,
+    TransactionAccepted,
+    TransactionDropped,
+    PeerConnected,
+    PeerDisconnected,
+    PeerDiscovered,
+    PeerBanned,
+    Message,
+}
+
+impl NodeEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            NodeEvent::Listening(_) => EventKind::Listening,
+            NodeEvent::BlockReceived { .. } => EventKind::BlockReceived,
+            NodeEvent::BlockApplied(_) => EventKind::BlockApplied,
+            NodeEvent::Reorg { .. } => EventKind::Reorg,
+            NodeEvent::TransactionAccepted(_) => EventKind::TransactionAccepted,
+            NodeEvent::TransactionDropped { .. } => EventKind::TransactionDropped,
+            NodeEvent::PeerConnected(_) => EventKind::PeerConnected,
+            NodeEvent::PeerDisconnected(_) => EventKind::PeerDisconnected,
+            NodeEvent::PeerDiscovered(_) => EventKind::PeerDiscovered,
+            NodeEvent::PeerBanned { .. } => EventKind::PeerBanned,
+            NodeEvent::Message { .. } => EventKind::Message,
+        }
+    }
+}
+
+/// Which events a subscriber wants, everything when
+/// no kinds are given
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    kinds: Option<HashSet<EventKind>>,
+}
+
+impl EventFilter {
+    pub fn all() -> Self {
+        EventFilter::default()
+    }
+
+    pub fn kinds(kinds: &[EventKind]) -> Self {
+        EventFilter {
+            kinds: Some(kinds.iter().copied().collect()),
+        }
+    }
+
+    // what changes the chain
+    pub fn chain() -> Self {
+        EventFilter::kinds(&[EventKind::BlockApplied, EventKind::Reorg])
+    }
+
+    pub fn mempool() -> Self {
+        EventFilter::kinds(&[EventKind::TransactionAccepted, EventKind::TransactionDropped])
+    }
+
+    pub fn network() -> Self {
+        EventFilter::kinds(&[
+            EventKind::Listening,
+            EventKind::PeerConnected,
+            EventKind::PeerDisconnected,
+            EventKind::PeerDiscovered,
+            EventKind::PeerBanned,
+        ])
+    }
+
+    // widens the filter by another one
+    pub fn or(mut self, other: EventFilter) -> Self {
+        match (&mut self.kinds, other.kinds) {
+            (Some(kinds), Some(more)) => kinds.extend(more),
+            _ => self.kinds = None,
+        }
+        self
+    }
+
+    pub fn matches(&self, event: &NodeEvent) -> bool {
+        match &self.kinds {
+            Some(kinds) => kinds.contains(&event.kind()),
+            None => true,
+        }
+    }
+}
+
+/// One stream of everything that happens in a node,
+/// publishing never blocks and never fails
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<NodeEvent>,
+}
+
+impl EventBus {
+    // `capacity` is how far a subscriber may fall
+    // behind before it starts missing events
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        EventBus { tx }
+    }
+
+    pub fn publish(&self, event: NodeEvent) {
+        // no subscribers is fine
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self, filter: EventFilter) -> Subscription {
+        Subscription {
+            rx: self.tx.subscribe(),
+            filter,
+        }
+    }
+}
+
+/// Events from the bus that match a filter
+pub struct Subscription {
+    rx: broadcast::Receiver<NodeEvent>,
+    filter: EventFilter,
+}
+
+impl Subscription {
+    // the next matching event, Lagged when events were
+    // missed and Closed once the node is gone
+    pub async fn recv(&mut self) -> Result<NodeEvent, RecvError> {
+        loop {
+            let event = self.rx.recv().await?;
+            if self.filter.matches(&event) {
+                return Ok(event);
+            }
+        }
+    }
+
+    // same as recv for subscribers on their own thread
+    pub fn blocking_recv(&mut self) -> Result<NodeEvent, RecvError> {
+        loop {
+            let event = self.rx.blocking_recv()?;
+            if self.filter.matches(&event) {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dropped(id: &str) -> NodeEvent {
+        NodeEvent::TransactionDropped {
+            id: id.to_string(),
+            reason: "expired".to_string(),
+        }
+    }
+
+    #[test]
+    fn filters_pick_their_kinds() {
+        let peer = NodeEvent::PeerConnected(PeerId::random());
+        let reorg = NodeEvent::Reorg {
+            reverted: vec![],
+            applied: vec![],
+        };
+        assert!(EventFilter::all().matches(&peer));
+        assert!(EventFilter::network().matches(&peer));
+        assert!(!EventFilter::chain().matches(&peer));
+        assert!(EventFilter::chain().matches(&reorg));
+        let both = EventFilter::chain().or(EventFilter::mempool());
+        assert!(both.matches(&reorg) && both.matches(&dropped("a")) && !both.matches(&peer));
+        assert!(EventFilter::chain().or(EventFilter::all()).matches(&peer));
+    }
+
+    #[test]
+    fn subscribers_only_see_what_they_asked_for() {
+        let bus = EventBus::new(8);
+        // publishing with nobody listening is fine
+        bus.publish(dropped("lost"));
+        let mut mempool = bus.subscribe(EventFilter::mempool());
+        let mut everything = bus.subscribe(EventFilter::all());
+        bus.publish(NodeEvent::PeerDiscovered(PeerId::random()));
+        bus.publish(dropped("a"));
+        let NodeEvent::TransactionDropped { id, .. } = mempool.blocking_recv().unwrap() else {
+            panic!("got an event the filter should have skipped");
+        };
+        assert_eq!(id, "a");
+        assert_eq!(everything.blocking_recv().unwrap().kind(), EventKind::PeerDiscovered);
+        assert_eq!(everything.blocking_recv().unwrap().kind(), EventKind::TransactionDropped);
+    }
+
+    #[test]
+    fn a_slow_subscriber_is_told_it_missed_events() {
+        let bus = EventBus::new(2);
+        let mut sub = bus.subscribe(EventFilter::all());
+        for i in 0..5 {
+            bus.publish(dropped(&i.to_string()));
+        }
+        assert!(matches!(sub.blocking_recv(), Err(RecvError::Lagged(3))));
+        assert!(sub.blocking_recv().is_ok());
+        drop(bus);
+        sub.blocking_recv().unwrap();
+        assert!(matches!(sub.blocking_recv(), Err(RecvError::Closed)));
+    }
+}
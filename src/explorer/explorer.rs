@@ -0,0 +1,379 @@
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::routing::get;
+use axum::Router;
+use serde::Deserialize;
+use std::fmt::Write;
+
+use crate::address::address::Address;
+use crate::block::block::Block;
+use crate::blockchain::blockchain::TxLocation;
+use crate::node::node::{NodeHandle, TxStatus};
+use crate::transaction::transaction::{BlockEntry, Transaction};
+
+// blocks on the front page
+const LATEST_BLOCKS: u64 = 20;
+// history rows on an account page
+const HISTORY_ROWS: usize = 100;
+
+const STYLE: &str = "body{font-family:monospace;margin:2em auto;max-width:70em;padding:0 1em}\
+table{border-collapse:collapse;width:100%}td,th{border-bottom:1px solid #ddd;padding:4px;text-align:left}\
+th{background:#f4f4f4}.kv th{width:12em}nav a{margin-right:1em}td{word-break:break-all}";
+
+/// Html pages over the node's chain, mempool and peers,
+/// nested under /explorer by the RPC server
+pub fn routes() -> Router<NodeHandle> {
+    Router::new()
+        .route("/", get(index))
+        .route("/search", get(search))
+        .route("/block/:id", get(block))
+        .route("/tx/:id", get(transaction))
+        .route("/account/:address", get(account))
+        .route("/mempool", get(mempool))
+        .route("/peers", get(peers))
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+}
+
+async fn index(State(node): State<NodeHandle>) -> Response {
+    let mut body = String::new();
+    match node.head() {
+        Some(head) => {
+            let _ = writeln!(body, "<h2>Latest blocks</h2>");
+            let start = head.height.saturating_sub(LATEST_BLOCKS - 1);
+            let blocks = (start..=head.height)
+                .rev()
+                .filter_map(|height| node.block_by_height(height));
+            body.push_str(&block_table(blocks));
+        }
+        None => body.push_str("<p>The chain is empty.</p>"),
+    }
+    page("Essex explorer", &body)
+}
+
+// a height, a block or tx hash, or an address
+async fn search(State(node): State<NodeHandle>, Query(query): Query<SearchQuery>) -> Response {
+    let q = query.q.trim();
+    if q.is_empty() {
+        return Redirect::to("/explorer").into_response();
+    }
+    if q.parse::<u64>().is_ok() || node.block_by_hash(q).is_some() {
+        return Redirect::to(&format!("/explorer/block/{}", q)).into_response();
+    }
+    if node.transaction(q).is_some() {
+        return Redirect::to(&format!("/explorer/tx/{}", q)).into_response();
+    }
+    if q.parse::<Address>().is_ok() {
+        return Redirect::to(&format!("/explorer/account/{}", q)).into_response();
+    }
+    not_found(&format!("Nothing matches {}", escape(q)))
+}
+
+// by height or by hash
+async fn block(State(node): State<NodeHandle>, Path(id): Path<String>) -> Response {
+    let found = match id.parse::<u64>() {
+        Ok(height) => node.block_by_height(height),
+        Err(_) => node.block_by_hash(&id),
+    };
+    let Some(blk) = found else {
+        return not_found(&format!("No block {}", escape(&id)));
+    };
+    let mut body = String::new();
+    let _ = writeln!(body, "<h2>Block {}</h2>", blk.height);
+    body.push_str(&kv_table(&[
+        ("hash", escape(&blk.block_hash)),
+        ("height", blk.height.to_string()),
+        ("previous", block_link(&blk.prev_hash)),
+        ("validator", account_link(&blk.validator)),
+        ("validator key", escape(&blk.validator_key)),
+        ("signature", escape(&blk.signature)),
+        ("data root", escape(&blk.data_root())),
+        ("timestamp", blk.timestamp.to_string()),
+        ("valid", blk.valid.to_string()),
+    ]));
+    let _ = writeln!(body, "<h3>Entries ({})</h3>", blk.block_data.len());
+    body.push_str("<table><tr><th>#</th><th>type</th><th>details</th></tr>");
+    for (position, entry) in blk.block_data.iter().enumerate() {
+        let (kind, details) = match entry {
+            BlockEntry::Transfer(tx) => (
+                "transfer",
+                format!(
+                    "{} {} → {} {}",
+                    tx_link(&tx.tx_header.transaction_id),
+                    account_link(&tx.tx_from),
+                    account_link(&tx.tx_to),
+                    tx.tx_amount
+                ),
+            ),
+            BlockEntry::RegisterMultisig(multisig) => (
+                "multisig",
+                format!(
+                    "{} of {} keys, {}",
+                    multisig.threshold,
+                    multisig.keys.len(),
+                    account_link(&multisig.address())
+                ),
+            ),
+            BlockEntry::Private(payload) => ("private", escape(&payload.commitment)),
+            BlockEntry::Record { data } => ("record", escape(data)),
+        };
+        let _ = writeln!(body, "<tr><td>{}</td><td>{}</td><td>{}</td></tr>", position, kind, details);
+    }
+    body.push_str("</table>");
+    page(&format!("Block {}", blk.height), &body)
+}
+
+async fn transaction(State(node): State<NodeHandle>, Path(id): Path<String>) -> Response {
+    let Some(status) = node.transaction(&id) else {
+        return not_found(&format!("No transaction {}", escape(&id)));
+    };
+    let (tx, location) = match status {
+        TxStatus::Pending { tx } => (tx, None),
+        TxStatus::Included { location, tx } => (tx, Some(location)),
+    };
+    let included = match &location {
+        Some(location) => format!(
+            "block {} at position {}",
+            block_link(&location.block_hash),
+            location.position
+        ),
+        None => "pending in the mempool".to_string(),
+    };
+    let mut body = String::new();
+    body.push_str("<h2>Transaction</h2>");
+    body.push_str(&kv_table(&[
+        ("id", escape(&tx.tx_header.transaction_id)),
+        ("status", included),
+        ("from", account_link(&tx.tx_from)),
+        ("to", account_link(&tx.tx_to)),
+        ("amount", tx.tx_amount.to_string()),
+        ("nonce", tx.tx_nonce.to_string()),
+        ("timestamp", tx.timestamp.to_string()),
+        ("public key", escape(&tx.tx_public)),
+        ("signature", escape(&tx.tx_header.transaction_signature)),
+        ("multisig signatures", tx.tx_signatures.len().to_string()),
+    ]));
+    page("Transaction", &body)
+}
+
+async fn account(State(node): State<NodeHandle>, Path(address): Path<String>) -> Response {
+    let Ok(addr) = address.parse::<Address>() else {
+        return not_found(&format!("{} is not an address", escape(&address)));
+    };
+    let pending: Vec<Transaction> = node
+        .mempool()
+        .into_iter()
+        .filter(|tx| tx.tx_from == addr || tx.tx_to == addr)
+        .collect();
+    let history = node.history(&addr);
+    let mut body = String::new();
+    let _ = writeln!(body, "<h2>Account {}</h2>", addr);
+    body.push_str(&kv_table(&[
+        ("balance", node.balance(&addr).to_string()),
+        ("nonce", node.nonce(&addr).to_string()),
+        ("next nonce", node.next_nonce(&addr).to_string()),
+        ("transactions", history.len().to_string()),
+    ]));
+    if !pending.is_empty() {
+        body.push_str("<h3>Pending</h3>");
+        body.push_str(&tx_table(pending.iter().map(|tx| (None, tx))));
+    }
+    body.push_str("<h3>History</h3>");
+    if history.len() > HISTORY_ROWS {
+        let _ = writeln!(body, "<p>The latest {} of {}.</p>", HISTORY_ROWS, history.len());
+    }
+    body.push_str(&tx_table(
+        history
+            .iter()
+            .take(HISTORY_ROWS)
+            .map(|(location, tx)| (Some(location), tx)),
+    ));
+    page(&format!("Account {}", addr), &body)
+}
+
+async fn mempool(State(node): State<NodeHandle>) -> Response {
+    let txs = node.mempool();
+    let mut body = String::new();
+    let _ = writeln!(body, "<h2>Mempool ({})</h2>", txs.len());
+    body.push_str(&tx_table(txs.iter().map(|tx| (None, tx))));
+    page("Mempool", &body)
+}
+
+async fn peers(State(node): State<NodeHandle>) -> Response {
+    let peers = node.peers();
+    let mut body = String::new();
+    let _ = writeln!(body, "<h2>Connected peers ({})</h2>", peers.len());
+    body.push_str("<table><tr><th>peer</th><th>agent</th><th>best height</th><th>latency</th><th>addresses</th></tr>");
+    for peer in &peers {
+        let addresses: Vec<String> = peer.addresses.iter().map(|x| escape(&x.to_string())).collect();
+        let _ = writeln!(
+            body,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            peer.peer_id,
+            escape(peer.agent_version.as_deref().unwrap_or("-")),
+            peer.best_height.map(|x| x.to_string()).unwrap_or("-".to_string()),
+            peer.latency_ms.map(|x| format!("{}ms", x)).unwrap_or("-".to_string()),
+            addresses.join("<br>")
+        );
+    }
+    body.push_str("</table>");
+    page("Peers", &body)
+}
+
+fn page(title: &str, body: &str) -> Response {
+    Html(layout(title, body)).into_response()
+}
+
+fn not_found(message: &str) -> Response {
+    let body = format!("<h2>Not found</h2><p>{}</p>", message);
+    (StatusCode::NOT_FOUND, Html(layout("Not found", &body))).into_response()
+}
+
+// `title` is escaped here, `body` has to be already
+fn layout(title: &str, body: &str) -> String {
+    format!(
+        "<!doctype html><html><head><meta charset=\"utf-8\"><title>{}</title><style>{}</style></head><body>\
+<nav><a href=\"/explorer\">Blocks</a><a href=\"/explorer/mempool\">Mempool</a><a href=\"/explorer/peers\">Peers</a></nav>\
+<form action=\"/explorer/search\"><input name=\"q\" size=\"70\" placeholder=\"height, block or tx hash, address\"> \
+<button>Search</button></form>{}</body></html>",
+        escape(title),
+        STYLE,
+        body
+    )
+}
+
+fn block_table<'a>(blocks: impl Iterator<Item = Block> + 'a) -> String {
+    let mut table = String::from("<table><tr><th>height</th><th>hash</th><th>validator</th><th>entries</th><th>timestamp</th></tr>");
+    for blk in blocks {
+        let _ = writeln!(
+            table,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            blk.height,
+            block_link(&blk.block_hash),
+            account_link(&blk.validator),
+            blk.block_data.len(),
+            blk.timestamp
+        );
+    }
+    table.push_str("</table>");
+    table
+}
+
+fn tx_table<'a>(txs: impl Iterator<Item = (Option<&'a TxLocation>, &'a Transaction)>) -> String {
+    let mut table = String::from("<table><tr><th>id</th><th>block</th><th>from</th><th>to</th><th>amount</th><th>nonce</th></tr>");
+    for (location, tx) in txs {
+        let block = match location {
+            Some(location) => format!("<a href=\"/explorer/block/{0}\">{0}</a>", location.height),
+            None => "pending".to_string(),
+        };
+        let _ = writeln!(
+            table,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            tx_link(&tx.tx_header.transaction_id),
+            block,
+            account_link(&tx.tx_from),
+            account_link(&tx.tx_to),
+            tx.tx_amount,
+            tx.tx_nonce
+        );
+    }
+    table.push_str("</table>");
+    table
+}
+
+fn kv_table(rows: &[(&str, String)]) -> String {
+    let mut table = String::from("<table class=\"kv\">");
+    for (key, value) in rows {
+        let _ = writeln!(table, "<tr><th>{}</th><td>{}</td></tr>", key, value);
+    }
+    table.push_str("</table>");
+    table
+}
+
+fn block_link(hash: &str) -> String {
+    let hash = escape(hash);
+    format!("<a href=\"/explorer/block/{0}\">{0}</a>", hash)
+}
+
+fn tx_link(id: &str) -> String {
+    let id = escape(id);
+    format!("<a href=\"/explorer/tx/{0}\">{0}</a>", id)
+}
+
+fn account_link(addr: &Address) -> String {
+    format!("<a href=\"/explorer/account/{0}\">{0}</a>", addr)
+}
+
+// block data and peer agents come off the network
+fn escape(raw: &str) -> String {
+    let mut out = String::with_capacity(raw.len());
+    for c in raw.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chainspec::chainspec::ChainSpec;
+    use axum::http::header;
+
+    async fn body(res: Response) -> String {
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn location(res: &Response) -> &str {
+        res.headers()[header::LOCATION].to_str().unwrap()
+    }
+
+    #[test]
+    fn escapes_everything_html_cares_about() {
+        let escaped = "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;";
+        assert_eq!(escape("<a href=\"x\">'&'</a>"), escaped);
+        assert_eq!(escape("essex1abc"), "essex1abc");
+    }
+
+    #[tokio::test]
+    async fn block_pages_escape_what_came_off_the_network() {
+        let spec = ChainSpec {
+            chain_id: "<script>alert(1)</script>".to_string(),
+            ..ChainSpec::default()
+        };
+        let node = NodeHandle::detached(spec);
+        let res = block(State(node.clone()), Path("0".to_string())).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let html = body(res).await;
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(!html.contains("<script>"));
+        let res = block(State(node), Path("<b>".to_string())).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(body(res).await.contains("No block &lt;b&gt;"));
+    }
+
+    #[tokio::test]
+    async fn search_sends_each_kind_of_query_to_its_page() {
+        let node = NodeHandle::detached(ChainSpec::default());
+        let genesis = node.head().unwrap().block_hash;
+        let addr = Address::from_bytes(&[3u8; 20]).unwrap().to_string();
+        let go = |q: &str| search(State(node.clone()), Query(SearchQuery { q: q.to_string() }));
+        assert_eq!(location(&go("0").await), "/explorer/block/0");
+        assert_eq!(location(&go(&genesis).await), format!("/explorer/block/{}", genesis));
+        assert_eq!(location(&go(&addr).await), format!("/explorer/account/{}", addr));
+        assert_eq!(location(&go("  ").await), "/explorer");
+        assert_eq!(go("nothing").await.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::{bail, Context, Result};
+use libp2p::identity::{Keypair, PublicKey as NodePublicKey};
+use libp2p::PeerId;
+use secp256k1::{ecdsa::Signature, PublicKey};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::account::account::{Account, ATTESTATION_DOMAIN};
+use crate::address::address::Address;
+use crate::codec::codec::{Encoder, CODEC_VERSION};
+use crate::timestamp::timestamp::Timestamp;
+
+pub const NODE_KEY_FILE: &str = "node.key";
+pub const ATTESTATION_FILE: &str = "attestation.json";
+
+// libp2p signatures carry no domain of their
+// own so the node side gets a prefix here
+const NODE_ATTESTATION_PREFIX: &[u8] = b"Essex Node Attestation";
+
+/// The node's libp2p keypair from `path`, a fresh
+/// ed25519 key is generated and stored on first run
+pub fn load_or_create_node_key<P: AsRef<Path>>(path: P) -> Result<Keypair> {
+    let path = path.as_ref();
+    if path.exists() {
+        let raw = fs::read(path).with_context(|| format!("reading node key {:?}", path))?;
+        let key = Keypair::from_protobuf_encoding(&raw)
+            .map_err(|e| anyhow::anyhow!("invalid node key {:?}: {}", path, e))?;
+        return Ok(key);
+    }
+    let key = Keypair::generate_ed25519();
+    let encoded = key
+        .to_protobuf_encoding()
+        .map_err(|e| anyhow::anyhow!("cannot encode node key: {}", e))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut opts = fs::File::options();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    let mut file = opts
+        .open(path)
+        .with_context(|| format!("writing node key {:?}", path))?;
+    file.write_all(&encoded)?;
+    file.sync_all()?;
+    log::info!("generated node key {}", key.public().to_peer_id());
+    Ok(key)
+}
+
+/// A validator account and a node vouching for each
+/// other, the account signs the PeerId it runs behind
+/// and the node key signs the same payload back
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidatorAttestation {
+    pub peer_id: String,
+    // protobuf encoded libp2p public key, hex
+    pub node_key: String,
+    pub validator: Address,
+    pub validator_key: String,
+    pub issued_at: Timestamp,
+    pub validator_signature: String,
+    pub node_signature: String,
+}
+
+impl ValidatorAttestation {
+    pub fn new(acc: &Account, node: &Keypair) -> Result<ValidatorAttestation> {
+        let mut att = ValidatorAttestation {
+            peer_id: node.public().to_peer_id().to_string(),
+            node_key: hex::encode(node.public().encode_protobuf()),
+            validator: acc.address(),
+            validator_key: acc.acc_public.to_string(),
+            issued_at: Timestamp::now(),
+            validator_signature: String::new(),
+            node_signature: String::new(),
+        };
+        let payload = att.signing_payload();
+        att.validator_signature = acc.sign_with_domain(ATTESTATION_DOMAIN, &payload).to_string();
+        let node_sig = node
+            .sign(&[NODE_ATTESTATION_PREFIX, &payload].concat())
+            .map_err(|e| anyhow::anyhow!("node key cannot sign: {}", e))?;
+        att.node_signature = hex::encode(node_sig);
+        Ok(att)
+    }
+
+    pub fn signing_payload(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        enc.put_u8(CODEC_VERSION);
+        enc.put(&self.peer_id);
+        enc.put(&self.node_key);
+        enc.put(&self.validator);
+        enc.put(&self.validator_key);
+        enc.put(&self.issued_at);
+        enc.buf
+    }
+
+    pub fn peer_id(&self) -> Result<PeerId> {
+        Ok(self.peer_id.parse()?)
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        let payload = self.signing_payload();
+        let public: PublicKey = self.validator_key.parse()?;
+        if !self.validator.matches(&public) {
+            bail!("validator key does not match {}", self.validator);
+        }
+        let sig: Signature = self.validator_signature.parse()?;
+        Account::verify_with_domain(ATTESTATION_DOMAIN, &payload, &sig, &public)?;
+        let node_key = NodePublicKey::try_decode_protobuf(&hex::decode(&self.node_key)?)
+            .map_err(|e| anyhow::anyhow!("invalid node key: {}", e))?;
+        if node_key.to_peer_id() != self.peer_id()? {
+            bail!("node key does not match peer id {}", self.peer_id);
+        }
+        let node_sig = hex::decode(&self.node_signature)?;
+        if !node_key.verify(&[NODE_ATTESTATION_PREFIX, &payload].concat(), &node_sig) {
+            bail!("node signature does not verify for {}", self.peer_id);
+        }
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ValidatorAttestation> {
+        let path = path.as_ref();
+        let raw = fs::read_to_string(path).with_context(|| format!("reading {:?}", path))?;
+        let att: ValidatorAttestation = serde_json::from_str(&raw)?;
+        att.verify()?;
+        Ok(att)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("essex-identity-{:016x}", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn the_node_key_survives_a_restart() {
+        let dir = scratch_dir();
+        let path = dir.join(NODE_KEY_FILE);
+        let first = load_or_create_node_key(&path).unwrap();
+        let second = load_or_create_node_key(&path).unwrap();
+        assert_eq!(first.public().to_peer_id(), second.public().to_peer_id());
+        fs::write(&path, b"garbage").unwrap();
+        assert!(load_or_create_node_key(&path).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn attestations_bind_one_account_to_one_node() {
+        let acc = Account::create().unwrap();
+        let node = Keypair::generate_ed25519();
+        let att = ValidatorAttestation::new(&acc, &node).unwrap();
+        att.verify().unwrap();
+        assert_eq!(att.peer_id().unwrap(), node.public().to_peer_id());
+
+        let mut moved = att.clone();
+        moved.peer_id = Keypair::generate_ed25519().public().to_peer_id().to_string();
+        assert!(moved.verify().is_err());
+        let mut claimed = att.clone();
+        claimed.validator = Account::create().unwrap().address();
+        assert!(claimed.verify().is_err());
+        // a genuine node signature over someone else's claim
+        let other = ValidatorAttestation::new(&Account::create().unwrap(), &node).unwrap();
+        let mut mixed = att;
+        mixed.node_signature = other.node_signature;
+        assert!(mixed.verify().is_err());
+    }
+
+    #[test]
+    fn a_saved_attestation_loads_back_verified() {
+        let dir = scratch_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(ATTESTATION_FILE);
+        let att = ValidatorAttestation::new(&Account::create().unwrap(), &Keypair::generate_ed25519()).unwrap();
+        att.save(&path).unwrap();
+        assert_eq!(ValidatorAttestation::load(&path).unwrap(), att);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::address::address::Address;
+use crate::block::block::Block;
+use crate::blockchain::blockchain::{self, Blockchain, TxLocation};
+use crate::events::events::NodeEvent;
+use crate::storage::storage;
+
+/// Where each transfer on the chain sits and which
+/// transfers touched each address, so lookups don't
+/// have to walk every block
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Indexer {
+    // hash of the last block indexed
+    pub tip: Option<String>,
+    // blocks indexed so far, the height of the next one
+    pub height: u64,
+    pub txs: HashMap<String, TxLocation>,
+    // tx ids from the oldest to the newest
+    pub addresses: HashMap<Address, Vec<String>>,
+}
+
+impl Indexer {
+    // from scratch, one block after the other
+    pub fn build(chain: &Blockchain) -> Result<Indexer> {
+        let mut index = Indexer::default();
+        for blk in chain.chain.iter() {
+            index.apply_block(blk)?;
+        }
+        Ok(index)
+    }
+
+    // picks up where a saved index left off, or starts
+    // over when it no longer matches the chain
+    pub fn catch_up(mut self, chain: &Blockchain) -> Result<Indexer> {
+        let tip = self
+            .height
+            .checked_sub(1)
+            .and_then(|height| chain.block_by_height(height))
+            .map(|x| x.block_hash.clone());
+        if tip != self.tip {
+            return Indexer::build(chain);
+        }
+        for blk in chain.chain.iter().skip(self.height as usize) {
+            self.apply_block(blk)?;
+        }
+        Ok(self)
+    }
+
+    // `blk` has to follow the last block indexed
+    pub fn apply_block(&mut self, blk: &Block) -> Result<()> {
+        if blk.height != self.height {
+            bail!("index is at height {}, got block {}", self.height, blk.height);
+        }
+        if self.tip.is_some() && self.tip.as_deref() != Some(blk.prev_hash.as_str()) {
+            bail!("block {} does not follow the indexed tip", blk.height);
+        }
+        for (location, tx) in blockchain::transfers(blk) {
+            let id = tx.tx_header.transaction_id;
+            self.addresses.entry(tx.tx_from).or_default().push(id.clone());
+            if tx.tx_to != tx.tx_from {
+                self.addresses.entry(tx.tx_to).or_default().push(id.clone());
+            }
+            self.txs.insert(id, location);
+        }
+        self.tip = Some(blk.block_hash.clone());
+        self.height += 1;
+        Ok(())
+    }
+
+    // takes the last block indexed back out, a reorg
+    // unwinds from the top down
+    pub fn unwind_block(&mut self, blk: &Block) -> Result<()> {
+        if self.tip.as_deref() != Some(blk.block_hash.as_str()) {
+            bail!("block {} is not the indexed tip", blk.height);
+        }
+        for (location, tx) in blockchain::transfers(blk).into_iter().rev() {
+            let id = &tx.tx_header.transaction_id;
+            if self.txs.get(id) == Some(&location) {
+                self.txs.remove(id);
+            }
+            for addr in [tx.tx_from, tx.tx_to] {
+                let Some(ids) = self.addresses.get_mut(&addr) else {
+                    continue;
+                };
+                if let Some(i) = ids.iter().rposition(|x| x == id) {
+                    ids.remove(i);
+                }
+                if ids.is_empty() {
+                    self.addresses.remove(&addr);
+                }
+            }
+        }
+        self.height -= 1;
+        self.tip = (blk.height > 0).then(|| blk.prev_hash.clone());
+        Ok(())
+    }
+
+    // chain events in order, anything else is ignored
+    pub fn handle(&mut self, event: &NodeEvent) -> Result<()> {
+        match event {
+            NodeEvent::BlockApplied(blk) => self.apply_block(blk),
+            NodeEvent::Reorg { reverted, applied } => {
+                for blk in reverted {
+                    self.unwind_block(blk)?;
+                }
+                for blk in applied {
+                    self.apply_block(blk)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn transaction(&self, id: &str) -> Option<&TxLocation> {
+        self.txs.get(id)
+    }
+
+    // where the transfers from or to `addr` are, newest first
+    pub fn history(&self, addr: &Address) -> Vec<&TxLocation> {
+        match self.addresses.get(addr) {
+            Some(ids) => ids.iter().rev().filter_map(|id| self.txs.get(id)).collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Indexer> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Indexer::default());
+        }
+        let raw = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&raw)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        storage::write_atomic(path, serde_json::to_string(self)?.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::account::Account;
+    use crate::transaction::transaction::{BlockEntry, Transaction};
+    use std::sync::Arc;
+
+    fn transfer(from: &Account, to: &Account, nonce: u64) -> BlockEntry {
+        let tx = Transaction::new(from, to.address(), String::new(), 1, nonce).unwrap();
+        BlockEntry::Transfer(Box::new(tx))
+    }
+
+    // blocks linked by hash, `tag` keeps branches apart
+    fn chain_of(tag: &str, entries: Vec<Vec<BlockEntry>>) -> Blockchain {
+        let mut chain = Blockchain::default();
+        for (height, block_data) in entries.into_iter().enumerate() {
+            let prev_hash = chain.chain.last().map(|x| x.block_hash.clone()).unwrap_or_default();
+            chain.chain.push(Block {
+                height: height as u64,
+                prev_hash,
+                block_hash: format!("{}-{}", tag, height),
+                block_data,
+                ..Block::default()
+            });
+        }
+        chain
+    }
+
+    fn id(entry: &BlockEntry) -> String {
+        match entry {
+            BlockEntry::Transfer(tx) => tx.tx_header.transaction_id.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn finds_transfers_and_history_newest_first() {
+        let [alice, bob, carol] = [(); 3].map(|_| Account::create().unwrap());
+        let (first, second) = (transfer(&alice, &bob, 0), transfer(&carol, &alice, 0));
+        let (first_id, second_id) = (id(&first), id(&second));
+        let chain = chain_of("a", vec![vec![], vec![first], vec![BlockEntry::record("x"), second]]);
+        let index = Indexer::build(&chain).unwrap();
+        assert_eq!((index.height, index.tip.as_deref()), (3, Some("a-2")));
+        let location = index.transaction(&second_id).unwrap();
+        assert_eq!((location.height, location.position), (2, 1));
+        let heights: Vec<u64> = index.history(&alice.address()).iter().map(|x| x.height).collect();
+        assert_eq!(heights, vec![2, 1]);
+        assert_eq!(index.history(&bob.address())[0], index.transaction(&first_id).unwrap());
+        assert!(index.history(&Account::create().unwrap().address()).is_empty());
+    }
+
+    #[test]
+    fn catch_up_continues_or_starts_over() {
+        let (alice, bob) = (Account::create().unwrap(), Account::create().unwrap());
+        let blocks = vec![vec![], vec![transfer(&alice, &bob, 0)], vec![transfer(&alice, &bob, 1)]];
+        let chain = chain_of("a", blocks);
+        let mut partial = Indexer::default();
+        partial.apply_block(&chain.chain[0]).unwrap();
+        partial.apply_block(&chain.chain[1]).unwrap();
+        let caught_up = partial.catch_up(&chain).unwrap();
+        assert_eq!(caught_up.height, 3);
+        assert_eq!(caught_up.history(&bob.address()).len(), 2);
+        // an index of another branch is thrown away
+        let other = chain_of("b", vec![vec![], vec![transfer(&bob, &alice, 0)]]);
+        let stale = Indexer::build(&other).unwrap();
+        let rebuilt = stale.catch_up(&chain).unwrap();
+        assert_eq!(rebuilt.tip.as_deref(), Some("a-2"));
+        assert_eq!(rebuilt.history(&alice.address()).len(), 2);
+    }
+
+    #[test]
+    fn blocks_go_on_and_come_off_in_order() {
+        let (alice, bob) = (Account::create().unwrap(), Account::create().unwrap());
+        let chain = chain_of("a", vec![vec![], vec![transfer(&alice, &bob, 0)]]);
+        let mut index = Indexer::default();
+        assert!(index.apply_block(&chain.chain[1]).is_err());
+        index.apply_block(&chain.chain[0]).unwrap();
+        index.apply_block(&chain.chain[1]).unwrap();
+        assert!(index.unwind_block(&chain.chain[0]).is_err());
+        index.unwind_block(&chain.chain[1]).unwrap();
+        assert_eq!((index.height, index.tip.as_deref()), (1, Some("a-0")));
+        assert!(index.txs.is_empty() && index.addresses.is_empty());
+    }
+
+    #[test]
+    fn a_reorg_swaps_the_indexed_branch() {
+        let (alice, bob) = (Account::create().unwrap(), Account::create().unwrap());
+        let old = transfer(&alice, &bob, 0);
+        let new = transfer(&bob, &alice, 0);
+        let (old_id, new_id) = (id(&old), id(&new));
+        let ours = chain_of("a", vec![vec![], vec![old]]);
+        let mut theirs = chain_of("b", vec![vec![], vec![new], vec![]]);
+        theirs.chain[0] = ours.chain[0].clone();
+        theirs.chain[1].prev_hash = ours.chain[0].block_hash.clone();
+        let mut index = Indexer::build(&ours).unwrap();
+        index
+            .handle(&NodeEvent::Reorg {
+                reverted: vec![Arc::new(ours.chain[1].clone())],
+                applied: theirs.chain[1..].iter().cloned().map(Arc::new).collect(),
+            })
+            .unwrap();
+        assert!(index.transaction(&old_id).is_none());
+        assert_eq!(index.transaction(&new_id).unwrap().height, 1);
+        assert_eq!(index.tip.as_deref(), Some("b-2"));
+    }
+
+    #[test]
+    fn a_saved_index_loads_back() {
+        let (alice, bob) = (Account::create().unwrap(), Account::create().unwrap());
+        let chain = chain_of("a", vec![vec![], vec![transfer(&alice, &bob, 0)]]);
+        let index = Indexer::build(&chain).unwrap();
+        let name = format!("essex-index-{:016x}.json", rand::random::<u64>());
+        let path = std::env::temp_dir().join(name);
+        assert_eq!(Indexer::load(&path).unwrap().height, 0);
+        index.save(&path).unwrap();
+        let loaded = Indexer::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(loaded.tip, index.tip);
+        assert_eq!(loaded.history(&bob.address()), index.history(&bob.address()));
+    }
+}
@@ -0,0 +1,275 @@
+use anyhow::{bail, Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::{rngs::OsRng, RngCore};
+use secp256k1::SecretKey;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
+
+use crate::account::account::Account;
+
+const KEYFILE_VERSION: u8 = 1;
+const KEYFILE_EXT: &str = "key";
+// scrypt cost parameters for new key files,
+// 2^15 rounds keeps unlocking under a second
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+// the most a key file may ask of scrypt, past this a
+// crafted file could eat gigabytes or hours on unlock
+const MAX_SCRYPT_LOG_N: u8 = 20;
+const MAX_SCRYPT_R: u32 = 16;
+const MAX_SCRYPT_P: u32 = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+    pub salt: String,
+}
+
+/// On-disk form of an account key, only the public
+/// key is readable without the passphrase
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyFile {
+    pub version: u8,
+    pub public: String,
+    pub kdf: KdfParams,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+pub struct Keystore {
+    pub dir: PathBuf,
+}
+
+fn derive_key(passphrase: &str, kdf: &KdfParams) -> Result<Zeroizing<[u8; 32]>> {
+    if kdf.log_n > MAX_SCRYPT_LOG_N || kdf.r > MAX_SCRYPT_R || kdf.p > MAX_SCRYPT_P {
+        bail!(
+            "kdf params log_n={} r={} p={} over the limit of {}/{}/{}",
+            kdf.log_n,
+            kdf.r,
+            kdf.p,
+            MAX_SCRYPT_LOG_N,
+            MAX_SCRYPT_R,
+            MAX_SCRYPT_P
+        );
+    }
+    let salt = hex::decode(&kdf.salt)?;
+    let params = scrypt::Params::new(kdf.log_n, kdf.r, kdf.p, 32)
+        .map_err(|e| anyhow::anyhow!("invalid kdf params: {}", e))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    scrypt::scrypt(passphrase.as_bytes(), &salt, &params, key.as_mut())
+        .map_err(|e| anyhow::anyhow!("kdf failed: {}", e))?;
+    Ok(key)
+}
+
+impl KeyFile {
+    pub fn seal(secret: &SecretKey, public: &str, passphrase: &str) -> Result<KeyFile> {
+        let mut salt = [0u8; 32];
+        let mut nonce = [0u8; 24];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        let kdf = KdfParams {
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            salt: hex::encode(salt),
+        };
+        let key = derive_key(passphrase, &kdf)?;
+        let cipher = XChaCha20Poly1305::new(key.as_ref().into());
+        let plain = Zeroizing::new(secret.secret_bytes());
+        // public key is bound as associated data so a
+        // key file can't be relabelled to another account
+        let ciphertext = cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                chacha20poly1305::aead::Payload {
+                    msg: plain.as_ref(),
+                    aad: public.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("key encryption failed"))?;
+        Ok(KeyFile {
+            version: KEYFILE_VERSION,
+            public: public.to_string(),
+            kdf,
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    pub fn unseal(&self, passphrase: &str) -> Result<SecretKey> {
+        if self.version != KEYFILE_VERSION {
+            bail!("unsupported key file version {}", self.version);
+        }
+        let key = derive_key(passphrase, &self.kdf)?;
+        let cipher = XChaCha20Poly1305::new(key.as_ref().into());
+        let nonce = hex::decode(&self.nonce)?;
+        if nonce.len() != 24 {
+            bail!("invalid key file nonce");
+        }
+        let ciphertext = hex::decode(&self.ciphertext)?;
+        let plain = Zeroizing::new(
+            cipher
+                .decrypt(
+                    XNonce::from_slice(&nonce),
+                    chacha20poly1305::aead::Payload {
+                        msg: &ciphertext,
+                        aad: self.public.as_bytes(),
+                    },
+                )
+                .map_err(|_| anyhow::anyhow!("wrong passphrase or corrupted key file"))?,
+        );
+        let secret = SecretKey::from_slice(&plain)?;
+        Ok(secret)
+    }
+}
+
+impl Keystore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Keystore> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).with_context(|| format!("creating keystore {:?}", dir))?;
+        Ok(Keystore { dir })
+    }
+
+    fn key_path(&self, public: &str) -> Result<PathBuf> {
+        // ids are hex public keys, anything else could
+        // be used to escape the keystore directory
+        if public.is_empty() || !public.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("invalid key id {}", public);
+        }
+        Ok(self.dir.join(format!("{}.{}", public, KEYFILE_EXT)))
+    }
+
+    pub fn save(&self, acc: &Account, passphrase: &str) -> Result<PathBuf> {
+        let public = acc.acc_public.to_string();
+        let keyfile = KeyFile::seal(&acc.acc_private, &public, passphrase)?;
+        let path = self.key_path(&public)?;
+        let mut opts = fs::File::options();
+        opts.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            opts.mode(0o600);
+        }
+        let mut file = opts
+            .open(&path)
+            .with_context(|| format!("writing key file {:?}", path))?;
+        file.write_all(serde_json::to_string_pretty(&keyfile)?.as_bytes())?;
+        file.sync_all()?;
+        log::info!("saved key {} to keystore", public);
+        Ok(path)
+    }
+
+    pub fn read_keyfile(&self, public: &str) -> Result<KeyFile> {
+        let path = self.key_path(public)?;
+        let raw = fs::read_to_string(&path).with_context(|| format!("no key {} in keystore", public))?;
+        let keyfile: KeyFile = serde_json::from_str(&raw)?;
+        if keyfile.public != public {
+            bail!("key file {:?} does not belong to {}", path, public);
+        }
+        Ok(keyfile)
+    }
+
+    pub fn load(&self, public: &str, passphrase: &str) -> Result<Account> {
+        let secret = self.read_keyfile(public)?.unseal(passphrase)?;
+        let acc = Account::from_secret(secret)?;
+        if acc.acc_public.to_string() != public {
+            bail!("key file {} holds a different key", public);
+        }
+        Ok(acc)
+    }
+
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|x| x.to_str()) != Some(KEYFILE_EXT) {
+                continue;
+            }
+            if let Some(stem) = path.file_stem().and_then(|x| x.to_str()) {
+                keys.push(stem.to_string());
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    pub fn delete(&self, public: &str) -> Result<()> {
+        let path = self.key_path(public)?;
+        fs::remove_file(&path).with_context(|| format!("no key {} in keystore", public))?;
+        log::info!("deleted key {} from keystore", public);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_keystore() -> Keystore {
+        let dir = std::env::temp_dir().join(format!("essex-keystore-{:016x}", rand::random::<u64>()));
+        Keystore::open(dir).unwrap()
+    }
+
+    #[test]
+    fn keys_unlock_with_their_passphrase_only() {
+        let keystore = scratch_keystore();
+        let acc = Account::create().unwrap();
+        let public = acc.acc_public.to_string();
+        keystore.save(&acc, "correct horse").unwrap();
+        assert_eq!(keystore.list().unwrap(), vec![public.clone()]);
+        assert!(keystore.save(&acc, "again").is_err());
+        let raw = fs::read_to_string(keystore.dir.join(format!("{}.{}", public, KEYFILE_EXT))).unwrap();
+        assert!(!raw.contains(&hex::encode(acc.acc_private.secret_bytes())));
+        assert!(keystore.load(&public, "wrong horse").is_err());
+        let loaded = keystore.load(&public, "correct horse").unwrap();
+        assert_eq!(loaded.acc_private, acc.acc_private);
+        keystore.delete(&public).unwrap();
+        assert!(keystore.list().unwrap().is_empty());
+        fs::remove_dir_all(&keystore.dir).unwrap();
+    }
+
+    #[test]
+    fn a_relabelled_key_file_does_not_open() {
+        let secret = SecretKey::new(&mut OsRng);
+        let mut keyfile = KeyFile::seal(&secret, "aa", "pass").unwrap();
+        keyfile.public = "bb".to_string();
+        assert!(keyfile.unseal("pass").is_err());
+    }
+
+    #[test]
+    fn costly_kdf_params_are_refused() {
+        let secret = SecretKey::new(&mut OsRng);
+        let keyfile = KeyFile::seal(&secret, "aa", "pass").unwrap();
+        for (log_n, r, p) in [(MAX_SCRYPT_LOG_N + 1, 8, 1), (15, 1 << 20, 1), (15, 8, 1 << 20)] {
+            let mut costly = keyfile.clone();
+            costly.kdf = KdfParams {
+                log_n,
+                r,
+                p,
+                ..keyfile.kdf.clone()
+            };
+            let err = costly.unseal("pass").unwrap_err();
+            assert!(err.to_string().contains("over the limit"), "{}", err);
+        }
+        assert_eq!(keyfile.unseal("pass").unwrap(), secret);
+    }
+
+    #[test]
+    fn key_ids_cannot_leave_the_keystore() {
+        let keystore = scratch_keystore();
+        assert!(keystore.read_keyfile("../secret").is_err());
+        assert!(keystore.delete("").is_err());
+        fs::remove_dir_all(&keystore.dir).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use rand::rngs::OsRng;
+use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+use secp256k1::Message;
+
+use crate::block::block::_BlockT;
+
+pub mod account;
+pub mod address;
+pub mod block;
+pub mod blockchain;
+pub mod chainspec;
+pub mod cli;
+pub mod codec;
+pub mod config;
+pub mod dynamic;
+pub mod events;
+pub mod explorer;
+pub mod identity;
+pub mod indexer;
+pub mod keystore;
+pub mod limits;
+pub mod multisig;
+pub mod node;
+pub mod payload;
+pub mod peers;
+pub mod reputation;
+pub mod rpc;
+pub mod sec8;
+pub mod state;
+pub mod storage;
+pub mod timestamp;
+pub mod transaction;
+pub mod wallet;
+pub mod weight;
+
+pub fn _check_rsa(msg: &str) {
+    let mut rng = rand::thread_rng();
+    let bits = 2048;
+    let private_key = RsaPrivateKey::new(&mut rng, bits).unwrap();
+    let public_key = RsaPublicKey::from(&private_key);
+    let encrypt_data = public_key
+        .encrypt(&mut rng, Pkcs1v15Encrypt, msg.as_bytes())
+        .unwrap();
+    let decrypt_data = private_key.decrypt(Pkcs1v15Encrypt, &encrypt_data).unwrap();
+    assert_ne!(msg.as_bytes(), encrypt_data);
+    assert_eq!(msg.as_bytes(), &decrypt_data[..]);
+}
+
+pub fn _check_secp256k1(msg: &[u8]) {
+    let secp = secp256k1::Secp256k1::new();
+    let (secret_key, public_key) = secp.generate_keypair(&mut OsRng);
+    log::debug!("selftest key {}", public_key);
+    let message = Message::from_digest_slice(msg);
+    match message {
+        Ok(mesx) => {
+            let sig = secp.sign_ecdsa(&mesx, &secret_key);
+            assert!(secp.verify_ecdsa(&mesx, &sig, &public_key).is_ok());
+        }
+        Err(e) => {
+            eprintln!("{:?}", e)
+        }
+    };
+}
+
+pub fn _tsmain() {
+    let spec = chainspec::chainspec::ChainSpec::default();
+    let mut chain = blockchain::blockchain::Blockchain::genesis(&spec);
+    let mut state = chain.verify(&spec).unwrap();
+    let genesis = chain.head().cloned().unwrap();
+    let account = account::account::Account::create().unwrap();
+    let mut pending = vec![transaction::transaction::BlockEntry::record("hello")];
+    let cb = <block::block::Block as _BlockT>::create_essex_block(
+        genesis,
+        account,
+        &mut pending,
+        &spec,
+    )
+    .unwrap();
+    chain
+        .extend(cb, &mut state, &spec, timestamp::timestamp::Timestamp::now())
+        .unwrap();
+    println!("{:?}", chain);
+}
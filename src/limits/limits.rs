@@ -0,0 +1,326 @@
+use libp2p::connection_limits::ConnectionLimits;
+use libp2p::core::{multiaddr::Protocol, ConnectedPoint, Endpoint, Multiaddr};
+use libp2p::swarm::{
+    dummy, ConnectionClosed, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour,
+    THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+};
+use libp2p::PeerId;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::fmt;
+use std::net::IpAddr;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use crate::codec::codec::NetMessage;
+use crate::config::config::{LimitsConfig, RateConfig};
+
+/// Node wide caps on connections, per IP limits
+/// are left to `IpLimits` which libp2p has no knob for
+pub fn connection_limits(config: &LimitsConfig) -> ConnectionLimits {
+    ConnectionLimits::default()
+        .with_max_pending_incoming(Some(config.max_pending_incoming))
+        .with_max_pending_outgoing(Some(config.max_pending_outgoing))
+        .with_max_established_incoming(Some(config.max_incoming))
+        .with_max_established_outgoing(Some(config.max_outgoing))
+        .with_max_established_per_peer(Some(config.max_per_peer))
+}
+
+fn ip_of(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter().find_map(|p| match p {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
+
+#[derive(Debug)]
+pub struct IpLimitExceeded {
+    pub ip: IpAddr,
+    pub limit: u32,
+}
+
+impl fmt::Display for IpLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} already has {} connections", self.ip, self.limit)
+    }
+}
+
+impl std::error::Error for IpLimitExceeded {}
+
+/// Refuses inbound connections from an IP that already
+/// holds `max_per_ip` connections, so one host can't
+/// take every slot by running many peer ids
+pub struct IpLimits {
+    max_per_ip: u32,
+    per_ip: HashMap<IpAddr, HashSet<ConnectionId>>,
+}
+
+impl IpLimits {
+    pub fn new(max_per_ip: u32) -> Self {
+        IpLimits {
+            max_per_ip,
+            per_ip: HashMap::new(),
+        }
+    }
+
+    fn check(&self, remote: &Multiaddr) -> Result<(), ConnectionDenied> {
+        let Some(ip) = ip_of(remote) else {
+            return Ok(());
+        };
+        let count = self.per_ip.get(&ip).map(|x| x.len()).unwrap_or(0);
+        if count >= self.max_per_ip as usize {
+            return Err(ConnectionDenied::new(IpLimitExceeded {
+                ip,
+                limit: self.max_per_ip,
+            }));
+        }
+        Ok(())
+    }
+}
+
+impl NetworkBehaviour for IpLimits {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: &Multiaddr,
+        remote: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.check(remote)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        remote: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.check(remote)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    // we pick who we dial, only inbound is limited
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(e) => {
+                if let Some(ip) = ip_of(e.endpoint.get_remote_address()) {
+                    self.per_ip.entry(ip).or_default().insert(e.connection_id);
+                }
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                connection_id,
+                endpoint,
+                ..
+            }) => {
+                let remote = match endpoint {
+                    ConnectedPoint::Dialer { address, .. } => address,
+                    ConnectedPoint::Listener { send_back_addr, .. } => send_back_addr,
+                };
+                if let Some(ip) = ip_of(remote) {
+                    if let Some(conns) = self.per_ip.get_mut(&ip) {
+                        conns.remove(&connection_id);
+                        if conns.is_empty() {
+                            self.per_ip.remove(&ip);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        Poll::Pending
+    }
+}
+
+/// Gossip traffic classes, each has its own budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MsgKind {
+    Block,
+    Transaction,
+    Text,
+    // clock and status announcements
+    Control,
+}
+
+impl MsgKind {
+    pub fn of(msg: &NetMessage) -> MsgKind {
+        match msg {
+            NetMessage::Block(_) => MsgKind::Block,
+            NetMessage::Transaction(_) => MsgKind::Transaction,
+            NetMessage::Text(_) => MsgKind::Text,
+            NetMessage::Clock(_) | NetMessage::Status { .. } => MsgKind::Control,
+        }
+    }
+
+    // the kind of a message still encoded, so a peer over
+    // its budget doesn't get us to decode what it sent,
+    // None for what wouldn't decode anyway
+    pub fn peek(data: &[u8]) -> Option<MsgKind> {
+        match NetMessage::peek_tag(data)? {
+            0 => Some(MsgKind::Text),
+            1 => Some(MsgKind::Block),
+            2 => Some(MsgKind::Transaction),
+            3 | 4 => Some(MsgKind::Control),
+            _ => None,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Token buckets per peer and message kind, a peer
+/// gets `burst` messages at once and `per_sec` after
+pub struct RateLimiter {
+    rates: HashMap<MsgKind, RateConfig>,
+    buckets: HashMap<(PeerId, MsgKind), Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &LimitsConfig) -> Self {
+        let rates = HashMap::from([
+            (MsgKind::Block, config.blocks.clone()),
+            (MsgKind::Transaction, config.transactions.clone()),
+            (MsgKind::Text, config.messages.clone()),
+            (MsgKind::Control, config.control.clone()),
+        ]);
+        RateLimiter {
+            rates,
+            buckets: HashMap::new(),
+        }
+    }
+
+    // takes a token, false means the peer is over budget
+    pub fn allow(&mut self, peer: &PeerId, kind: MsgKind, now: Instant) -> bool {
+        let Some(rate) = self.rates.get(&kind) else {
+            return true;
+        };
+        let bucket = self.buckets.entry((*peer, kind)).or_insert(Bucket {
+            tokens: rate.burst,
+            refilled: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate.per_sec).min(rate.burst);
+        bucket.refilled = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.buckets.retain(|(p, _), _| p != peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limiter() -> RateLimiter {
+        let config = LimitsConfig {
+            blocks: RateConfig::new(2.0, 3.0),
+            ..LimitsConfig::default()
+        };
+        RateLimiter::new(&config)
+    }
+
+    #[test]
+    fn a_peer_gets_its_burst_then_the_rate() {
+        let mut rl = limiter();
+        let peer = PeerId::random();
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(rl.allow(&peer, MsgKind::Block, start));
+        }
+        assert!(!rl.allow(&peer, MsgKind::Block, start));
+        // two per second, so one more after half a second
+        let later = start + Duration::from_millis(500);
+        assert!(rl.allow(&peer, MsgKind::Block, later));
+        assert!(!rl.allow(&peer, MsgKind::Block, later));
+        // idle time refills up to the burst and no further
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(rl.allow(&peer, MsgKind::Block, much_later));
+        }
+        assert!(!rl.allow(&peer, MsgKind::Block, much_later));
+    }
+
+    #[test]
+    fn budgets_are_per_peer_and_kind() {
+        let mut rl = limiter();
+        let (noisy, quiet) = (PeerId::random(), PeerId::random());
+        let now = Instant::now();
+        while rl.allow(&noisy, MsgKind::Block, now) {}
+        assert!(rl.allow(&quiet, MsgKind::Block, now));
+        assert!(rl.allow(&noisy, MsgKind::Transaction, now));
+        rl.remove_peer(&noisy);
+        assert!(rl.allow(&noisy, MsgKind::Block, now));
+    }
+
+    #[test]
+    fn the_kind_is_read_off_the_wire_before_decoding() {
+        use crate::codec::codec;
+        use crate::timestamp::timestamp::Timestamp;
+        let msgs = [
+            NetMessage::Text("hi".to_string()),
+            NetMessage::Clock(Timestamp::now()),
+            NetMessage::Status {
+                best_height: 1,
+                best_hash: String::new(),
+            },
+        ];
+        for msg in msgs {
+            assert_eq!(MsgKind::peek(&codec::to_bytes(&msg)), Some(MsgKind::of(&msg)));
+        }
+        // a block tag is enough, the rest can be garbage
+        let garbage = [codec::CODEC_VERSION, 1, 0xff, 0xff];
+        assert_eq!(MsgKind::peek(&garbage), Some(MsgKind::Block));
+        assert_eq!(MsgKind::peek(&[codec::CODEC_VERSION, 9]), None);
+        assert_eq!(MsgKind::peek(&[codec::CODEC_VERSION + 1, 0]), None);
+        assert_eq!(MsgKind::peek(&[]), None);
+    }
+
+    #[test]
+    fn one_ip_can_only_hold_so_many_connections() {
+        let mut limits = IpLimits::new(2);
+        let remote: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let ip = ip_of(&remote).unwrap();
+        assert!(limits.check(&remote).is_ok());
+        let conns = (1..=2).map(ConnectionId::new_unchecked).collect();
+        limits.per_ip.insert(ip, conns);
+        assert!(limits.check(&remote).is_err());
+        assert!(limits.check(&"/ip4/10.0.0.2/tcp/4001".parse().unwrap()).is_ok());
+        // nothing to count by without an ip
+        assert!(limits.check(&"/dns4/example.com/tcp/4001".parse().unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,300 @@
+use anyhow::{bail, Context, Result};
+use secp256k1::hashes::{hash160, Hash};
+use secp256k1::{ecdsa::Signature, PublicKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::{
+    account::account::{Account, TX_DOMAIN},
+    address::address::Address,
+    transaction::transaction::Transaction,
+};
+
+pub const MAX_MULTISIG_KEYS: usize = 16;
+// keeps multisig addresses apart from single key ones
+const MULTISIG_TAG: &[u8] = b"essex-multisig";
+
+/// M-of-N account, funds sent to its address only
+/// move with `threshold` distinct signatures from `keys`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultisigAccount {
+    pub threshold: u8,
+    // compressed lowercase hex public keys, kept sorted
+    // so the same set always gives the same address
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartialSignature {
+    pub public: String,
+    pub signature: String,
+}
+
+/// A multisig transaction being passed around between
+/// key holders, each one adds a signature offline until
+/// the threshold is met
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartiallySignedTx {
+    pub multisig: MultisigAccount,
+    pub tx: Transaction,
+    pub signatures: Vec<PartialSignature>,
+}
+
+impl MultisigAccount {
+    pub fn new(threshold: u8, keys: &[PublicKey]) -> Result<MultisigAccount> {
+        let mut keys: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+        keys.sort();
+        keys.dedup();
+        let ms = MultisigAccount { threshold, keys };
+        ms.check()?;
+        Ok(ms)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.keys.is_empty() || self.keys.len() > MAX_MULTISIG_KEYS {
+            bail!("multisig needs between 1 and {} keys", MAX_MULTISIG_KEYS);
+        }
+        if self.threshold == 0 || self.threshold as usize > self.keys.len() {
+            bail!("threshold {} out of range for {} keys", self.threshold, self.keys.len());
+        }
+        // a key in another case or uncompressed parses to the
+        // same point, it would let one holder count twice
+        for k in &self.keys {
+            let public = k
+                .parse::<PublicKey>()
+                .with_context(|| format!("invalid multisig key {}", k))?;
+            if *k != public.to_string() {
+                bail!("multisig key {} is not in compressed lowercase form", k);
+            }
+        }
+        let mut sorted = self.keys.clone();
+        sorted.sort();
+        sorted.dedup();
+        if sorted != self.keys {
+            bail!("multisig keys must be sorted and distinct");
+        }
+        Ok(())
+    }
+
+    // the member keys as points, unparsable ones left out
+    fn members(&self) -> HashSet<PublicKey> {
+        self.keys.iter().filter_map(|k| k.parse().ok()).collect()
+    }
+
+    // hashes the canonical form of the keys so the address
+    // belongs to the key set, not to how it is written
+    pub fn address(&self) -> Address {
+        let mut keys: Vec<String> = self
+            .keys
+            .iter()
+            .map(|k| k.parse::<PublicKey>().map(|x| x.to_string()).unwrap_or_else(|_| k.clone()))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        let mut data = MULTISIG_TAG.to_vec();
+        data.push(self.threshold);
+        for k in &keys {
+            data.extend_from_slice(k.as_bytes());
+        }
+        let digest = hash160::Hash::hash(&data);
+        Address::from_bytes(&digest.to_byte_array()).expect("hash160 is address sized")
+    }
+
+    // counts distinct member keys with a valid signature
+    // over the tx payload, anything else is ignored, keys
+    // are told apart as points and not by how they're written
+    pub fn valid_signers(&self, tx: &Transaction, sigs: &[PartialSignature]) -> usize {
+        let payload = tx.signing_payload();
+        let members = self.members();
+        let mut signers = HashSet::new();
+        for ps in sigs {
+            let (Ok(public), Ok(sig)) = (ps.public.parse::<PublicKey>(), ps.signature.parse::<Signature>())
+            else {
+                continue;
+            };
+            if !members.contains(&public) || signers.contains(&public) {
+                continue;
+            }
+            if Account::verify_with_domain(TX_DOMAIN, &payload, &sig, &public).is_ok() {
+                signers.insert(public);
+            }
+        }
+        signers.len()
+    }
+
+    pub fn verify_transaction(&self, tx: &Transaction) -> Result<()> {
+        if tx.tx_from != self.address() {
+            bail!("tx sender {} is not this multisig", tx.tx_from);
+        }
+        tx.verify_id()?;
+        let signers = self.valid_signers(tx, &tx.tx_signatures);
+        if signers < self.threshold as usize {
+            bail!("multisig tx has {} of {} signatures", signers, self.threshold);
+        }
+        Ok(())
+    }
+}
+
+impl PartiallySignedTx {
+    pub fn new(multisig: MultisigAccount, tx: Transaction) -> Result<PartiallySignedTx> {
+        multisig.check()?;
+        if tx.tx_from != multisig.address() {
+            bail!("tx sender {} is not this multisig", tx.tx_from);
+        }
+        Ok(PartiallySignedTx {
+            multisig,
+            tx,
+            signatures: vec![],
+        })
+    }
+
+    pub fn sign(&mut self, acc: &Account) -> Result<()> {
+        let public = acc.acc_public.to_string();
+        if !self.multisig.keys.contains(&public) {
+            bail!("{} is not a member of this multisig", acc.address());
+        }
+        if self.signatures.iter().any(|x| x.public == public) {
+            return Ok(());
+        }
+        let sig = acc.sign_with_domain(TX_DOMAIN, &self.tx.signing_payload());
+        self.signatures.push(PartialSignature {
+            public,
+            signature: sig.to_string(),
+        });
+        Ok(())
+    }
+
+    // pick up signatures another key holder collected
+    pub fn merge(&mut self, other: &PartiallySignedTx) -> Result<()> {
+        if other.tx.tx_header.transaction_id != self.tx.tx_header.transaction_id {
+            bail!("partial signatures are for a different transaction");
+        }
+        for ps in &other.signatures {
+            if !self.signatures.contains(ps) {
+                self.signatures.push(ps.clone());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.multisig.valid_signers(&self.tx, &self.signatures) >= self.multisig.threshold as usize
+    }
+
+    pub fn finalize(mut self) -> Result<Transaction> {
+        self.tx.tx_signatures = self.signatures;
+        self.multisig.verify_transaction(&self.tx)?;
+        Ok(self.tx)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("writing {}", path))?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<PartiallySignedTx> {
+        let raw = std::fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+        Ok(serde_json::from_str(&raw)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(n: usize) -> Vec<Account> {
+        (0..n).map(|_| Account::create().unwrap()).collect()
+    }
+
+    fn two_of_three() -> (Vec<Account>, MultisigAccount, PartiallySignedTx) {
+        let accs = members(3);
+        let keys: Vec<PublicKey> = accs.iter().map(|a| a.acc_public).collect();
+        let ms = MultisigAccount::new(2, &keys).unwrap();
+        let tx = Transaction::unsigned(ms.address(), accs[0].address(), String::new(), 5, 0);
+        let pst = PartiallySignedTx::new(ms.clone(), tx).unwrap();
+        (accs, ms, pst)
+    }
+
+    #[test]
+    fn needs_the_threshold_of_distinct_members() {
+        let (accs, _, mut pst) = two_of_three();
+        pst.sign(&accs[0]).unwrap();
+        pst.sign(&accs[0]).unwrap();
+        assert!(!pst.is_complete());
+        assert!(pst.clone().finalize().is_err());
+        assert!(pst.sign(&Account::create().unwrap()).is_err());
+        pst.sign(&accs[2]).unwrap();
+        assert!(pst.is_complete());
+        pst.finalize().unwrap();
+    }
+
+    #[test]
+    fn signatures_collected_apart_merge() {
+        let (accs, ms, mut first) = two_of_three();
+        let mut second = first.clone();
+        first.sign(&accs[1]).unwrap();
+        second.sign(&accs[2]).unwrap();
+        first.merge(&second).unwrap();
+        first.merge(&second).unwrap();
+        assert_eq!(first.signatures.len(), 2);
+        let tx = first.finalize().unwrap();
+        ms.verify_transaction(&tx).unwrap();
+        let other = Transaction::unsigned(ms.address(), accs[1].address(), String::new(), 5, 1);
+        let mut unrelated = PartiallySignedTx::new(ms, other).unwrap();
+        assert!(unrelated.merge(&second).is_err());
+    }
+
+    #[test]
+    fn a_tampered_tx_loses_its_signatures() {
+        let (accs, ms, mut pst) = two_of_three();
+        pst.sign(&accs[0]).unwrap();
+        pst.sign(&accs[1]).unwrap();
+        let mut tx = pst.finalize().unwrap();
+        tx.tx_amount = 500;
+        assert!(ms.verify_transaction(&tx).is_err());
+    }
+
+    #[test]
+    fn address_depends_on_keys_and_threshold_not_order() {
+        let accs = members(3);
+        let keys: Vec<PublicKey> = accs.iter().map(|a| a.acc_public).collect();
+        let mut reversed = keys.clone();
+        reversed.reverse();
+        let ms = MultisigAccount::new(2, &keys).unwrap();
+        assert_eq!(MultisigAccount::new(2, &reversed).unwrap().address(), ms.address());
+        assert_ne!(MultisigAccount::new(1, &keys).unwrap().address(), ms.address());
+        assert!(MultisigAccount::new(0, &keys).is_err());
+        assert!(MultisigAccount::new(4, &keys).is_err());
+        assert!(MultisigAccount::new(1, &[]).is_err());
+    }
+
+    #[test]
+    fn one_key_written_two_ways_is_one_member() {
+        let (accs, ms, mut pst) = two_of_three();
+        let upper = accs[0].acc_public.to_string().to_uppercase();
+        let uncompressed: String = accs[0]
+            .acc_public
+            .serialize_uncompressed()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        for other in [upper.clone(), uncompressed] {
+            let mut keys = vec![accs[0].acc_public.to_string(), other];
+            keys.sort();
+            let dup = MultisigAccount { threshold: 2, keys };
+            assert!(dup.check().is_err());
+        }
+        let mut keys = ms.keys.clone();
+        keys[0] = keys[0].to_uppercase();
+        let relabelled = MultisigAccount { threshold: 2, keys };
+        assert_eq!(relabelled.address(), ms.address());
+        // the same signature under another spelling of the key
+        pst.sign(&accs[0]).unwrap();
+        let mut again = pst.signatures[0].clone();
+        again.public = upper;
+        pst.signatures.push(again);
+        assert_eq!(ms.valid_signers(&pst.tx, &pst.signatures), 1);
+        assert!(!pst.is_complete());
+    }
+}
@@ -0,0 +1,1353 @@
+use anyhow::{anyhow, bail, Result};
+use futures::stream::StreamExt;
+use libp2p::{gossipsub, identify, kad, mdns, ping, swarm::SwarmEvent};
+use libp2p::{Multiaddr, PeerId, StreamProtocol, Swarm};
+use log::info;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::timeout_at;
+
+use crate::account::account::Account;
+use crate::address::address::Address;
+use crate::block::block::Block;
+use crate::blockchain::blockchain::{self, Blockchain, Checkpoint, SideBlocks, TxLocation};
+use crate::chainspec::chainspec::ChainSpec;
+use crate::codec::codec::{self, NetMessage};
+use crate::config::config::{LockInfo, NodeConfig, NodeInfo, NodeRole};
+use crate::dynamic::{self, EssexBehaviour, EssexBehaviourEvent};
+use crate::events::events::{EventBus, EventFilter, NodeEvent, Subscription};
+use crate::identity::identity;
+use crate::indexer::indexer::Indexer;
+use crate::limits::limits::{MsgKind, RateLimiter};
+use crate::peers::peers::{NodeProtocol, PeerInfo, PeerTable};
+use crate::reputation::reputation::{PeerEvent, Reputation, Verdict};
+use crate::rpc::rpc;
+use crate::state::state::State;
+use crate::storage::storage::DirLock;
+use crate::timestamp::timestamp::{self, NetworkClock, Timestamp};
+use crate::transaction::transaction::{BlockEntry, Transaction, TransactionPool};
+use crate::weight::weight;
+
+// events a subscriber can fall behind by before
+// it starts missing them
+const EVENT_CAPACITY: usize = 1024;
+const COMMAND_CAPACITY: usize = 64;
+
+/// Chain, state and mempool as the node sees them,
+/// shared by the node task and every handle
+#[derive(Debug)]
+pub struct ChainStore {
+    pub chain: Blockchain,
+    // state after applying the whole chain
+    pub state: State,
+    pub mempool: TransactionPool,
+    // follows the chain off the event bus, see index_chain
+    pub index: Indexer,
+    // blocks off the chain, see Blockchain::fork_choice
+    pub side: SideBlocks,
+    // state at the last final block, reorgs replay from it
+    pub settled: Checkpoint,
+}
+
+// what a handle asks of the node task
+enum NodeCommand {
+    Publish(NetMessage, oneshot::Sender<Result<()>>),
+    Dial(Multiaddr, oneshot::Sender<Result<()>>),
+    Shutdown,
+}
+
+/// A transaction the node knows about
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum TxStatus {
+    Pending { tx: Transaction },
+    Included { location: TxLocation, tx: Transaction },
+}
+
+/// A node that has been configured but not started
+pub struct Node {
+    config: NodeConfig,
+    spec: ChainSpec,
+    // signs the blocks of an authority
+    validator: Option<Account>,
+}
+
+impl Node {
+    pub fn new(config: NodeConfig, spec: ChainSpec) -> Result<Node> {
+        config.check()?;
+        Ok(Node {
+            config,
+            spec,
+            validator: None,
+        })
+    }
+
+    pub fn with_validator(mut self, validator: Account) -> Node {
+        self.validator = Some(validator);
+        self
+    }
+
+    pub fn from_config(config: NodeConfig) -> Result<Node> {
+        let spec = config.chain_spec()?;
+        Node::new(config, spec)
+    }
+
+    pub fn config(&self) -> &NodeConfig {
+        &self.config
+    }
+
+    // sets up the swarm and spawns the node onto the
+    // tokio runtime this is awaited on
+    pub async fn start(self) -> Result<NodeHandle> {
+        let Node { config, spec, validator } = self;
+        if config.role == NodeRole::Authority && validator.is_none() {
+            bail!("an authority needs its validator key to produce blocks");
+        }
+        std::fs::create_dir_all(&config.data_dir)?;
+        // one node per data dir, held until it has flushed
+        let Some(mut lock) = DirLock::try_acquire(config.lock_path())? else {
+            bail!("data dir {:?} is in use by another node", config.data_dir);
+        };
+        // bound up front so a taken port fails the start
+        let rpc_listener = match config.rpc.enabled {
+            true => Some(TcpListener::bind(config.rpc.listen).await?),
+            false => None,
+        };
+        let rpc_addr = match &rpc_listener {
+            Some(listener) => Some(listener.local_addr()?),
+            None => None,
+        };
+        let holder = LockInfo {
+            pid: std::process::id(),
+            rpc: rpc_addr,
+        };
+        lock.record(&serde_json::to_string(&holder)?)?;
+        // same PeerId across restarts so peers can
+        // whitelist us and validators can attest to it
+        let node_key = identity::load_or_create_node_key(config.node_key_path())?;
+        let chain = Blockchain::load(config.chain_path(), &spec)?;
+        let state = chain.verify(&spec)?;
+        let mut settled = Checkpoint::genesis(&spec)?;
+        settled.advance(&chain, &spec)?;
+        let index = match Indexer::load(config.index_path()) {
+            Ok(index) => index,
+            Err(e) => {
+                info!("rebuilding the index: {}", e);
+                Indexer::default()
+            }
+        };
+        let index = index.catch_up(&chain)?;
+        let genesis_hash = chain.chain.first().map(|x| x.block_hash.clone());
+        let local_protocol = NodeProtocol::new(&spec.chain_id, genesis_hash.as_deref());
+        let mut swarm = dynamic::build_swarm(&config, &spec, node_key, &local_protocol)?;
+        let topic = gossipsub::IdentTopic::new(&config.gossip.topic);
+        swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+        // whatever was queued while we were down goes
+        // back in if it still applies
+        let limits = &config.limits;
+        let mut mempool = TransactionPool::with_limits(limits.max_pool_txs, limits.max_pool_txs_per_sender);
+        for tx in TransactionPool::load(config.mempool_path())?.transactions {
+            let id = tx.tx_header.transaction_id.clone();
+            if let Err(e) = mempool.admit(&state, tx) {
+                info!("dropping queued tx {}: {}", id, e);
+            }
+        }
+        // bans outlive restarts, peers that were
+        // banned are still blocked when we come back
+        let bans_path = config.bans_path();
+        let reputation = Reputation::load(&bans_path, config.reputation.clone())?;
+        for peer in reputation.banned(Timestamp::now()) {
+            dynamic::ban_peer(&mut swarm, &peer);
+        }
+        for addr in config.listen.iter() {
+            swarm.listen_on(addr.clone())?;
+        }
+        let mut bootstrap_known = false;
+        // peers on our chain from the last run seed the
+        // dht alongside the bootstrap list
+        let known = PeerTable::load(config.peers_path())?;
+        for info in known.peers.values() {
+            if info.chain_id.as_deref() == Some(spec.chain_id.as_str()) {
+                for addr in info.addresses.iter() {
+                    swarm.behaviour_mut().kad.add_address(&info.peer_id, addr.clone());
+                    bootstrap_known = true;
+                }
+            }
+        }
+        for addr in config.bootstrap.iter() {
+            if let Some((peer_id, base)) = dynamic::split_peer_id(addr) {
+                swarm.behaviour_mut().kad.add_address(&peer_id, base);
+                bootstrap_known = true;
+            }
+            if let Err(e) = swarm.dial(addr.clone()) {
+                println!("cannot dial bootstrap peer {addr}: {e}");
+            }
+        }
+        if bootstrap_known {
+            let _ = swarm.behaviour_mut().kad.bootstrap();
+        }
+
+        let store = Arc::new(RwLock::new(ChainStore {
+            chain,
+            state,
+            mempool,
+            index,
+            side: SideBlocks::default(),
+            settled,
+        }));
+        let peers = Arc::new(RwLock::new(known));
+        let events = EventBus::new(EVENT_CAPACITY);
+        let (command_tx, command_rx) = mpsc::channel(COMMAND_CAPACITY);
+        let (stopped_tx, stopped_rx) = watch::channel(false);
+        // subscribed before the worker can apply anything
+        tokio::spawn(index_chain(
+            store.clone(),
+            events.subscribe(EventFilter::chain()),
+            stopped_rx.clone(),
+        ));
+        // bounded both ways, a full queue drops gossip
+        // instead of letting it pile up in memory
+        let (work_tx, mut work_rx) = mpsc::channel::<ChainWork>(config.limits.queue_capacity);
+        let (verdict_tx, verdict_rx) = mpsc::channel::<ChainVerdict>(config.limits.queue_capacity);
+        let stopping = Arc::new(AtomicBool::new(false));
+        let worker = ChainWorker {
+            spec: spec.clone(),
+            chain_path: config.chain_path(),
+            store: store.clone(),
+            events: events.clone(),
+        };
+        // a full node keeps no key even if it was given one
+        let producer = match config.role {
+            NodeRole::Authority => validator.map(|validator| BlockProducer {
+                worker: worker.clone(),
+                validator: Arc::new(Mutex::new(validator)),
+            }),
+            NodeRole::Full => None,
+        };
+        let worker_stopping = stopping.clone();
+        let worker = tokio::task::spawn_blocking(move || {
+            while let Some(work) = work_rx.blocking_recv() {
+                // whatever is still queued at shutdown is dropped
+                if worker_stopping.load(Ordering::Acquire) {
+                    break;
+                }
+                if verdict_tx.blocking_send(worker.process(work)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut info = NodeInfo::detect(&config, &spec);
+        if rpc_addr.is_some() {
+            info.rpc = rpc_addr;
+        }
+        let rpc_config = config.rpc.clone();
+        let handle = NodeHandle {
+            peer_id: *swarm.local_peer_id(),
+            info,
+            spec: Arc::new(spec.clone()),
+            commands: command_tx,
+            events: events.clone(),
+            stopped: stopped_rx,
+            store: store.clone(),
+            peers: peers.clone(),
+        };
+        let kad_proto = dynamic::kad_protocol(&spec.chain_id)?;
+        let runner = NodeRunner {
+            rate_limiter: RateLimiter::new(&config.limits),
+            random_walk: Duration::from_secs(config.discovery.random_walk_secs),
+            block_interval: Duration::from_secs(config.block_interval_secs),
+            producer,
+            config,
+            spec,
+            swarm,
+            topic,
+            kad_proto,
+            local_protocol,
+            reputation,
+            bans_path,
+            clock: NetworkClock::new(),
+            store,
+            peers,
+            events,
+            work_tx: Some(work_tx),
+            stopping,
+        };
+        tokio::spawn(async move {
+            runner.run(command_rx, verdict_rx, worker).await;
+            drop(lock);
+            let _ = stopped_tx.send(true);
+        });
+        if let Some(listener) = rpc_listener {
+            tokio::spawn(rpc::serve(handle.clone(), listener, rpc_config));
+        }
+        Ok(handle)
+    }
+}
+
+/// Controls a running node and reads its view of
+/// the chain, clones all talk to the same node
+#[derive(Clone)]
+pub struct NodeHandle {
+    peer_id: PeerId,
+    info: NodeInfo,
+    spec: Arc<ChainSpec>,
+    commands: mpsc::Sender<NodeCommand>,
+    events: EventBus,
+    stopped: watch::Receiver<bool>,
+    store: Arc<RwLock<ChainStore>>,
+    peers: Arc<RwLock<PeerTable>>,
+}
+
+impl NodeHandle {
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    pub fn info(&self) -> &NodeInfo {
+        &self.info
+    }
+
+    pub fn spec(&self) -> &ChainSpec {
+        &self.spec
+    }
+
+    pub fn subscribe(&self, filter: EventFilter) -> Subscription {
+        self.events.subscribe(filter)
+    }
+
+    pub fn head(&self) -> Option<Block> {
+        self.store.read().unwrap().chain.head().cloned()
+    }
+
+    pub fn block_by_height(&self, height: u64) -> Option<Block> {
+        self.store.read().unwrap().chain.block_by_height(height).cloned()
+    }
+
+    pub fn finalized_height(&self) -> u64 {
+        self.store.read().unwrap().chain.finalized_height(&self.spec)
+    }
+
+    pub fn block_by_hash(&self, hash: &str) -> Option<Block> {
+        self.store.read().unwrap().chain.block_by_hash(hash).cloned()
+    }
+
+    pub fn balance(&self, addr: &Address) -> u32 {
+        self.store.read().unwrap().state.balance(addr)
+    }
+
+    // the nonce on chain, not counting queued txs
+    pub fn nonce(&self, addr: &Address) -> u64 {
+        self.store.read().unwrap().state.nonce(addr)
+    }
+
+    pub fn next_nonce(&self, addr: &Address) -> u64 {
+        let store = self.store.read().unwrap();
+        store.mempool.next_nonce(&store.state, addr)
+    }
+
+    pub fn mempool(&self) -> Vec<Transaction> {
+        self.store.read().unwrap().mempool.transactions.clone()
+    }
+
+    // the mempool first, then the chain
+    pub fn transaction(&self, id: &str) -> Option<TxStatus> {
+        let store = self.store.read().unwrap();
+        if let Some(tx) = store.mempool.get(id) {
+            return Some(TxStatus::Pending { tx: tx.clone() });
+        }
+        let location = store.index.transaction(id)?;
+        let tx = store.chain.transaction_at(location)?;
+        Some(TxStatus::Included {
+            location: location.clone(),
+            tx,
+        })
+    }
+
+    // transfers from or to `addr` on the chain, newest first
+    pub fn history(&self, addr: &Address) -> Vec<(TxLocation, Transaction)> {
+        let store = self.store.read().unwrap();
+        store
+            .index
+            .history(addr)
+            .into_iter()
+            .filter_map(|location| Some((location.clone(), store.chain.transaction_at(location)?)))
+            .collect()
+    }
+
+    // throws the index away and builds it again from
+    // the chain, returns how many blocks it covers
+    pub fn reindex(&self) -> Result<u64> {
+        let mut store = self.store.write().unwrap();
+        store.index = Indexer::build(&store.chain)?;
+        Ok(store.index.height)
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.peers.read().unwrap().connected().cloned().collect()
+    }
+
+    // checks `tx`, queues it and gossips it, the tx
+    // stays queued even when no peer is there to take it
+    // signatures are checked by admit against the
+    // sender's account, multisig or not
+    pub async fn submit_transaction(&self, tx: Transaction) -> Result<String> {
+        let id = tx.tx_header.transaction_id.clone();
+        {
+            let mut store = self.store.write().unwrap();
+            let ChainStore { chain, state, mempool, .. } = &mut *store;
+            weight::check_tx_weight(&tx, self.spec.max_block_weight(chain.chain.len() as u64))?;
+            if let Some(old) = mempool.admit(state, tx.clone())? {
+                self.events.publish(evicted(old));
+            }
+        }
+        self.events.publish(NodeEvent::TransactionAccepted(Arc::new(tx.clone())));
+        if let Err(e) = self.publish(NetMessage::Transaction(Box::new(tx))).await {
+            info!("tx {} not gossiped yet: {}", id, e);
+        }
+        Ok(id)
+    }
+
+    pub async fn dial(&self, addr: Multiaddr) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        if self.commands.send(NodeCommand::Dial(addr, reply)).await.is_err() {
+            bail!("node has stopped");
+        }
+        rx.await?
+    }
+
+    pub async fn publish_text(&self, text: String) -> Result<()> {
+        self.publish(NetMessage::Text(text)).await
+    }
+
+    async fn publish(&self, msg: NetMessage) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        if self.commands.send(NodeCommand::Publish(msg, reply)).await.is_err() {
+            bail!("node has stopped");
+        }
+        rx.await?
+    }
+
+    // resolves once the node task has finished
+    pub async fn stopped(&self) {
+        let mut stopped = self.stopped.clone();
+        let _ = stopped.wait_for(|x| *x).await;
+    }
+
+    // asks the node to stop and waits until it has,
+    // see NodeConfig::shutdown_deadline_secs
+    pub async fn shutdown(&self) -> Result<()> {
+        let _ = self.commands.send(NodeCommand::Shutdown).await;
+        self.stopped().await;
+        Ok(())
+    }
+
+    // a handle on a fresh chain with no network behind
+    // it, commands fail as if the node had stopped
+    #[cfg(test)]
+    pub(crate) fn detached(spec: ChainSpec) -> NodeHandle {
+        let chain = Blockchain::genesis(&spec);
+        let state = chain.verify(&spec).unwrap();
+        let store = ChainStore {
+            index: Indexer::build(&chain).unwrap(),
+            chain,
+            state,
+            mempool: TransactionPool::default(),
+            side: SideBlocks::default(),
+            settled: Checkpoint::genesis(&spec).unwrap(),
+        };
+        let (commands, _) = mpsc::channel(1);
+        let (_, stopped) = watch::channel(false);
+        NodeHandle {
+            peer_id: PeerId::random(),
+            info: NodeInfo::detect(&NodeConfig::default(), &spec),
+            spec: Arc::new(spec),
+            commands,
+            events: EventBus::new(EVENT_CAPACITY),
+            stopped,
+            store: Arc::new(RwLock::new(store)),
+            peers: Arc::new(RwLock::new(PeerTable::new())),
+        }
+    }
+
+    // signs an empty block on top of the head and
+    // applies it, no events go out
+    #[cfg(test)]
+    pub(crate) fn extend_with(&self, validator: &Account) -> Block {
+        let mut store = self.store.write().unwrap();
+        let ChainStore { chain, state, .. } = &mut *store;
+        let head = chain.head().cloned().unwrap();
+        let blk = Block::next(head, validator, &mut vec![], &self.spec).unwrap();
+        chain.extend(blk.clone(), state, &self.spec, Timestamp::now()).unwrap();
+        blk
+    }
+}
+
+// keeps the index in step with the chain, falling
+// behind the bus means catching up from the chain
+async fn index_chain(store: Arc<RwLock<ChainStore>>, mut events: Subscription, mut stopped: watch::Receiver<bool>) {
+    loop {
+        let event = select! {
+            event = events.recv() => event,
+            _ = stopped.wait_for(|x| *x) => break,
+        };
+        let mut store = store.write().unwrap();
+        let result = match event {
+            Ok(event) => store.index.handle(&event),
+            Err(RecvError::Lagged(n)) => Err(anyhow!("missed {} chain events", n)),
+            Err(RecvError::Closed) => break,
+        };
+        if let Err(e) = result {
+            info!("index out of step, catching up: {}", e);
+            let store = &mut *store;
+            let index = std::mem::take(&mut store.index);
+            match index.catch_up(&store.chain) {
+                Ok(index) => store.index = index,
+                Err(e) => info!("cannot index the chain: {}", e),
+            }
+        }
+    }
+}
+
+/// Gossip that passed the cheap checks on the swarm
+/// task and waits in the queue for chain processing
+struct ChainWork {
+    peer: PeerId,
+    id: gossipsub::MessageId,
+    msg: NetMessage,
+    // network time when the message arrived
+    now: Timestamp,
+}
+
+// a tx pushed out of a full mempool for another
+fn evicted(tx: Transaction) -> NodeEvent {
+    NodeEvent::TransactionDropped {
+        id: tx.tx_header.transaction_id,
+        reason: "evicted from a full mempool".to_string(),
+    }
+}
+
+/// What chain processing made of a message
+struct ChainVerdict {
+    peer: PeerId,
+    id: gossipsub::MessageId,
+    acceptance: gossipsub::MessageAcceptance,
+    report: Option<PeerEvent>,
+}
+
+/// What became of a block that checked out on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockOutcome {
+    // it went on top of our head
+    Applied,
+    // its branch outgrew ours and the head moved to it
+    Reorged,
+    // it leads back to our chain but its branch isn't
+    // the longest, kept in case that changes
+    Stored,
+    // seen before or its parent never arrived, nothing
+    // to judge it by
+    NotApplied,
+}
+
+// the slow part of handling gossip, runs on its own
+// thread so the swarm keeps polling meanwhile
+#[derive(Clone)]
+struct ChainWorker {
+    spec: ChainSpec,
+    chain_path: PathBuf,
+    store: Arc<RwLock<ChainStore>>,
+    events: EventBus,
+}
+
+impl ChainWorker {
+    // a block on top of our head goes onto the chain and
+    // to disk before the lock is let go, so shutdown never
+    // sees a block that is applied but not saved, anything
+    // else goes to the side blocks and the head follows
+    // the longest branch
+    fn apply_block(&self, blk: &Block, now: Timestamp) -> Result<BlockOutcome> {
+        let mut store = self.store.write().unwrap();
+        let ChainStore { chain, state, side, settled, .. } = &mut *store;
+        if chain.block_by_hash(&blk.block_hash).is_some() || side.contains(&blk.block_hash) {
+            return Ok(BlockOutcome::NotApplied);
+        }
+        if chain.head().map(|x| x.block_hash == blk.prev_hash).unwrap_or(false) {
+            chain.extend(blk.clone(), state, &self.spec, now)?;
+            self.moved_head(&mut store, vec![blk.clone()], vec![]);
+            return Ok(BlockOutcome::Applied);
+        }
+        side.insert(blk.clone());
+        if let Some(branch) = chain.fork_choice(side, &self.spec) {
+            let carries_blk = branch.iter().any(|x| x.block_hash == blk.block_hash);
+            match chain.switch_to(branch.clone(), settled, &self.spec, now) {
+                Ok((next, reverted)) => {
+                    *state = next;
+                    self.moved_head(&mut store, branch, reverted);
+                    return Ok(BlockOutcome::Reorged);
+                }
+                Err(e) => {
+                    for x in &branch {
+                        side.remove(&x.block_hash);
+                    }
+                    if carries_blk {
+                        return Err(e);
+                    }
+                    info!("dropped a side branch: {}", e);
+                }
+            }
+        }
+        match chain.branch(blk, side) {
+            Some(_) => Ok(BlockOutcome::Stored),
+            None => Ok(BlockOutcome::NotApplied),
+        }
+    }
+
+    // after `applied` went on from the fork point up, and
+    // `reverted` came off from the top down, txs of the
+    // old branch the new one lacks are queued again
+    fn moved_head(&self, store: &mut ChainStore, applied: Vec<Block>, reverted: Vec<Block>) {
+        let ChainStore { chain, state, mempool, side, settled, .. } = store;
+        for blk in &applied {
+            side.remove(&blk.block_hash);
+        }
+        if let Err(e) = settled.advance(chain, &self.spec) {
+            println!("cannot settle final blocks: {e}");
+        }
+        let dropped = mempool.prune(state);
+        let mut requeued = vec![];
+        for blk in &reverted {
+            for (_, tx) in blockchain::transfers(blk) {
+                if mempool.admit(state, tx.clone()).is_ok() {
+                    requeued.push(tx);
+                }
+            }
+            side.insert(blk.clone());
+        }
+        if let Err(e) = chain.save(&self.chain_path) {
+            println!("cannot save chain: {e}");
+        }
+        // txs the blocks carried are in the chain events already
+        let included: Vec<String> = applied
+            .iter()
+            .flat_map(blockchain::transfers)
+            .map(|(_, tx)| tx.tx_header.transaction_id)
+            .collect();
+        let applied: Vec<Arc<Block>> = applied.into_iter().map(Arc::new).collect();
+        match reverted.is_empty() {
+            true => {
+                for blk in applied {
+                    self.events.publish(NodeEvent::BlockApplied(blk));
+                }
+            }
+            false => {
+                info!("head moved to another branch, {} blocks reverted", reverted.len());
+                let reverted = reverted.into_iter().map(Arc::new).collect();
+                self.events.publish(NodeEvent::Reorg { reverted, applied });
+            }
+        }
+        for tx in requeued {
+            self.events.publish(NodeEvent::TransactionAccepted(Arc::new(tx)));
+        }
+        for (tx, reason) in dropped {
+            if !included.contains(&tx.tx_header.transaction_id) {
+                self.events.publish(NodeEvent::TransactionDropped {
+                    id: tx.tx_header.transaction_id,
+                    reason,
+                });
+            }
+        }
+    }
+
+    fn process(&self, work: ChainWork) -> ChainVerdict {
+        let ChainWork { peer, id, msg, now } = work;
+        let (acceptance, report) = match msg {
+            // only ever shown, text from a peer never runs anything
+            NetMessage::Text(text) => {
+                println!("Got message {:?} with id: {id} from peer: {peer}", text);
+                self.events.publish(NodeEvent::Message { peer, text });
+                (gossipsub::MessageAcceptance::Accept, None)
+            }
+            NetMessage::Block(gblock) => {
+                match timestamp::check_future_drift(&gblock, now, &self.spec)
+                    .and_then(|_| gblock.verify_signature())
+                {
+                    Ok(()) => {
+                        println!("🧱 Received block: {} at height {}", gblock.block_hash, gblock.height);
+                        self.events.publish(NodeEvent::BlockReceived {
+                            peer,
+                            hash: gblock.block_hash.clone(),
+                            height: gblock.height,
+                        });
+                        match self.apply_block(&gblock, now) {
+                            Ok(BlockOutcome::Applied | BlockOutcome::Reorged) => {
+                                (gossipsub::MessageAcceptance::Accept, Some(PeerEvent::UsefulBlock))
+                            }
+                            // others on our chain need it for their
+                            // fork choice, but it didn't help us yet
+                            Ok(BlockOutcome::Stored) => (gossipsub::MessageAcceptance::Accept, None),
+                            // old news or from a chain we don't follow,
+                            // no reason to pass it on or hold it against
+                            // the peer
+                            Ok(BlockOutcome::NotApplied) => (gossipsub::MessageAcceptance::Ignore, None),
+                            Err(e) => {
+                                println!("rejected block from peer {peer}: {e}");
+                                (gossipsub::MessageAcceptance::Reject, Some(PeerEvent::InvalidBlock))
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        println!("rejected block from peer {peer}: {e}");
+                        (gossipsub::MessageAcceptance::Reject, Some(PeerEvent::InvalidBlock))
+                    }
+                }
+            }
+            NetMessage::Transaction(tx) => {
+                let tx_id = tx.tx_header.transaction_id.clone();
+                let tx = Arc::new(*tx);
+                let admitted = {
+                    let mut store = self.store.write().unwrap();
+                    let ChainStore { chain, state, mempool, .. } = &mut *store;
+                    // checked against the sender's account, a
+                    // multisig is signed by its members
+                    if let Err(e) = state.verify_transaction(&tx) {
+                        println!("rejected tx {} from peer {peer}: {e}", tx_id);
+                        return ChainVerdict {
+                            peer,
+                            id,
+                            acceptance: gossipsub::MessageAcceptance::Reject,
+                            report: Some(PeerEvent::InvalidTransaction),
+                        };
+                    }
+                    let limit = self.spec.max_block_weight(chain.chain.len() as u64);
+                    weight::check_tx_weight(&tx, limit).and_then(|_| mempool.admit(state, (*tx).clone()))
+                };
+                match admitted {
+                    Ok(old) => {
+                        if let Some(old) = old {
+                            self.events.publish(evicted(old));
+                        }
+                        println!("Got tx {} from peer: {peer}", tx_id);
+                        self.events.publish(NodeEvent::TransactionAccepted(tx));
+                        (gossipsub::MessageAcceptance::Accept, None)
+                    }
+                    // signed but stale or a repeat, not the
+                    // sender's fault and not worth forwarding
+                    Err(e) => {
+                        info!("ignoring tx {} from peer {}: {}", tx_id, peer, e);
+                        (gossipsub::MessageAcceptance::Ignore, None)
+                    }
+                }
+            }
+            // handled on the swarm task, never queued
+            NetMessage::Clock(_) | NetMessage::Status { .. } => {
+                (gossipsub::MessageAcceptance::Accept, None)
+            }
+        };
+        ChainVerdict {
+            peer,
+            id,
+            acceptance,
+            report,
+        }
+    }
+}
+
+/// Seals the mempool of an authority into blocks
+#[derive(Clone)]
+struct BlockProducer {
+    worker: ChainWorker,
+    validator: Arc<Mutex<Account>>,
+}
+
+impl BlockProducer {
+    // the block goes on the head and to disk under the
+    // same lock as one from a peer, see apply_block, an
+    // empty mempool makes no block
+    fn produce(&self, now: Timestamp) -> Result<Option<Block>> {
+        let spec = &self.worker.spec;
+        let mut store = self.worker.store.write().unwrap();
+        let ChainStore { chain, state, mempool, .. } = &mut *store;
+        if mempool.transactions.is_empty() {
+            return Ok(None);
+        }
+        let head = chain.head().cloned().ok_or_else(|| anyhow!("no chain to build on"))?;
+        // queued in nonce order, whatever doesn't fit
+        // waits for the next block
+        let mut pending: Vec<BlockEntry> = mempool
+            .transactions
+            .iter()
+            .map(|tx| BlockEntry::Transfer(Box::new(tx.clone())))
+            .collect();
+        let mut validator = self.validator.lock().unwrap();
+        validator.acc_balance = state.balance(&validator.address());
+        let blk = Block::next(head, &validator, &mut pending, spec)?;
+        chain.extend(blk.clone(), state, spec, now)?;
+        self.worker.moved_head(&mut store, vec![blk.clone()], vec![]);
+        Ok(Some(blk))
+    }
+}
+
+// owns the swarm, everything else reaches it through
+// commands or the shared store
+struct NodeRunner {
+    config: NodeConfig,
+    spec: ChainSpec,
+    swarm: Swarm<EssexBehaviour>,
+    topic: gossipsub::IdentTopic,
+    kad_proto: StreamProtocol,
+    local_protocol: NodeProtocol,
+    reputation: Reputation,
+    bans_path: PathBuf,
+    rate_limiter: RateLimiter,
+    // peers share their clocks so blocks are judged
+    // against network time rather than our own clock
+    clock: NetworkClock,
+    random_walk: Duration,
+    // set on an authority only
+    producer: Option<BlockProducer>,
+    block_interval: Duration,
+    store: Arc<RwLock<ChainStore>>,
+    peers: Arc<RwLock<PeerTable>>,
+    events: EventBus,
+    // gone once shutdown starts
+    work_tx: Option<mpsc::Sender<ChainWork>>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl NodeRunner {
+    async fn run(
+        mut self,
+        mut commands: mpsc::Receiver<NodeCommand>,
+        mut verdicts: mpsc::Receiver<ChainVerdict>,
+        worker: JoinHandle<()>,
+    ) {
+        let mut ban_tick = tokio::time::interval(Duration::from_secs(60));
+        let mut clock_tick = tokio::time::interval(Duration::from_secs(60));
+        // walks towards random ids keep the routing
+        // table filled as peers come and go
+        let mut walk_tick = tokio::time::interval(self.random_walk);
+        let mut block_tick = tokio::time::interval(self.block_interval);
+        loop {
+            select! {
+                command = commands.recv() => match command {
+                    Some(NodeCommand::Publish(msg, reply)) => {
+                        let _ = reply.send(self.publish(&msg));
+                    }
+                    Some(NodeCommand::Dial(addr, reply)) => {
+                        let _ = reply.send(self.swarm.dial(addr).map_err(Into::into));
+                    }
+                    // every handle is gone or one asked us to stop
+                    Some(NodeCommand::Shutdown) | None => break,
+                },
+                Some(verdict) = verdicts.recv() => {
+                    self.settle_message(verdict);
+                }
+                _ = ban_tick.tick() => {
+                    let now = Timestamp::now();
+                    for peer in self.reputation.expire(now) {
+                        dynamic::unban_peer(&mut self.swarm, &peer);
+                    }
+                    if let Err(e) = self.reputation.save(&self.bans_path) {
+                        println!("cannot save ban list: {e}");
+                    }
+                }
+                _ = block_tick.tick(), if self.producer.is_some() => self.produce().await,
+                _ = walk_tick.tick() => {
+                    self.swarm.behaviour_mut().kad.get_closest_peers(PeerId::random());
+                }
+                _ = clock_tick.tick() => {
+                    let _ = self.publish(&NetMessage::Clock(Timestamp::now()));
+                    let head = self.store.read().unwrap().chain.head().map(|x| NetMessage::Status {
+                        best_height: x.height,
+                        best_hash: x.block_hash.clone(),
+                    });
+                    if let Some(status) = head {
+                        let _ = self.publish(&status);
+                    }
+                }
+                event = self.swarm.select_next_some() => self.on_swarm_event(event),
+            }
+        }
+        self.shutdown(worker).await;
+        println!("node {} stopped", self.swarm.local_peer_id());
+    }
+
+    // stops taking gossip, lets the chain worker finish
+    // the block it is on, flushes everything to disk and
+    // closes connections, giving up at the deadline
+    async fn shutdown(&mut self, worker: JoinHandle<()>) {
+        let deadline = tokio::time::Instant::now()
+            + Duration::from_secs(self.config.shutdown_deadline_secs);
+        println!("shutting down within {}s", self.config.shutdown_deadline_secs);
+        let _ = self.swarm.behaviour_mut().gossipsub.unsubscribe(&self.topic);
+        self.stopping.store(true, Ordering::Release);
+        self.work_tx = None;
+        if timeout_at(deadline, worker).await.is_err() {
+            println!("chain worker still busy at the deadline");
+        }
+        let store = self.store.clone();
+        let (chain_path, mempool_path) = (self.config.chain_path(), self.config.mempool_path());
+        let index_path = self.config.index_path();
+        let flush = tokio::task::spawn_blocking(move || -> Result<()> {
+            let store = store.read().unwrap();
+            store.chain.save(chain_path)?;
+            store.mempool.save(mempool_path)?;
+            // an index behind the chain catches up on start
+            store.index.save(index_path)
+        });
+        match timeout_at(deadline, flush).await {
+            Ok(Ok(Ok(()))) => {}
+            Ok(Ok(Err(e))) => println!("cannot flush chain store: {e}"),
+            Ok(Err(e)) => println!("cannot flush chain store: {e}"),
+            Err(_) => println!("chain store not flushed by the deadline"),
+        }
+        let connected: Vec<PeerId> = self.swarm.connected_peers().cloned().collect();
+        for peer in connected {
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+        while self.swarm.connected_peers().next().is_some() {
+            match timeout_at(deadline, self.swarm.select_next_some()).await {
+                Ok(event) => self.on_swarm_event(event),
+                Err(_) => {
+                    println!("peers still connected at the deadline");
+                    break;
+                }
+            }
+        }
+        if let Err(e) = self.peers.read().unwrap().save(self.config.peers_path()) {
+            println!("cannot save peer table: {e}");
+        }
+        if let Err(e) = self.reputation.save(&self.bans_path) {
+            println!("cannot save ban list: {e}");
+        }
+    }
+
+    // seals what is queued into a block on our head and
+    // gossips it, off the swarm task like gossip blocks
+    async fn produce(&mut self) {
+        let Some(producer) = self.producer.clone() else {
+            return;
+        };
+        let now = self.clock.now();
+        let blk = match tokio::task::spawn_blocking(move || producer.produce(now)).await {
+            Ok(Ok(Some(blk))) => blk,
+            Ok(Ok(None)) => return,
+            Ok(Err(e)) => {
+                println!("cannot produce a block: {e}");
+                return;
+            }
+            Err(e) => {
+                println!("cannot produce a block: {e}");
+                return;
+            }
+        };
+        println!("⛏️  Produced block: {} at height {}", blk.block_hash, blk.height);
+        if let Err(e) = self.publish(&NetMessage::Block(Box::new(blk))) {
+            info!("block not gossiped yet: {}", e);
+        }
+    }
+
+    fn publish(&mut self, msg: &NetMessage) -> Result<()> {
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(self.topic.clone(), codec::to_bytes(msg))?;
+        Ok(())
+    }
+
+    fn report_peer(&mut self, peer: &PeerId, event: PeerEvent) {
+        let verdict =
+            dynamic::report_peer(&mut self.swarm, &mut self.reputation, peer, event, &self.bans_path);
+        if let Verdict::Ban(until) = verdict {
+            self.events.publish(NodeEvent::PeerBanned { peer: *peer, until });
+        }
+    }
+
+    // tells gossipsub whether to forward a message and
+    // scores the peer that sent it
+    fn settle_message(&mut self, verdict: ChainVerdict) {
+        let _ = self.swarm.behaviour_mut().gossipsub.report_message_validation_result(
+            &verdict.id,
+            &verdict.peer,
+            verdict.acceptance,
+        );
+        if let Some(event) = verdict.report {
+            self.report_peer(&verdict.peer, event);
+        }
+    }
+
+    fn on_gossip(&mut self, peer_id: PeerId, id: gossipsub::MessageId, message: gossipsub::Message) {
+        // the budget is charged before any decoding, a peer
+        // over it is held to account and nothing is forwarded
+        if let Some(kind) = MsgKind::peek(&message.data) {
+            if !self.rate_limiter.allow(&peer_id, kind, Instant::now()) {
+                info!("peer {} is over its {:?} rate", peer_id, kind);
+                self.settle_message(ChainVerdict {
+                    peer: peer_id,
+                    id,
+                    acceptance: gossipsub::MessageAcceptance::Ignore,
+                    report: Some(PeerEvent::Spam),
+                });
+                return;
+            }
+        }
+        let settled = match dynamic::decode_gossip_message(&message.data, &self.spec) {
+            Err(e) => {
+                println!("rejected message {id} from peer {peer_id}: {e}");
+                Some((gossipsub::MessageAcceptance::Reject, Some(PeerEvent::Spam)))
+            }
+            Ok(NetMessage::Clock(peer_time)) => {
+                // one sample per publisher and never relayed, a
+                // relayed clock is stale and would let one peer
+                // speak for all of its neighbours
+                let origin = message.source.unwrap_or(peer_id);
+                self.clock.add_sample(&origin.to_string(), peer_time);
+                Some((gossipsub::MessageAcceptance::Ignore, None))
+            }
+            Ok(NetMessage::Status { best_height, best_hash }) => {
+                // relayed statuses belong to whoever signed them
+                let origin = message.source.unwrap_or(peer_id);
+                self.peers.write().unwrap().set_best(&origin, best_height, &best_hash);
+                Some((gossipsub::MessageAcceptance::Accept, None))
+            }
+            Ok(msg) => match &self.work_tx {
+                Some(work_tx) => {
+                    let work = ChainWork {
+                        peer: peer_id,
+                        id: id.clone(),
+                        msg,
+                        now: self.clock.now(),
+                    };
+                    match work_tx.try_send(work) {
+                        Ok(()) => None,
+                        // backpressure, the sender did nothing wrong
+                        Err(_) => {
+                            println!("chain queue full, dropping message {id}");
+                            Some((gossipsub::MessageAcceptance::Ignore, None))
+                        }
+                    }
+                }
+                // shutting down, nothing new gets processed
+                None => Some((gossipsub::MessageAcceptance::Ignore, None)),
+            },
+        };
+        if let Some((acceptance, report)) = settled {
+            self.settle_message(ChainVerdict {
+                peer: peer_id,
+                id,
+                acceptance,
+                report,
+            });
+        }
+    }
+
+    fn on_swarm_event(&mut self, event: SwarmEvent<EssexBehaviourEvent>) {
+        match event {
+            SwarmEvent::Behaviour(EssexBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+                for (peer_id, multiaddr) in list {
+                    println!("discovered peer {peer_id}");
+                    self.peers.write().unwrap().add_address(&peer_id, multiaddr);
+                    self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                    self.events.publish(NodeEvent::PeerDiscovered(peer_id));
+                }
+            }
+            SwarmEvent::Behaviour(EssexBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
+                for (peer_id, _multiaddr) in list {
+                    println!("mdns discover expired: {peer_id}");
+                    self.clock.remove_peer(&peer_id.to_string());
+                    self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                }
+            }
+            SwarmEvent::Behaviour(EssexBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
+                message,
+            })) => self.on_gossip(propagation_source, message_id, message),
+            SwarmEvent::Behaviour(EssexBehaviourEvent::Identify(identify::Event::Received { peer_id, info })) => {
+                let checked = self.peers.write().unwrap().identified(
+                    &peer_id,
+                    &info.agent_version,
+                    &info.protocol_version,
+                    &info.listen_addrs,
+                    &self.local_protocol,
+                );
+                match checked {
+                    Err(e) => {
+                        // wrong chain or protocol, nothing to talk about
+                        println!("disconnecting peer {peer_id}: {e}");
+                        self.swarm.behaviour_mut().kad.remove_peer(&peer_id);
+                        let _ = self.swarm.disconnect_peer_id(peer_id);
+                    }
+                    // only peers speaking our dht go into the table
+                    Ok(()) if info.protocols.contains(&self.kad_proto) => {
+                        for addr in info.listen_addrs {
+                            self.swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+                        }
+                    }
+                    Ok(()) => {}
+                }
+            }
+            SwarmEvent::Behaviour(EssexBehaviourEvent::Ping(ping::Event { peer, result: Ok(rtt), .. })) => {
+                self.peers.write().unwrap().set_latency(&peer, rtt);
+            }
+            SwarmEvent::Behaviour(EssexBehaviourEvent::Ping(ping::Event {
+                peer,
+                result: Err(ping::Failure::Timeout),
+                ..
+            })) => self.report_peer(&peer, PeerEvent::Timeout),
+            SwarmEvent::Behaviour(EssexBehaviourEvent::Kad(kad::Event::RoutingUpdated {
+                peer,
+                is_new_peer: true,
+                ..
+            })) if !self.swarm.is_connected(&peer) => {
+                println!("discovered peer {peer} through the dht");
+                self.events.publish(NodeEvent::PeerDiscovered(peer));
+                let _ = self.swarm.dial(peer);
+            }
+            SwarmEvent::Behaviour(EssexBehaviourEvent::Kad(kad::Event::OutboundQueryProgressed {
+                result: kad::QueryResult::GetClosestPeers(Ok(ok)),
+                ..
+            })) => {
+                for peer in ok.peers {
+                    if peer != *self.swarm.local_peer_id() && !self.swarm.is_connected(&peer) {
+                        let _ = self.swarm.dial(peer);
+                    }
+                }
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                num_established,
+                endpoint,
+                ..
+            } if num_established.get() == 1 => {
+                println!("connected to peer {peer_id}");
+                let mut peers = self.peers.write().unwrap();
+                if endpoint.is_dialer() {
+                    peers.add_address(&peer_id, endpoint.get_remote_address().clone());
+                }
+                peers.set_connected(&peer_id, true);
+                self.events.publish(NodeEvent::PeerConnected(peer_id));
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                self.peers.write().unwrap().set_connected(&peer_id, false);
+                self.rate_limiter.remove_peer(&peer_id);
+                self.events.publish(NodeEvent::PeerDisconnected(peer_id));
+            }
+            SwarmEvent::NewListenAddr { address, .. } => {
+                println!("🌈 Node Listener: {}", address);
+                self.events.publish(NodeEvent::Listening(address));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gossipsub::MessageAcceptance;
+
+    fn worker() -> ChainWorker {
+        worker_with(ChainSpec::default())
+    }
+
+    fn worker_with(spec: ChainSpec) -> ChainWorker {
+        let chain = Blockchain::genesis(&spec);
+        let state = chain.verify(&spec).unwrap();
+        let dir = std::env::temp_dir().join(format!("essex-worker-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = ChainStore {
+            chain,
+            state,
+            mempool: TransactionPool::default(),
+            index: Indexer::default(),
+            side: SideBlocks::default(),
+            settled: Checkpoint::genesis(&spec).unwrap(),
+        };
+        ChainWorker {
+            spec,
+            chain_path: dir.join("blockchain.json"),
+            store: Arc::new(RwLock::new(store)),
+            events: EventBus::new(64),
+        }
+    }
+
+    fn deliver(worker: &ChainWorker, blk: &Block) -> ChainVerdict {
+        worker.process(ChainWork {
+            peer: PeerId::random(),
+            id: gossipsub::MessageId::new(blk.block_hash.as_bytes()),
+            msg: NetMessage::Block(Box::new(blk.clone())),
+            now: Timestamp::now(),
+        })
+    }
+
+    fn head(worker: &ChainWorker) -> Block {
+        worker.store.read().unwrap().chain.head().cloned().unwrap()
+    }
+
+    fn cleanup(worker: &ChainWorker) {
+        let _ = std::fs::remove_dir_all(worker.chain_path.parent().unwrap());
+    }
+
+    #[test]
+    fn text_from_a_peer_runs_nothing() {
+        let worker = worker();
+        let mut events = worker.events.subscribe(EventFilter::all());
+        let had_block_file = std::path::Path::new("block.txt").exists();
+        let verdict = worker.process(ChainWork {
+            peer: PeerId::random(),
+            id: gossipsub::MessageId::new(b"text"),
+            msg: NetMessage::Text("createchain".to_string()),
+            now: Timestamp::now(),
+        });
+        assert!(matches!(verdict.acceptance, MessageAcceptance::Accept));
+        assert!(matches!(events.blocking_recv(), Ok(NodeEvent::Message { .. })));
+        assert_eq!(worker.store.read().unwrap().chain.chain.len(), 1);
+        assert_eq!(std::path::Path::new("block.txt").exists(), had_block_file);
+        cleanup(&worker);
+    }
+
+    #[test]
+    fn a_block_on_the_head_is_applied_and_rewarded() {
+        let worker = worker();
+        let validator = Account::create().unwrap();
+        let blk = Block::next(head(&worker), &validator, &mut vec![], &worker.spec).unwrap();
+        let verdict = deliver(&worker, &blk);
+        assert!(matches!(verdict.acceptance, MessageAcceptance::Accept));
+        assert_eq!(verdict.report, Some(PeerEvent::UsefulBlock));
+        assert_eq!(head(&worker).block_hash, blk.block_hash);
+        assert!(worker.chain_path.exists());
+        cleanup(&worker);
+    }
+
+    #[test]
+    fn a_block_we_cannot_place_is_ignored_without_a_report() {
+        let worker = worker();
+        let validator = Account::create().unwrap();
+        let first = Block::next(head(&worker), &validator, &mut vec![], &worker.spec).unwrap();
+        let second = Block::next(first.clone(), &validator, &mut vec![], &worker.spec).unwrap();
+        // ahead of our head, its parent never arrived
+        let verdict = deliver(&worker, &second);
+        assert!(matches!(verdict.acceptance, MessageAcceptance::Ignore));
+        assert_eq!(verdict.report, None);
+        deliver(&worker, &first);
+        // a repeat of what we already have
+        let verdict = deliver(&worker, &first);
+        assert!(matches!(verdict.acceptance, MessageAcceptance::Ignore));
+        assert_eq!(verdict.report, None);
+        assert_eq!(head(&worker).block_hash, first.block_hash);
+        cleanup(&worker);
+    }
+
+    #[test]
+    fn a_forged_block_is_rejected() {
+        let worker = worker();
+        let validator = Account::create().unwrap();
+        let mut blk = Block::next(head(&worker), &validator, &mut vec![], &worker.spec).unwrap();
+        blk.block_data.push(BlockEntry::record("slipped in"));
+        let verdict = deliver(&worker, &blk);
+        assert!(matches!(verdict.acceptance, MessageAcceptance::Reject));
+        assert_eq!(verdict.report, Some(PeerEvent::InvalidBlock));
+        cleanup(&worker);
+    }
+
+    #[test]
+    fn an_authority_seals_what_is_queued() {
+        let alice = Account::create().unwrap();
+        let mut spec = ChainSpec::default();
+        spec.allocations.insert(alice.address(), 100);
+        let worker = worker_with(spec);
+        let producer = BlockProducer {
+            worker: worker.clone(),
+            validator: Arc::new(Mutex::new(Account::create().unwrap())),
+        };
+        assert!(producer.produce(Timestamp::now()).unwrap().is_none());
+        let bob = Account::create().unwrap();
+        let tx = Transaction::new(&alice, bob.address(), head(&worker).block_hash, 7, 0).unwrap();
+        {
+            let mut store = worker.store.write().unwrap();
+            let ChainStore { state, mempool, .. } = &mut *store;
+            mempool.admit(state, tx.clone()).unwrap();
+        }
+        let blk = producer.produce(Timestamp::now()).unwrap().unwrap();
+        assert_eq!(head(&worker).block_hash, blk.block_hash);
+        let id = &tx.tx_header.transaction_id;
+        assert!(matches!(
+            &blk.block_data[..],
+            [BlockEntry::Transfer(x)] if &x.tx_header.transaction_id == id
+        ));
+        let store = worker.store.read().unwrap();
+        assert!(store.mempool.transactions.is_empty());
+        assert_eq!(store.state.balance(&bob.address()), 7);
+        drop(store);
+        assert!(worker.chain_path.exists());
+        cleanup(&worker);
+    }
+
+    #[tokio::test]
+    async fn an_authority_does_not_start_without_its_key() {
+        let config = NodeConfig {
+            role: NodeRole::Authority,
+            ..NodeConfig::default()
+        };
+        let err = Node::new(config, ChainSpec::default()).unwrap().start().await.err().unwrap();
+        assert_eq!(err.to_string(), "an authority needs its validator key to produce blocks");
+    }
+
+    #[test]
+    fn a_longer_branch_takes_over_the_head() {
+        let alice = Account::create().unwrap();
+        let mut spec = ChainSpec::default();
+        spec.allocations.insert(alice.address(), 100);
+        let worker = worker_with(spec);
+        let mut events = worker.events.subscribe(EventFilter::chain());
+        let (ours, theirs) = (Account::create().unwrap(), Account::create().unwrap());
+        let genesis = head(&worker);
+        let tx = Transaction::new(&alice, theirs.address(), genesis.block_hash.clone(), 7, 0).unwrap();
+        let mut pending = vec![BlockEntry::Transfer(Box::new(tx.clone()))];
+        let a1 = Block::next(genesis.clone(), &ours, &mut pending, &worker.spec).unwrap();
+        let b1 = Block::next(genesis, &theirs, &mut vec![], &worker.spec).unwrap();
+        let b2 = Block::next(b1.clone(), &theirs, &mut vec![], &worker.spec).unwrap();
+
+        deliver(&worker, &a1);
+        assert!(matches!(events.blocking_recv(), Ok(NodeEvent::BlockApplied(x)) if x.block_hash == a1.block_hash));
+        // as long as ours, kept and passed on but no reward
+        let verdict = deliver(&worker, &b1);
+        assert!(matches!(verdict.acceptance, MessageAcceptance::Accept));
+        assert_eq!(verdict.report, None);
+        assert_eq!(head(&worker).block_hash, a1.block_hash);
+
+        let verdict = deliver(&worker, &b2);
+        assert_eq!(verdict.report, Some(PeerEvent::UsefulBlock));
+        assert_eq!(head(&worker).block_hash, b2.block_hash);
+        match events.blocking_recv() {
+            Ok(NodeEvent::Reorg { reverted, applied }) => {
+                let reverted: Vec<_> = reverted.iter().map(|x| x.block_hash.clone()).collect();
+                let applied: Vec<_> = applied.iter().map(|x| x.block_hash.clone()).collect();
+                assert_eq!(reverted, vec![a1.block_hash.clone()]);
+                assert_eq!(applied, vec![b1.block_hash.clone(), b2.block_hash.clone()]);
+            }
+            other => panic!("expected a reorg, got {:?}", other),
+        }
+        // the transfer only the old branch carried is pending again
+        let store = worker.store.read().unwrap();
+        assert_eq!(store.state.balance(&alice.address()), 100);
+        assert!(store.mempool.get(&tx.tx_header.transaction_id).is_some());
+        assert!(store.side.contains(&a1.block_hash));
+        drop(store);
+        cleanup(&worker);
+    }
+}
@@ -0,0 +1,157 @@
+use anyhow::{bail, Result};
+use rand::{rngs::OsRng, RngCore};
+use secp256k1::hashes::{sha256, Hash, HashEngine};
+use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    account::account::Account,
+    blockchain::blockchain::Blockchain,
+    sec8::sec8::{self, Sec8Envelope},
+    transaction::transaction::BlockEntry,
+};
+
+const COMMIT_DOMAIN: &[u8] = b"essex-private-commit";
+const SALT_LEN: usize = 32;
+
+/// Confidential block_data record, everyone sees the
+/// commitment but only the listed recipients can open
+/// the envelope and check it against the commitment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrivatePayload {
+    pub commitment: String,
+    pub envelope: Sec8Envelope,
+}
+
+/// A payload a recipient managed to decrypt
+#[derive(Debug, Clone)]
+pub struct OpenedPayload {
+    pub height: u64,
+    pub block_hash: String,
+    pub commitment: String,
+    pub data: Vec<u8>,
+}
+
+// salted so records with little entropy
+// can't be guessed from the commitment
+fn commit(salt: &[u8], data: &[u8]) -> String {
+    let mut engine = sha256::Hash::engine();
+    engine.input(COMMIT_DOMAIN);
+    engine.input(salt);
+    engine.input(data);
+    sha256::Hash::from_engine(engine).to_string()
+}
+
+impl PrivatePayload {
+    pub fn seal(recipients: &[PublicKey], data: &[u8]) -> Result<PrivatePayload> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut framed = salt.to_vec();
+        framed.extend_from_slice(data);
+        let envelope = sec8::sec8_encrypt_block_data(recipients, &framed)?;
+        Ok(PrivatePayload {
+            commitment: commit(&salt, data),
+            envelope,
+        })
+    }
+
+    // block_data entry ready to queue for a block
+    pub fn seal_entry(recipients: &[PublicKey], data: &[u8]) -> Result<BlockEntry> {
+        Ok(BlockEntry::Private(PrivatePayload::seal(recipients, data)?))
+    }
+
+    pub fn open(&self, acc: &Account) -> Result<Vec<u8>> {
+        let framed = sec8::sec8_decrypt_block_data(&acc.acc_private, &self.envelope)?;
+        if framed.len() < SALT_LEN {
+            bail!("private payload too short");
+        }
+        let (salt, data) = framed.split_at(SALT_LEN);
+        if commit(salt, data) != self.commitment {
+            bail!("private payload does not match its commitment");
+        }
+        Ok(data.to_vec())
+    }
+
+    pub fn is_for(&self, acc: &Account) -> bool {
+        let public = acc.acc_public.to_string();
+        self.envelope.recipients.iter().any(|x| x.public == public)
+    }
+}
+
+// walks the chain and opens every payload addressed to `acc`
+pub fn scan_private_payloads(chain: &Blockchain, acc: &Account) -> Vec<OpenedPayload> {
+    let mut opened = Vec::new();
+    for block in &chain.chain {
+        for entry in &block.block_data {
+            let BlockEntry::Private(payload) = entry else {
+                continue;
+            };
+            if !payload.is_for(acc) {
+                continue;
+            }
+            match payload.open(acc) {
+                Ok(data) => opened.push(OpenedPayload {
+                    height: block.height,
+                    block_hash: block.block_hash.clone(),
+                    commitment: payload.commitment.clone(),
+                    data,
+                }),
+                Err(e) => log::error!("cannot open payload {}: {}", payload.commitment, e),
+            }
+        }
+    }
+    opened
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::block::Block;
+
+    #[test]
+    fn only_recipients_open_a_payload() {
+        let (alice, eve) = (Account::create().unwrap(), Account::create().unwrap());
+        let payload = PrivatePayload::seal(&[alice.acc_public], b"invoice 42").unwrap();
+        assert!(payload.is_for(&alice));
+        assert!(!payload.is_for(&eve));
+        assert_eq!(payload.open(&alice).unwrap(), b"invoice 42");
+        assert!(payload.open(&eve).is_err());
+    }
+
+    #[test]
+    fn commitments_are_salted_and_checked_on_open() {
+        let alice = Account::create().unwrap();
+        let first = PrivatePayload::seal(&[alice.acc_public], b"yes").unwrap();
+        let second = PrivatePayload::seal(&[alice.acc_public], b"yes").unwrap();
+        assert_ne!(first.commitment, second.commitment);
+        let swapped = PrivatePayload {
+            commitment: second.commitment,
+            envelope: first.envelope,
+        };
+        assert!(swapped.open(&alice).is_err());
+    }
+
+    #[test]
+    fn scan_opens_what_is_addressed_to_the_account() {
+        let (alice, bob) = (Account::create().unwrap(), Account::create().unwrap());
+        let blk = Block {
+            height: 3,
+            block_data: vec![
+                PrivatePayload::seal_entry(&[alice.acc_public], b"for alice").unwrap(),
+                PrivatePayload::seal_entry(&[bob.acc_public], b"for bob").unwrap(),
+                BlockEntry::record("public"),
+            ],
+            ..Block::default()
+        };
+        let chain = Blockchain {
+            chain: vec![blk],
+            ..Blockchain::default()
+        };
+        let opened = scan_private_payloads(&chain, &alice);
+        assert_eq!(opened.len(), 1);
+        assert_eq!(
+            (opened[0].height, opened[0].data.as_slice()),
+            (3, &b"for alice"[..])
+        );
+    }
+}
@@ -0,0 +1,328 @@
+use anyhow::{bail, Result};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::storage::storage;
+use crate::timestamp::timestamp::Timestamp;
+
+pub const PROTOCOL_NAME: &str = "essex";
+pub const PROTOCOL_VERSION: &str = "1.0.0";
+// genesis of a node that has no chain yet
+pub const NO_GENESIS: &str = "none";
+
+/// What a node announces through identify's protocol
+/// version, `/essex/<version>/<chain id>/<genesis hash>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeProtocol {
+    pub version: String,
+    pub chain_id: String,
+    pub genesis_hash: String,
+}
+
+impl NodeProtocol {
+    pub fn new(chain_id: &str, genesis_hash: Option<&str>) -> Self {
+        NodeProtocol {
+            version: PROTOCOL_VERSION.to_string(),
+            chain_id: chain_id.to_string(),
+            genesis_hash: genesis_hash.unwrap_or(NO_GENESIS).to_string(),
+        }
+    }
+
+    fn major(&self) -> &str {
+        self.version.split('.').next().unwrap_or_default()
+    }
+
+    // same major version, same chain and the same
+    // genesis once both sides have one
+    pub fn check_compatible(&self, other: &NodeProtocol) -> Result<()> {
+        if self.major() != other.major() {
+            bail!("protocol version {} is incompatible with {}", other.version, self.version);
+        }
+        if self.chain_id != other.chain_id {
+            bail!("peer is on chain {}, not {}", other.chain_id, self.chain_id);
+        }
+        if self.genesis_hash != NO_GENESIS
+            && other.genesis_hash != NO_GENESIS
+            && self.genesis_hash != other.genesis_hash
+        {
+            bail!("peer has genesis {}, not {}", other.genesis_hash, self.genesis_hash);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for NodeProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "/{}/{}/{}/{}",
+            PROTOCOL_NAME, self.version, self.chain_id, self.genesis_hash
+        )
+    }
+}
+
+impl FromStr for NodeProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split('/').collect();
+        match parts.as_slice() {
+            ["", PROTOCOL_NAME, version, chain_id, genesis_hash]
+                if !version.is_empty() && !chain_id.is_empty() && !genesis_hash.is_empty() =>
+            {
+                Ok(NodeProtocol {
+                    version: version.to_string(),
+                    chain_id: chain_id.to_string(),
+                    genesis_hash: genesis_hash.to_string(),
+                })
+            }
+            _ => bail!("not an essex protocol version: {}", s),
+        }
+    }
+}
+
+/// Everything we have learnt about one peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub peer_id: PeerId,
+    pub addresses: Vec<Multiaddr>,
+    pub agent_version: Option<String>,
+    pub protocol_version: Option<String>,
+    pub chain_id: Option<String>,
+    pub genesis_hash: Option<String>,
+    // round trip of the last ping
+    pub latency_ms: Option<u64>,
+    pub best_height: Option<u64>,
+    pub best_hash: Option<String>,
+    pub connected: bool,
+    pub last_seen: Timestamp,
+}
+
+impl PeerInfo {
+    fn new(peer_id: PeerId) -> Self {
+        PeerInfo {
+            peer_id,
+            addresses: vec![],
+            agent_version: None,
+            protocol_version: None,
+            chain_id: None,
+            genesis_hash: None,
+            latency_ms: None,
+            best_height: None,
+            best_hash: None,
+            connected: false,
+            last_seen: Timestamp::now(),
+        }
+    }
+}
+
+fn or_unknown<T: fmt::Display>(v: &Option<T>) -> String {
+    match v {
+        Some(x) => x.to_string(),
+        None => "?".to_string(),
+    }
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} agent={} chain={} height={} latency={}ms",
+            self.peer_id,
+            if self.connected { "connected" } else { "known" },
+            or_unknown(&self.agent_version),
+            or_unknown(&self.chain_id),
+            or_unknown(&self.best_height),
+            or_unknown(&self.latency_ms),
+        )
+    }
+}
+
+/// Peers the node has seen, connected or not
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PeerTable {
+    pub peers: HashMap<PeerId, PeerInfo>,
+}
+
+impl PeerTable {
+    pub fn new() -> Self {
+        PeerTable::default()
+    }
+
+    fn entry(&mut self, peer: &PeerId) -> &mut PeerInfo {
+        let info = self
+            .peers
+            .entry(*peer)
+            .or_insert_with(|| PeerInfo::new(*peer));
+        info.last_seen = Timestamp::now();
+        info
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<&PeerInfo> {
+        self.peers.get(peer)
+    }
+
+    pub fn add_address(&mut self, peer: &PeerId, addr: Multiaddr) {
+        let info = self.entry(peer);
+        if !info.addresses.contains(&addr) {
+            info.addresses.push(addr);
+        }
+    }
+
+    pub fn set_connected(&mut self, peer: &PeerId, connected: bool) {
+        self.entry(peer).connected = connected;
+    }
+
+    // fills in identify, the announced protocol is
+    // parsed and checked against ours, an error means
+    // the peer should be dropped
+    pub fn identified(
+        &mut self,
+        peer: &PeerId,
+        agent_version: &str,
+        protocol_version: &str,
+        listen_addrs: &[Multiaddr],
+        local: &NodeProtocol,
+    ) -> Result<()> {
+        let info = self.entry(peer);
+        info.agent_version = Some(agent_version.to_string());
+        info.protocol_version = Some(protocol_version.to_string());
+        for addr in listen_addrs {
+            if !info.addresses.contains(addr) {
+                info.addresses.push(addr.clone());
+            }
+        }
+        let remote: NodeProtocol = protocol_version.parse()?;
+        info.chain_id = Some(remote.chain_id.clone());
+        info.genesis_hash = Some(remote.genesis_hash.clone());
+        local.check_compatible(&remote)
+    }
+
+    pub fn set_latency(&mut self, peer: &PeerId, rtt: Duration) {
+        self.entry(peer).latency_ms = Some(rtt.as_millis() as u64);
+    }
+
+    pub fn set_best(&mut self, peer: &PeerId, height: u64, hash: &str) {
+        let info = self.entry(peer);
+        info.best_height = Some(height);
+        info.best_hash = Some(hash.to_string());
+    }
+
+    pub fn connected(&self) -> impl Iterator<Item = &PeerInfo> {
+        self.peers.values().filter(|x| x.connected)
+    }
+
+    // highest block any connected peer claims
+    pub fn best_height(&self) -> Option<u64> {
+        self.connected().filter_map(|x| x.best_height).max()
+    }
+
+    // peers from the last run, none of them connected yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<PeerTable> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(PeerTable::default());
+        }
+        let raw = std::fs::read_to_string(path)?;
+        let mut table: PeerTable = serde_json::from_str(&raw)?;
+        for info in table.peers.values_mut() {
+            info.connected = false;
+        }
+        Ok(table)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        storage::write_atomic(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_versions_round_trip() {
+        let ours = NodeProtocol::new("test-net", Some("abcd"));
+        assert_eq!(ours.to_string(), "/essex/1.0.0/test-net/abcd");
+        assert_eq!(ours.to_string().parse::<NodeProtocol>().unwrap(), ours);
+        let bad_versions = [
+            "/ipfs/0.1.0",
+            "/essex/1.0.0/test-net",
+            "/essex//test-net/abcd",
+            "essex/1.0.0/a/b",
+        ];
+        for bad in bad_versions {
+            assert!(bad.parse::<NodeProtocol>().is_err(), "accepted {}", bad);
+        }
+    }
+
+    #[test]
+    fn compatible_peers_share_major_chain_and_genesis() {
+        let ours = NodeProtocol::new("test-net", Some("abcd"));
+        let mut minor = ours.clone();
+        minor.version = "1.4.2".to_string();
+        ours.check_compatible(&minor).unwrap();
+        ours.check_compatible(&NodeProtocol::new("test-net", None)).unwrap();
+        let mut major = ours.clone();
+        major.version = "2.0.0".to_string();
+        assert!(ours.check_compatible(&major).is_err());
+        assert!(ours.check_compatible(&NodeProtocol::new("main-net", Some("abcd"))).is_err());
+        assert!(ours.check_compatible(&NodeProtocol::new("test-net", Some("ef01"))).is_err());
+    }
+
+    #[test]
+    fn identify_fills_in_the_peer() {
+        let ours = NodeProtocol::new("test-net", Some("abcd"));
+        let mut table = PeerTable::new();
+        let (good, bad) = (PeerId::random(), PeerId::random());
+        let addr: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        table.set_connected(&good, true);
+        let announced = ours.to_string();
+        let addrs = [addr.clone(), addr.clone()];
+        table
+            .identified(&good, "essex-rs/0.1.0", &announced, &addrs, &ours)
+            .unwrap();
+        let info = table.get(&good).unwrap();
+        assert_eq!(info.addresses, vec![addr]);
+        assert_eq!(info.chain_id.as_deref(), Some("test-net"));
+        // what a wrong chain peer said is kept for the log
+        let theirs = NodeProtocol::new("main-net", None).to_string();
+        assert!(table.identified(&bad, "other/1.0", &theirs, &[], &ours).is_err());
+        assert_eq!(table.get(&bad).unwrap().chain_id.as_deref(), Some("main-net"));
+    }
+
+    #[test]
+    fn best_height_only_counts_connected_peers() {
+        let mut table = PeerTable::new();
+        let (near, gone) = (PeerId::random(), PeerId::random());
+        table.set_connected(&near, true);
+        table.set_best(&near, 10, "aa");
+        table.set_connected(&gone, true);
+        table.set_best(&gone, 50, "bb");
+        assert_eq!(table.best_height(), Some(50));
+        table.set_connected(&gone, false);
+        assert_eq!(table.best_height(), Some(10));
+    }
+
+    #[test]
+    fn a_saved_table_loads_back_disconnected() {
+        let name = format!("essex-peers-{:016x}.json", rand::random::<u64>());
+        let path = std::env::temp_dir().join(name);
+        assert!(PeerTable::load(&path).unwrap().peers.is_empty());
+        let mut table = PeerTable::new();
+        let peer = PeerId::random();
+        table.set_connected(&peer, true);
+        table.set_latency(&peer, Duration::from_millis(42));
+        table.save(&path).unwrap();
+        let loaded = PeerTable::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let info = loaded.get(&peer).unwrap();
+        assert!(!info.connected);
+        assert_eq!(info.latency_ms, Some(42));
+    }
+}
@@ -0,0 +1,283 @@
+use anyhow::Result;
+use libp2p::gossipsub::{self, PeerScoreParams, PeerScoreThresholds, TopicScoreParams};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::config::config::ReputationConfig;
+use crate::storage::storage;
+use crate::timestamp::timestamp::Timestamp;
+
+/// Things a peer does that change what we think of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerEvent {
+    InvalidBlock,
+    InvalidTransaction,
+    UsefulBlock,
+    Timeout,
+    // undecodable, oversized or otherwise junk messages
+    Spam,
+}
+
+impl PeerEvent {
+    pub fn score_delta(&self) -> f64 {
+        match self {
+            PeerEvent::InvalidBlock => -50.0,
+            PeerEvent::InvalidTransaction => -20.0,
+            PeerEvent::UsefulBlock => 10.0,
+            PeerEvent::Timeout => -5.0,
+            PeerEvent::Spam => -30.0,
+        }
+    }
+}
+
+/// What the node should do with a peer after a report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Keep,
+    Disconnect,
+    Ban(Timestamp),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerScore {
+    pub score: f64,
+    pub updated: Timestamp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub until: Timestamp,
+    pub reason: String,
+    // bans so far, each one lasts twice the last
+    pub strikes: u32,
+}
+
+/// Per peer scores that drift back to zero over time,
+/// with bans for peers that fall below the threshold.
+/// Only bans are persisted, scores start over
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Reputation {
+    #[serde(skip)]
+    pub config: ReputationConfig,
+    #[serde(skip)]
+    pub scores: HashMap<PeerId, PeerScore>,
+    pub bans: HashMap<PeerId, Ban>,
+}
+
+impl Reputation {
+    pub fn new(config: ReputationConfig) -> Self {
+        Reputation {
+            config,
+            ..Default::default()
+        }
+    }
+
+    // halves every half life, so a peer that
+    // behaves for long enough is forgiven
+    fn decayed(&self, score: &PeerScore, now: Timestamp) -> f64 {
+        let elapsed = now.as_millis().saturating_sub(score.updated.as_millis()) as f64 / 1000.0;
+        let half_life = self.config.half_life_secs.max(1) as f64;
+        score.score * 0.5f64.powf(elapsed / half_life)
+    }
+
+    pub fn score(&self, peer: &PeerId, now: Timestamp) -> f64 {
+        self.scores
+            .get(peer)
+            .map(|x| self.decayed(x, now))
+            .unwrap_or(0.0)
+    }
+
+    pub fn report(&mut self, peer: &PeerId, event: PeerEvent, now: Timestamp) -> Verdict {
+        let score = (self.score(peer, now) + event.score_delta())
+            .clamp(self.config.ban_threshold * 2.0, self.config.max_score);
+        self.scores.insert(*peer, PeerScore { score, updated: now });
+        if score <= self.config.ban_threshold {
+            return Verdict::Ban(self.ban(peer, &format!("{:?}", event), now));
+        }
+        if score <= self.config.disconnect_threshold {
+            return Verdict::Disconnect;
+        }
+        Verdict::Keep
+    }
+
+    pub fn ban(&mut self, peer: &PeerId, reason: &str, now: Timestamp) -> Timestamp {
+        let strikes = self.bans.get(peer).map(|x| x.strikes).unwrap_or(0) + 1;
+        let secs = self
+            .config
+            .ban_secs
+            .saturating_mul(1u64 << (strikes - 1).min(16))
+            .min(self.config.max_ban_secs);
+        let until = now.saturating_add(secs.saturating_mul(1000));
+        log::warn!("banning peer {} until {} for {}", peer, until, reason);
+        self.bans.insert(
+            *peer,
+            Ban {
+                until,
+                reason: reason.to_string(),
+                strikes,
+            },
+        );
+        // serving the ban wipes the slate
+        self.scores.remove(peer);
+        until
+    }
+
+    pub fn is_banned(&self, peer: &PeerId, now: Timestamp) -> bool {
+        self.bans.get(peer).map(|x| x.until > now).unwrap_or(false)
+    }
+
+    pub fn banned(&self, now: Timestamp) -> Vec<PeerId> {
+        self.bans
+            .iter()
+            .filter(|(_, x)| x.until > now)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    // peers whose ban has run out, lifting a ban twice
+    // is harmless so the caller can unblock them all.
+    // a ban is remembered for its strikes until the
+    // longest ban has passed again without a new one
+    pub fn expire(&mut self, now: Timestamp) -> Vec<PeerId> {
+        let forget_after = self.config.max_ban_secs.saturating_mul(1000);
+        self.bans
+            .retain(|_, x| now.as_millis().saturating_sub(x.until.as_millis()) < forget_after);
+        self.bans
+            .iter()
+            .filter(|(_, x)| x.until <= now)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, config: ReputationConfig) -> Result<Reputation> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Reputation::new(config));
+        }
+        let raw = std::fs::read_to_string(path)?;
+        let mut rep: Reputation = serde_json::from_str(&raw)
+            .map_err(|e| anyhow::anyhow!("invalid ban list {:?}: {}", path, e))?;
+        rep.config = config;
+        Ok(rep)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        storage::write_atomic(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+}
+
+/// Gossipsub scoring lined up with ours: our score is
+/// the application score, and its disconnect and ban
+/// thresholds are where gossipsub stops publishing to
+/// and then ignores a peer
+pub fn gossip_score_params(
+    config: &ReputationConfig,
+    topic: &gossipsub::IdentTopic,
+) -> (PeerScoreParams, PeerScoreThresholds) {
+    let topic_params = TopicScoreParams {
+        topic_weight: 1.0,
+        // blocks are rare, missing deliveries in the
+        // mesh is normal and shouldn't cost anything
+        mesh_message_deliveries_weight: 0.0,
+        mesh_failure_penalty_weight: 0.0,
+        // messages we reject during validation
+        invalid_message_deliveries_weight: PeerEvent::Spam.score_delta(),
+        invalid_message_deliveries_decay: 0.5,
+        ..Default::default()
+    };
+    let mut params = PeerScoreParams {
+        app_specific_weight: 1.0,
+        ..Default::default()
+    };
+    params.topics.insert(topic.hash(), topic_params);
+    let thresholds = PeerScoreThresholds {
+        gossip_threshold: config.disconnect_threshold / 4.0,
+        publish_threshold: config.disconnect_threshold,
+        graylist_threshold: config.ban_threshold,
+        ..Default::default()
+    };
+    (params, thresholds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3600 * 1000;
+
+    fn at(ms: u64) -> Timestamp {
+        Timestamp::from_millis(1_700_000_000_000 + ms)
+    }
+
+    #[test]
+    fn scores_halve_every_half_life() {
+        let mut rep = Reputation::new(ReputationConfig::default());
+        let peer = PeerId::random();
+        rep.report(&peer, PeerEvent::UsefulBlock, at(0));
+        assert_eq!(rep.score(&peer, at(0)), 10.0);
+        assert_eq!(rep.score(&peer, at(600 * 1000)), 5.0);
+        assert_eq!(rep.score(&PeerId::random(), at(0)), 0.0);
+    }
+
+    #[test]
+    fn bad_peers_are_dropped_then_banned() {
+        let mut rep = Reputation::new(ReputationConfig::default());
+        let peer = PeerId::random();
+        assert_eq!(rep.report(&peer, PeerEvent::Timeout, at(0)), Verdict::Keep);
+        assert_eq!(
+            rep.report(&peer, PeerEvent::InvalidBlock, at(0)),
+            Verdict::Disconnect
+        );
+        assert_eq!(
+            rep.report(&peer, PeerEvent::Spam, at(0)),
+            Verdict::Ban(at(HOUR))
+        );
+        assert!(rep.is_banned(&peer, at(0)));
+        assert_eq!(rep.banned(at(0)), vec![peer]);
+        // the slate is wiped once banned
+        assert_eq!(rep.score(&peer, at(0)), 0.0);
+    }
+
+    #[test]
+    fn repeat_offenders_serve_longer_up_to_the_cap() {
+        let mut rep = Reputation::new(ReputationConfig::default());
+        let peer = PeerId::random();
+        assert_eq!(rep.ban(&peer, "spam", at(0)), at(HOUR));
+        assert_eq!(rep.ban(&peer, "spam", at(0)), at(2 * HOUR));
+        for _ in 0..10 {
+            rep.ban(&peer, "spam", at(0));
+        }
+        assert_eq!(rep.bans[&peer].until, at(24 * HOUR));
+    }
+
+    #[test]
+    fn bans_run_out_and_are_forgotten_later() {
+        let mut rep = Reputation::new(ReputationConfig::default());
+        let peer = PeerId::random();
+        rep.ban(&peer, "spam", at(0));
+        assert!(rep.expire(at(HOUR / 2)).is_empty());
+        assert_eq!(rep.expire(at(HOUR)), vec![peer]);
+        assert!(!rep.is_banned(&peer, at(HOUR)));
+        // strikes are still counted within the memory window
+        assert_eq!(rep.bans[&peer].strikes, 1);
+        rep.expire(at(26 * HOUR));
+        assert!(rep.bans.is_empty());
+    }
+
+    #[test]
+    fn only_bans_are_saved() {
+        let path =
+            std::env::temp_dir().join(format!("essex-bans-{:016x}.json", rand::random::<u64>()));
+        let mut rep = Reputation::new(ReputationConfig::default());
+        let (banned, scored) = (PeerId::random(), PeerId::random());
+        rep.ban(&banned, "spam", at(0));
+        rep.report(&scored, PeerEvent::UsefulBlock, at(0));
+        rep.save(&path).unwrap();
+        let loaded = Reputation::load(&path, ReputationConfig::default()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(loaded.is_banned(&banned, at(0)));
+        assert!(loaded.scores.is_empty());
+    }
+}
@@ -0,0 +1,378 @@
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use libp2p::Multiaddr;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+use crate::address::address::Address;
+use crate::config::config::RpcConfig;
+use crate::explorer::explorer;
+use crate::node::node::NodeHandle;
+use crate::rpc::ws;
+use crate::transaction::transaction::Transaction;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+// the node turned the call down, e.g. a bad tx
+pub const REJECTED: i64 = -32000;
+pub const UNAUTHORIZED: i64 = -32001;
+
+// how long the CLI waits on a node, see call
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+// need the admin token
+const ADMIN_METHODS: &[&str] = &["admin_addPeer", "admin_reindex", "admin_shutdown"];
+
+#[derive(Debug, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub(crate) fn new(code: i64, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RpcState {
+    pub node: NodeHandle,
+    pub config: RpcConfig,
+}
+
+// serves JSON-RPC 2.0 on POST /, subscriptions on /ws
+// and the explorer on /explorer until the node stops
+pub async fn serve(node: NodeHandle, listener: TcpListener, config: RpcConfig) {
+    let stopped = node.clone();
+    let mut app = Router::new()
+        .route("/", post(handle_http))
+        .route("/ws", get(ws::upgrade));
+    if config.explorer {
+        app = app.nest("/explorer", explorer::routes().with_state(node.clone()));
+    }
+    let app = app.with_state(RpcState { node, config });
+    let server = axum::serve(listener, app).with_graceful_shutdown(async move {
+        stopped.stopped().await;
+    });
+    if let Err(e) = server.await {
+        println!("rpc server failed: {e}");
+    }
+}
+
+// a blocking call to a node's RPC for the CLI, plain
+// HTTP/1.1 with the connection closed after the reply
+pub fn call(addr: SocketAddr, method: &str, params: Value) -> anyhow::Result<Value> {
+    let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }).to_string();
+    let mut stream = TcpStream::connect_timeout(&addr, CALL_TIMEOUT)?;
+    stream.set_read_timeout(Some(CALL_TIMEOUT))?;
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        addr,
+        body.len(),
+        body
+    )?;
+    let mut raw = String::new();
+    stream.read_to_string(&mut raw)?;
+    let Some((head, body)) = raw.split_once("\r\n\r\n") else {
+        anyhow::bail!("malformed reply from {}", addr);
+    };
+    let status = head.lines().next().unwrap_or_default();
+    if !status.starts_with("HTTP/1.1 200") {
+        anyhow::bail!("rpc at {} answered {}", addr, status);
+    }
+    let mut reply: Value = serde_json::from_str(body)?;
+    if let Some(err) = reply.get("error") {
+        anyhow::bail!("{}", err.get("message").and_then(Value::as_str).unwrap_or("rpc error"));
+    }
+    Ok(reply["result"].take())
+}
+
+async fn handle_http(State(rpc): State<RpcState>, headers: HeaderMap, body: Bytes) -> Response {
+    let admin = is_admin(&headers, rpc.config.admin_token.as_deref());
+    match respond(&rpc.node, &body, admin).await {
+        Some(res) => Json(res).into_response(),
+        // only notifications, nothing to say
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+// the response to a raw request, a single call or a
+// batch, None when there is nothing to send back
+pub(crate) async fn respond(node: &NodeHandle, raw: &[u8], admin: bool) -> Option<Value> {
+    let request: Value = match serde_json::from_slice(raw) {
+        Ok(x) => x,
+        Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e))),
+    };
+    match request {
+        Value::Array(batch) if batch.is_empty() => {
+            Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "empty batch")))
+        }
+        Value::Array(batch) => {
+            let mut responses = vec![];
+            for call in batch {
+                if let Some(res) = handle_call(node, call, admin).await {
+                    responses.push(res);
+                }
+            }
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        call => handle_call(node, call, admin).await,
+    }
+}
+
+// bearer token, compared without bailing out early
+pub(crate) fn is_admin(headers: &HeaderMap, token: Option<&str>) -> bool {
+    let (Some(token), Some(given)) = (token, headers.get(header::AUTHORIZATION)) else {
+        return false;
+    };
+    let Some(given) = given.to_str().ok().and_then(|x| x.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    given.len() == token.len()
+        && given.bytes().zip(token.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+pub(crate) fn error_response(id: Value, err: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": err, "id": id })
+}
+
+// None for notifications, calls without an id
+async fn handle_call(node: &NodeHandle, call: Value, admin: bool) -> Option<Value> {
+    let Value::Object(mut call) = call else {
+        return Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "not an object")));
+    };
+    let id = call.remove("id");
+    let method = match (call.remove("jsonrpc"), call.remove("method")) {
+        (Some(v), Some(Value::String(method))) if v == "2.0" => method,
+        _ => {
+            let err = RpcError::new(INVALID_REQUEST, "needs jsonrpc 2.0 and a method");
+            return Some(error_response(id.unwrap_or(Value::Null), err));
+        }
+    };
+    let params = call.remove("params").unwrap_or(Value::Null);
+    let result = dispatch(node, &method, params, admin).await;
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(err) => error_response(id, err),
+    })
+}
+
+// positional or named, `params[index]` or `params[name]`
+pub(crate) fn param<T: DeserializeOwned>(params: &Value, index: usize, name: &str) -> Result<T, RpcError> {
+    let value = match params {
+        Value::Array(list) => list.get(index),
+        Value::Object(map) => map.get(name),
+        _ => None,
+    };
+    serde_json::from_value(value.cloned().unwrap_or(Value::Null))
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("{}: {}", name, e)))
+}
+
+fn to_value<T: Serialize>(x: T) -> Result<Value, RpcError> {
+    serde_json::to_value(x).map_err(|e| RpcError::new(INTERNAL_ERROR, e))
+}
+
+pub async fn dispatch(node: &NodeHandle, method: &str, params: Value, admin: bool) -> Result<Value, RpcError> {
+    if ADMIN_METHODS.contains(&method) && !admin {
+        return Err(RpcError::new(UNAUTHORIZED, "admin method, send the admin token"));
+    }
+    match method {
+        "chain_getHead" => to_value(node.head()),
+        "chain_getBlockByHash" => {
+            let hash: String = param(&params, 0, "hash")?;
+            to_value(node.block_by_hash(&hash))
+        }
+        "chain_getBlockByHeight" => {
+            let height: u64 = param(&params, 0, "height")?;
+            to_value(node.block_by_height(height))
+        }
+        "account_getBalance" => {
+            let address: Address = param(&params, 0, "address")?;
+            to_value(node.balance(&address))
+        }
+        "account_getNonce" => {
+            let address: Address = param(&params, 0, "address")?;
+            // counting queued txs when asked for pending
+            let pending: Option<bool> = param(&params, 1, "pending")?;
+            match pending {
+                Some(true) => to_value(node.next_nonce(&address)),
+                _ => to_value(node.nonce(&address)),
+            }
+        }
+        "account_getHistory" => {
+            let address: Address = param(&params, 0, "address")?;
+            let limit: Option<usize> = param(&params, 1, "limit")?;
+            let history: Vec<Value> = node
+                .history(&address)
+                .into_iter()
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|(location, tx)| json!({ "location": location, "tx": tx }))
+                .collect();
+            Ok(Value::Array(history))
+        }
+        "tx_submit" => {
+            let tx: Transaction = param(&params, 0, "tx")?;
+            let id = node
+                .submit_transaction(tx)
+                .await
+                .map_err(|e| RpcError::new(REJECTED, e))?;
+            to_value(id)
+        }
+        "tx_get" => {
+            let id: String = param(&params, 0, "id")?;
+            to_value(node.transaction(&id))
+        }
+        "mempool_list" => to_value(node.mempool()),
+        "net_peers" => to_value(node.peers()),
+        "node_info" => {
+            let mut info = to_value(node.info())?;
+            info["peer_id"] = json!(node.peer_id().to_string());
+            info["head"] = to_value(node.head().map(|x| json!({ "hash": x.block_hash, "height": x.height })))?;
+            Ok(info)
+        }
+        "admin_addPeer" => {
+            let addr: Multiaddr = param(&params, 0, "addr")?;
+            node.dial(addr).await.map_err(|e| RpcError::new(REJECTED, e))?;
+            Ok(Value::Bool(true))
+        }
+        "admin_reindex" => {
+            let node = node.clone();
+            let blocks = tokio::task::spawn_blocking(move || node.reindex())
+                .await
+                .map_err(|e| RpcError::new(INTERNAL_ERROR, e))?
+                .map_err(|e| RpcError::new(INTERNAL_ERROR, e))?;
+            to_value(blocks)
+        }
+        "admin_shutdown" => {
+            // answered before the node goes away
+            let node = node.clone();
+            tokio::spawn(async move { node.shutdown().await });
+            Ok(Value::Bool(true))
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("no method {}", method))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::account::Account;
+    use crate::chainspec::chainspec::ChainSpec;
+    use axum::http::HeaderValue;
+
+    fn funded(acc: &Account) -> NodeHandle {
+        let mut spec = ChainSpec::default();
+        spec.allocations.insert(acc.address(), 100);
+        NodeHandle::detached(spec)
+    }
+
+    fn request(method: &str, params: Value, id: Value) -> String {
+        json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": id }).to_string()
+    }
+
+    async fn ask(node: &NodeHandle, raw: &str, admin: bool) -> Value {
+        respond(node, raw.as_bytes(), admin).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_reads_against_the_chain() {
+        let acc = Account::create().unwrap();
+        let node = funded(&acc);
+        let res = ask(&node, r#"{"jsonrpc":"2.0","method":"chain_getHead","id":1}"#, false).await;
+        assert_eq!(res["id"], 1);
+        assert_eq!(res["result"]["height"], 0);
+        let call = request("account_getBalance", json!({ "address": acc.address() }), json!("a"));
+        let res = ask(&node, &call, false).await;
+        assert_eq!((res["id"].clone(), res["result"].clone()), (json!("a"), json!(100)));
+    }
+
+    #[tokio::test]
+    async fn takes_a_signed_transfer_into_the_mempool() {
+        let acc = Account::create().unwrap();
+        let node = funded(&acc);
+        let genesis = node.head().unwrap().block_hash;
+        let to = Account::create().unwrap().address();
+        let tx = Transaction::new(&acc, to, genesis, 10, 0).unwrap();
+        let call = request("tx_submit", json!([tx]), json!(1));
+        let res = ask(&node, &call, false).await;
+        assert_eq!(res["result"], json!(tx.tx_header.transaction_id));
+        let nonce = request("account_getNonce", json!([acc.address(), true]), json!(2));
+        assert_eq!(ask(&node, &nonce, false).await["result"], 1);
+        // the same tx again is turned down
+        let res = ask(&node, &call, false).await;
+        assert_eq!(res["error"]["code"], REJECTED);
+    }
+
+    #[tokio::test]
+    async fn reports_malformed_calls() {
+        let node = NodeHandle::detached(ChainSpec::default());
+        let code = |res: Value| res["error"]["code"].as_i64().unwrap();
+        assert_eq!(code(ask(&node, "{", false).await), PARSE_ERROR);
+        assert_eq!(code(ask(&node, "[]", false).await), INVALID_REQUEST);
+        let no_version = r#"{"method":"chain_getHead","id":1}"#;
+        assert_eq!(code(ask(&node, no_version, false).await), INVALID_REQUEST);
+        let unknown = request("nope", Value::Null, json!(1));
+        assert_eq!(code(ask(&node, &unknown, false).await), METHOD_NOT_FOUND);
+        let bad_param = request("account_getBalance", json!(["essex1xyz"]), json!(1));
+        assert_eq!(code(ask(&node, &bad_param, false).await), INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn batches_leave_out_notifications() {
+        let node = NodeHandle::detached(ChainSpec::default());
+        let batch = r#"[
+            {"jsonrpc":"2.0","method":"mempool_list","id":1},
+            {"jsonrpc":"2.0","method":"mempool_list"},
+            {"jsonrpc":"2.0","method":"nope","id":2}
+        ]"#;
+        let res = ask(&node, batch, false).await;
+        let ids: Vec<Value> = res.as_array().unwrap().iter().map(|x| x["id"].clone()).collect();
+        assert_eq!(ids, vec![json!(1), json!(2)]);
+        let only_notifications = r#"[{"jsonrpc":"2.0","method":"mempool_list"}]"#;
+        assert!(respond(&node, only_notifications.as_bytes(), false).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn admin_methods_need_the_token() {
+        let node = NodeHandle::detached(ChainSpec::default());
+        let call = r#"{"jsonrpc":"2.0","method":"admin_reindex","id":1}"#;
+        assert_eq!(ask(&node, call, false).await["error"]["code"], UNAUTHORIZED);
+        // blocks covered, just genesis
+        assert_eq!(ask(&node, call, true).await["result"], 1);
+
+        let mut headers = HeaderMap::new();
+        assert!(!is_admin(&headers, Some("secret")));
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        assert!(is_admin(&headers, Some("secret")));
+        assert!(!is_admin(&headers, Some("secret2")));
+        assert!(!is_admin(&headers, None));
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("secret"));
+        assert!(!is_admin(&headers, Some("secret")));
+    }
+
+    #[test]
+    fn params_are_positional_or_named() {
+        assert_eq!(param::<u64>(&json!([7]), 0, "height").unwrap(), 7);
+        assert_eq!(param::<u64>(&json!({ "height": 7 }), 0, "height").unwrap(), 7);
+        assert_eq!(param::<Option<bool>>(&json!([1]), 1, "pending").unwrap(), None);
+        assert_eq!(param::<u64>(&Value::Null, 0, "height").unwrap_err().code, INVALID_PARAMS);
+    }
+}
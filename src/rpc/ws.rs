@@ -0,0 +1,386 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::Response;
+use futures::{SinkExt, StreamExt};
+use log::info;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+
+use crate::address::address::Address;
+use crate::block::block::Block;
+use crate::blockchain::blockchain::{self, TxLocation};
+use crate::events::events::{EventFilter, NodeEvent};
+use crate::node::node::NodeHandle;
+use crate::rpc::rpc::{self, RpcError, RpcState, INVALID_PARAMS, REJECTED};
+use crate::transaction::transaction::Transaction;
+
+/// What a websocket client can subscribe to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Topic {
+    NewHeads,
+    PendingTransactions,
+    // blocks as they become final, see
+    // Blockchain::finalized_height
+    Finalized,
+    Reorgs,
+    // txs from or to an address, pending and included
+    Address(Address),
+}
+
+impl Topic {
+    // ["newHeads"] or ["address", "<address>"]
+    fn parse(params: &Value) -> Result<Topic, RpcError> {
+        let name: String = rpc::param(params, 0, "topic")?;
+        match name.as_str() {
+            "newHeads" => Ok(Topic::NewHeads),
+            "pendingTransactions" => Ok(Topic::PendingTransactions),
+            "finalized" => Ok(Topic::Finalized),
+            "reorgs" => Ok(Topic::Reorgs),
+            "address" => Ok(Topic::Address(rpc::param(params, 1, "address")?)),
+            _ => Err(RpcError::new(INVALID_PARAMS, format!("no topic {}", name))),
+        }
+    }
+}
+
+// the subscriptions of one client by id
+#[derive(Default)]
+struct Subscriptions {
+    next_id: u64,
+    topics: HashMap<String, Topic>,
+    // highest final block the client has been told of
+    finalized: u64,
+}
+
+impl Subscriptions {
+    fn add(&mut self, topic: Topic, max: usize) -> Result<String, RpcError> {
+        if self.topics.len() >= max {
+            return Err(RpcError::new(REJECTED, format!("at most {} subscriptions", max)));
+        }
+        self.next_id += 1;
+        let id = format!("0x{:x}", self.next_id);
+        self.topics.insert(id.clone(), topic);
+        Ok(id)
+    }
+
+    // what `event` means for each subscription, as
+    // ready to send notifications
+    fn notify(&mut self, event: &NodeEvent, node: &NodeHandle) -> Vec<String> {
+        let finalized = self.newly_final(event, node);
+        let mut notes = vec![];
+        for (id, topic) in &self.topics {
+            for result in results(topic, event, &finalized) {
+                let note = json!({
+                    "jsonrpc": "2.0",
+                    "method": "subscription",
+                    "params": { "subscription": id, "result": result },
+                });
+                notes.push(note.to_string());
+            }
+        }
+        notes
+    }
+
+    // every block that became final since the last call,
+    // a head that moves up several blocks at once, as a
+    // reorg can, finalizes all the blocks on the way
+    fn newly_final(&mut self, event: &NodeEvent, node: &NodeHandle) -> Vec<Value> {
+        let moved = matches!(event, NodeEvent::BlockApplied(_) | NodeEvent::Reorg { .. });
+        if !moved || !self.topics.values().any(|x| *x == Topic::Finalized) {
+            return vec![];
+        }
+        let upto = node.finalized_height();
+        let blocks = (self.finalized + 1..=upto)
+            .filter_map(|height| node.block_by_height(height))
+            .map(|x| head(&x))
+            .collect();
+        self.finalized = self.finalized.max(upto);
+        blocks
+    }
+}
+
+// `finalized` are the blocks `event` made final
+fn results(topic: &Topic, event: &NodeEvent, finalized: &[Value]) -> Vec<Value> {
+    match (topic, event) {
+        (Topic::NewHeads, NodeEvent::BlockApplied(blk)) => vec![head(blk)],
+        // the new branch from the fork point up
+        (Topic::NewHeads, NodeEvent::Reorg { applied, .. }) => applied.iter().map(|x| head(x)).collect(),
+        (Topic::Finalized, _) => finalized.to_vec(),
+        (Topic::Reorgs, NodeEvent::Reorg { reverted, applied }) => vec![json!({
+            "reverted": reverted.iter().map(|x| head(x)).collect::<Vec<_>>(),
+            "applied": applied.iter().map(|x| head(x)).collect::<Vec<_>>(),
+        })],
+        (Topic::PendingTransactions, NodeEvent::TransactionAccepted(tx)) => vec![json!(&**tx)],
+        (Topic::Address(addr), NodeEvent::TransactionAccepted(tx)) if involves(tx, addr) => {
+            vec![json!({ "status": "pending", "tx": &**tx })]
+        }
+        (Topic::Address(addr), NodeEvent::BlockApplied(blk)) => transfers(blk, addr)
+            .into_iter()
+            .map(|(location, tx)| json!({ "status": "included", "location": location, "tx": tx }))
+            .collect(),
+        (Topic::Address(addr), NodeEvent::Reorg { reverted, applied }) => {
+            let reverted = reverted
+                .iter()
+                .flat_map(|blk| transfers(blk, addr))
+                .map(|(location, tx)| json!({ "status": "reverted", "location": location, "tx": tx }));
+            let included = applied
+                .iter()
+                .flat_map(|blk| transfers(blk, addr))
+                .map(|(location, tx)| json!({ "status": "included", "location": location, "tx": tx }));
+            reverted.chain(included).collect()
+        }
+        _ => vec![],
+    }
+}
+
+// a block without its entries
+fn head(blk: &Block) -> Value {
+    json!({
+        "hash": blk.block_hash,
+        "prev_hash": blk.prev_hash,
+        "height": blk.height,
+        "validator": blk.validator,
+        "timestamp": blk.timestamp,
+        "entries": blk.block_data.len(),
+    })
+}
+
+fn involves(tx: &Transaction, addr: &Address) -> bool {
+    tx.tx_from == *addr || tx.tx_to == *addr
+}
+
+// transfers in `blk` from or to `addr`
+fn transfers(blk: &Block, addr: &Address) -> Vec<(TxLocation, Transaction)> {
+    blockchain::transfers(blk)
+        .into_iter()
+        .filter(|(_, tx)| involves(tx, addr))
+        .collect()
+}
+
+pub(crate) async fn upgrade(State(rpc): State<RpcState>, headers: HeaderMap, ws: WebSocketUpgrade) -> Response {
+    let admin = rpc::is_admin(&headers, rpc.config.admin_token.as_deref());
+    ws.on_upgrade(move |socket| client(socket, rpc, admin))
+}
+
+// one task reads the client and the bus, another writes
+// to the client through a bounded queue, a client that
+// lets the queue fill up or the bus lag is dropped
+async fn client(socket: WebSocket, rpc: RpcState, admin: bool) {
+    let (mut sink, mut stream) = socket.split();
+    let (out_tx, mut out_rx) = mpsc::channel::<Message>(rpc.config.ws_buffer);
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = out_rx.recv().await {
+            if sink.send(msg).await.is_err() {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    let node = rpc.node.clone();
+    let mut events = node.subscribe(EventFilter::chain().or(EventFilter::mempool()));
+    let mut subs = Subscriptions::default();
+    let mut slow = false;
+    loop {
+        let outgoing = tokio::select! {
+            incoming = stream.next() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    match handle_text(&rpc, &mut subs, &text, admin).await {
+                        Some(reply) => vec![reply],
+                        None => vec![],
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // pings are answered underneath
+                Some(Ok(_)) => vec![],
+            },
+            event = events.recv() => match event {
+                Ok(event) => subs.notify(&event, &node),
+                Err(RecvError::Lagged(n)) => {
+                    info!("dropping websocket client {} events behind", n);
+                    slow = true;
+                    break;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = node.stopped() => break,
+        };
+        for text in outgoing {
+            if out_tx.try_send(Message::Text(text)).is_err() {
+                info!("dropping websocket client with a full buffer");
+                slow = true;
+                break;
+            }
+        }
+        if slow {
+            break;
+        }
+    }
+    drop(out_tx);
+    if slow {
+        // nothing left to wait on a client that doesn't read
+        writer.abort();
+    } else {
+        let _ = writer.await;
+    }
+}
+
+// subscribe and unsubscribe are handled here, the rest
+// goes to the plain RPC methods
+async fn handle_text(rpc: &RpcState, subs: &mut Subscriptions, text: &str, admin: bool) -> Option<String> {
+    let call: Value = serde_json::from_str(text).unwrap_or(Value::Null);
+    let method = call.get("method").and_then(Value::as_str).unwrap_or_default();
+    if method != "subscribe" && method != "unsubscribe" {
+        return rpc::respond(&rpc.node, text.as_bytes(), admin)
+            .await
+            .map(|x| x.to_string());
+    }
+    let id = call.get("id").cloned().unwrap_or(Value::Null);
+    let params = call.get("params").cloned().unwrap_or(Value::Null);
+    let result = match method {
+        "subscribe" => Topic::parse(&params)
+            .and_then(|topic| {
+                // final blocks from before don't count as new
+                if topic == Topic::Finalized && !subs.topics.values().any(|x| *x == topic) {
+                    subs.finalized = rpc.node.finalized_height();
+                }
+                subs.add(topic, rpc.config.ws_max_subscriptions)
+            })
+            .map(Value::String),
+        _ => rpc::param::<String>(&params, 0, "subscription")
+            .map(|x| Value::Bool(subs.topics.remove(&x).is_some())),
+    };
+    let res = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(err) => rpc::error_response(id, err),
+    };
+    Some(res.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::account::Account;
+    use crate::chainspec::chainspec::ChainSpec;
+    use crate::config::config::RpcConfig;
+    use crate::transaction::transaction::BlockEntry;
+    use std::sync::Arc;
+
+    fn block(height: u64, entries: Vec<BlockEntry>) -> Arc<Block> {
+        Arc::new(Block {
+            height,
+            block_hash: format!("{:064x}", height),
+            block_data: entries,
+            ..Block::default()
+        })
+    }
+
+    fn transfer(from: &Account, to: Address) -> BlockEntry {
+        BlockEntry::Transfer(Box::new(Transaction::new(from, to, String::new(), 1, 0).unwrap()))
+    }
+
+    #[test]
+    fn topics_parse_from_params() {
+        assert_eq!(Topic::parse(&json!(["newHeads"])).unwrap(), Topic::NewHeads);
+        let addr = Account::create().unwrap().address();
+        assert_eq!(Topic::parse(&json!(["address", addr])).unwrap(), Topic::Address(addr));
+        assert_eq!(Topic::parse(&json!(["address"])).unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(Topic::parse(&json!(["logs"])).unwrap_err().code, INVALID_PARAMS);
+    }
+
+    #[test]
+    fn a_client_has_a_subscription_limit() {
+        let mut subs = Subscriptions::default();
+        let first = subs.add(Topic::NewHeads, 2).unwrap();
+        assert_ne!(subs.add(Topic::Reorgs, 2).unwrap(), first);
+        assert_eq!(subs.add(Topic::Finalized, 2).unwrap_err().code, REJECTED);
+    }
+
+    #[test]
+    fn a_reorg_reports_new_heads_and_moved_transfers() {
+        let (alice, bob) = (Account::create().unwrap(), Account::create().unwrap());
+        let reorg = NodeEvent::Reorg {
+            reverted: vec![block(1, vec![transfer(&alice, bob.address())])],
+            applied: vec![block(1, vec![]), block(2, vec![transfer(&bob, alice.address())])],
+        };
+        let heads = results(&Topic::NewHeads, &reorg, &[]);
+        let heights: Vec<Value> = heads.iter().map(|x| x["height"].clone()).collect();
+        assert_eq!(heights, vec![json!(1), json!(2)]);
+        let moved = results(&Topic::Address(alice.address()), &reorg, &[]);
+        let status: Vec<Value> = moved.iter().map(|x| x["status"].clone()).collect();
+        assert_eq!(status, vec![json!("reverted"), json!("included")]);
+        let stranger = Account::create().unwrap().address();
+        assert!(results(&Topic::Address(stranger), &reorg, &[]).is_empty());
+        assert!(results(&Topic::PendingTransactions, &reorg, &[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn finalized_announces_every_block_that_became_final() {
+        let spec = ChainSpec {
+            finality_depth: 2,
+            ..ChainSpec::default()
+        };
+        let rpc = RpcState {
+            node: NodeHandle::detached(spec),
+            config: RpcConfig::default(),
+        };
+        let validator = Account::create().unwrap();
+        let mut subs = Subscriptions::default();
+        let sub = r#"{"jsonrpc":"2.0","method":"subscribe","params":["finalized"],"id":1}"#;
+        send(&rpc, &mut subs, sub).await;
+        let heights = |notes: Vec<String>| -> Vec<u64> {
+            notes
+                .iter()
+                .map(|x| serde_json::from_str::<Value>(x).unwrap())
+                .map(|x| x["params"]["result"]["height"].as_u64().unwrap())
+                .collect()
+        };
+        for _ in 0..3 {
+            rpc.node.extend_with(&validator);
+        }
+        let applied = NodeEvent::BlockApplied(Arc::new(rpc.node.head().unwrap()));
+        assert_eq!(heights(subs.notify(&applied, &rpc.node)), vec![1]);
+        // the head jumping up by three finalizes three
+        for _ in 0..3 {
+            rpc.node.extend_with(&validator);
+        }
+        let reorg = NodeEvent::Reorg {
+            reverted: vec![],
+            applied: vec![Arc::new(rpc.node.head().unwrap())],
+        };
+        assert_eq!(heights(subs.notify(&reorg, &rpc.node)), vec![2, 3, 4]);
+        assert!(subs.notify(&reorg, &rpc.node).is_empty());
+    }
+
+    async fn send(rpc: &RpcState, subs: &mut Subscriptions, text: &str) -> Value {
+        serde_json::from_str(&handle_text(rpc, subs, text, false).await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn subscribe_and_unsubscribe_over_the_socket_protocol() {
+        let rpc = RpcState {
+            node: NodeHandle::detached(ChainSpec::default()),
+            config: RpcConfig::default(),
+        };
+        let mut subs = Subscriptions::default();
+        let sub = r#"{"jsonrpc":"2.0","method":"subscribe","params":["newHeads"],"id":1}"#;
+        let res = send(&rpc, &mut subs, sub).await;
+        let id = res["result"].as_str().unwrap().to_string();
+        assert_eq!(subs.topics[&id], Topic::NewHeads);
+        let notes = subs.notify(&NodeEvent::BlockApplied(block(1, vec![])), &rpc.node);
+        assert_eq!(notes.len(), 1);
+        let note: Value = serde_json::from_str(&notes[0]).unwrap();
+        assert_eq!(note["params"]["subscription"], json!(id));
+
+        let unsub = json!({ "jsonrpc": "2.0", "method": "unsubscribe", "params": [id], "id": 2 });
+        let res = send(&rpc, &mut subs, &unsub.to_string()).await;
+        assert_eq!(res["result"], true);
+        assert!(subs.topics.is_empty());
+        // anything else is a plain rpc call
+        let head = r#"{"jsonrpc":"2.0","method":"chain_getHead","id":3}"#;
+        let res = send(&rpc, &mut subs, head).await;
+        assert_eq!(res["result"]["height"], 0);
+    }
+}
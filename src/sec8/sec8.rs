@@ -1,8 +1,17 @@
-use rand::rngs::OsRng;
-use rsa::{RsaPrivateKey, RsaPublicKey, Pkcs1v15Encrypt};
-use secp256k1::{SecretKey, PublicKey};
+use chacha20poly1305::{
+  aead::{Aead, KeyInit, Payload},
+  XChaCha20Poly1305, XNonce,
+};
+use rand::{rngs::OsRng, RngCore};
+use secp256k1::constants::CURVE_ORDER;
+use secp256k1::ecdh::SharedSecret;
+use secp256k1::hashes::{sha256, Hash, HashEngine};
+use secp256k1::schnorr::Signature as SchnorrSignature;
+use secp256k1::{Keypair, Message, Parity, Scalar, Secp256k1, SecretKey, PublicKey, XOnlyPublicKey};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
 
-use anyhow::{Ok,Result};
+use anyhow::{anyhow, bail, Ok, Result};
 
 pub fn sec8_tx_id_hash() -> Result<(SecretKey, PublicKey)> {
   let secp = secp256k1::Secp256k1::new();
@@ -16,17 +25,635 @@ pub fn sec8_block_id_hash()-> Result<(SecretKey, PublicKey)> {
   Ok((sk, pk))
 }
 
-pub fn sec8_encrypt_block_data(bits_size: usize, block_data:&[u8]) -> Result<Vec<u8>>{
-  let mut rng = rand::thread_rng();
-  let sec8_private = RsaPrivateKey::new(&mut rng, bits_size)?;
-  let sec8_public = RsaPublicKey::from(&sec8_private);
-  let sec8_data_encrypt = sec8_public.encrypt(&mut rng, Pkcs1v15Encrypt,block_data)?;
-  Ok(sec8_data_encrypt)
+/// Hybrid encrypted payload, the data is sealed once under a
+/// random content key and that key is wrapped for every
+/// recipient with ecies over secp256k1
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sec8Envelope {
+  pub version: u8,
+  pub recipients: Vec<Sec8WrappedKey>,
+  pub nonce: String,
+  pub ciphertext: String,
 }
 
-pub fn sec8_decrypt_block_data(bits_size: usize, block_data:Vec<u8>) -> Result<Vec<u8>>{
-  let mut rng = rand::thread_rng();
-  let sec8_private = RsaPrivateKey::new(&mut rng, bits_size)?;
-  let sec8_data_decrypt = sec8_private.decrypt(Pkcs1v15Encrypt,&block_data)?;
-  Ok(sec8_data_decrypt)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sec8WrappedKey {
+  // recipient public key
+  pub public: String,
+  // ephemeral key the shared secret was made with
+  pub ephemeral: String,
+  pub nonce: String,
+  pub wrapped: String,
+}
+
+const SEC8_ENVELOPE_VERSION: u8 = 1;
+const SEC8_WRAP_DOMAIN: &[u8] = b"essex-sec8-wrap";
+const SEC8_DATA_DOMAIN: &[u8] = b"essex-sec8-data";
+
+fn sec8_random_nonce() -> [u8; 24] {
+  let mut nonce = [0u8; 24];
+  OsRng.fill_bytes(&mut nonce);
+  nonce
+}
+
+// key encryption key for one recipient, bound to both
+// public keys so a wrapped key can't be moved elsewhere
+fn sec8_kek(shared: &SharedSecret, ephemeral: &PublicKey, recipient: &PublicKey) -> Zeroizing<[u8; 32]> {
+  let mut engine = sha256::Hash::engine();
+  engine.input(SEC8_WRAP_DOMAIN);
+  engine.input(&shared.secret_bytes());
+  engine.input(&ephemeral.serialize());
+  engine.input(&recipient.serialize());
+  Zeroizing::new(sha256::Hash::from_engine(engine).to_byte_array())
+}
+
+fn sec8_hex_nonce(nonce: &str) -> Result<XNonce> {
+  let raw = hex::decode(nonce)?;
+  if raw.len() != 24 {
+    bail!("invalid nonce length {}", raw.len());
+  }
+  Ok(*XNonce::from_slice(&raw))
+}
+
+pub fn sec8_encrypt_block_data(recipients: &[PublicKey], block_data: &[u8]) -> Result<Sec8Envelope> {
+  if recipients.is_empty() {
+    bail!("no recipients to encrypt block data for");
+  }
+  let secp = Secp256k1::new();
+  let mut content_key = Zeroizing::new([0u8; 32]);
+  OsRng.fill_bytes(content_key.as_mut());
+  let nonce = sec8_random_nonce();
+  let ciphertext = XChaCha20Poly1305::new(content_key.as_ref().into())
+    .encrypt(XNonce::from_slice(&nonce), Payload { msg: block_data, aad: SEC8_DATA_DOMAIN })
+    .map_err(|_| anyhow!("block data encryption failed"))?;
+  let mut wrapped_keys = Vec::with_capacity(recipients.len());
+  for recipient in recipients {
+    let (eph_secret, eph_public) = secp.generate_keypair(&mut OsRng);
+    let shared = SharedSecret::new(recipient, &eph_secret);
+    let kek = sec8_kek(&shared, &eph_public, recipient);
+    let wrap_nonce = sec8_random_nonce();
+    let wrapped = XChaCha20Poly1305::new(kek.as_ref().into())
+      .encrypt(XNonce::from_slice(&wrap_nonce), content_key.as_ref())
+      .map_err(|_| anyhow!("key wrapping failed"))?;
+    wrapped_keys.push(Sec8WrappedKey {
+      public: recipient.to_string(),
+      ephemeral: eph_public.to_string(),
+      nonce: hex::encode(wrap_nonce),
+      wrapped: hex::encode(wrapped),
+    });
+  }
+  Ok(Sec8Envelope {
+    version: SEC8_ENVELOPE_VERSION,
+    recipients: wrapped_keys,
+    nonce: hex::encode(nonce),
+    ciphertext: hex::encode(ciphertext),
+  })
+}
+
+pub fn sec8_decrypt_block_data(secret: &SecretKey, envelope: &Sec8Envelope) -> Result<Vec<u8>> {
+  if envelope.version != SEC8_ENVELOPE_VERSION {
+    bail!("unsupported envelope version {}", envelope.version);
+  }
+  let secp = Secp256k1::signing_only();
+  let public = PublicKey::from_secret_key(&secp, secret);
+  let entry = envelope
+    .recipients
+    .iter()
+    .find(|x| x.public == public.to_string())
+    .ok_or_else(|| anyhow!("block data is not encrypted for {}", public))?;
+  let ephemeral: PublicKey = entry.ephemeral.parse()?;
+  let shared = SharedSecret::new(&ephemeral, secret);
+  let kek = sec8_kek(&shared, &ephemeral, &public);
+  let content_key = Zeroizing::new(
+    XChaCha20Poly1305::new(kek.as_ref().into())
+      .decrypt(&sec8_hex_nonce(&entry.nonce)?, hex::decode(&entry.wrapped)?.as_ref())
+      .map_err(|_| anyhow!("could not unwrap content key"))?,
+  );
+  if content_key.len() != 32 {
+    bail!("invalid content key length");
+  }
+  let ciphertext = hex::decode(&envelope.ciphertext)?;
+  let plain = XChaCha20Poly1305::new(content_key.as_slice().into())
+    .decrypt(&sec8_hex_nonce(&envelope.nonce)?, Payload { msg: &ciphertext, aad: SEC8_DATA_DOMAIN })
+    .map_err(|_| anyhow!("block data decryption failed"))?;
+  Ok(plain)
+}
+
+// bip-340 schnorr, messages are hashed with
+// sha256 first so any length can be signed
+pub fn sec8_schnorr_keypair() -> Result<Keypair> {
+  let secp = Secp256k1::new();
+  Ok(Keypair::new(&secp, &mut OsRng))
+}
+
+pub fn sec8_schnorr_digest(msg: &[u8]) -> Message {
+  Message::from_digest(sha256::Hash::hash(msg).to_byte_array())
+}
+
+pub fn sec8_schnorr_sign(keypair: &Keypair, msg: &[u8]) -> SchnorrSignature {
+  let secp = Secp256k1::signing_only();
+  secp.sign_schnorr_with_rng(&sec8_schnorr_digest(msg), keypair, &mut OsRng)
+}
+
+pub fn sec8_schnorr_verify(public: &XOnlyPublicKey, msg: &[u8], sig: &SchnorrSignature) -> Result<()> {
+  let secp = Secp256k1::verification_only();
+  secp.verify_schnorr(sig, &sec8_schnorr_digest(msg), public)?;
+  Ok(())
+}
+
+// below this many signatures the threads
+// cost more than they save
+const SEC8_PARALLEL_MIN: usize = 64;
+
+fn sec8_schnorr_first_bad(batch: &[(XOnlyPublicKey, Vec<u8>, SchnorrSignature)]) -> Option<usize> {
+  batch.iter().position(|(pk, msg, sig)| sec8_schnorr_verify(pk, msg, sig).is_err())
+}
+
+// checks a whole batch with one random linear combination,
+// Σaᵢsᵢ·G == Σaᵢ·Rᵢ + Σaᵢeᵢ·Pᵢ, a bad signature only gets
+// through if the random aᵢ happen to cancel it out. errors
+// are the rare zero sums, the caller then checks one by one
+fn sec8_schnorr_batch_check(batch: &[(XOnlyPublicKey, Vec<u8>, SchnorrSignature)]) -> Result<bool> {
+  let secp = Secp256k1::new();
+  let mut s_sum: Option<SecretKey> = None;
+  let mut points = Vec::with_capacity(batch.len() * 2);
+  // a key that signs many messages costs one multiplication
+  let mut key_coefficients: std::collections::HashMap<XOnlyPublicKey, SecretKey> =
+    std::collections::HashMap::new();
+  for (pk, msg, sig) in batch {
+    let bytes = sig.as_ref();
+    let (r, s) = bytes.split_at(32);
+    let core::result::Result::Ok(r_point) = XOnlyPublicKey::from_slice(r) else {
+      return Ok(false);
+    };
+    let core::result::Result::Ok(s) = Scalar::from_be_bytes(s.try_into()?) else {
+      return Ok(false);
+    };
+    let digest = sec8_schnorr_digest(msg);
+    let e = sec8_scalar_mod_n(sec8_tagged_hash(
+      "BIP0340/challenge",
+      &[r, &pk.serialize(), digest.as_ref()],
+    ));
+    let a = SecretKey::new(&mut OsRng);
+
+    if s != Scalar::ZERO {
+      let term = a.mul_tweak(&s)?;
+      s_sum = Some(match s_sum {
+        Some(sum) => sum.add_tweak(&Scalar::from(term))?,
+        None => term,
+      });
+    }
+    points.push(r_point.public_key(Parity::Even).mul_tweak(&secp, &Scalar::from(a))?);
+    if e != Scalar::ZERO {
+      let term = a.mul_tweak(&e)?;
+      let c = match key_coefficients.get(pk) {
+        Some(c) => c.add_tweak(&Scalar::from(term))?,
+        None => term,
+      };
+      key_coefficients.insert(*pk, c);
+    }
+  }
+  for (pk, c) in &key_coefficients {
+    points.push(pk.public_key(Parity::Even).mul_tweak(&secp, &Scalar::from(*c))?);
+  }
+  let lhs = PublicKey::from_secret_key(&secp, &s_sum.ok_or_else(|| anyhow!("zero s sum"))?);
+  let rhs = PublicKey::combine_keys(&points.iter().collect::<Vec<_>>())?;
+  Ok(lhs == rhs)
+}
+
+// one by one only runs once the combination failed,
+// to find which signature it was
+fn sec8_schnorr_batch_first_bad(
+  batch: &[(XOnlyPublicKey, Vec<u8>, SchnorrSignature)],
+) -> Option<usize> {
+  match sec8_schnorr_batch_check(batch) {
+    core::result::Result::Ok(true) => None,
+    _ => sec8_schnorr_first_bad(batch),
+  }
+}
+
+// batch verifies every (key, msg, sig) and reports the
+// first bad index, large sets are split over the cores
+pub fn sec8_schnorr_verify_all(batch: &[(XOnlyPublicKey, Vec<u8>, SchnorrSignature)]) -> Result<()> {
+  if batch.is_empty() {
+    return Ok(());
+  }
+  let workers = std::thread::available_parallelism().map(|x| x.get()).unwrap_or(1);
+  let bad = if workers == 1 || batch.len() < SEC8_PARALLEL_MIN {
+    sec8_schnorr_batch_first_bad(batch)
+  } else {
+    let chunk = batch.len().div_ceil(workers);
+    std::thread::scope(|scope| {
+      let handles: Vec<_> = batch
+        .chunks(chunk)
+        .enumerate()
+        .map(|(ci, items)| {
+          scope.spawn(move || sec8_schnorr_batch_first_bad(items).map(|i| ci * chunk + i))
+        })
+        .collect();
+      // a panicking worker is a bug, pass it on
+      // rather than blaming some signature for it
+      handles
+        .into_iter()
+        .filter_map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+        .min()
+    })
+  };
+  match bad {
+    Some(i) => bail!("invalid schnorr signature at index {}", i),
+    None => Ok(()),
+  }
+}
+
+// bip-340 style tagged hash
+fn sec8_tagged_hash(tag: &str, parts: &[&[u8]]) -> [u8; 32] {
+  let tag_hash = sha256::Hash::hash(tag.as_bytes());
+  let mut engine = sha256::Hash::engine();
+  engine.input(tag_hash.as_ref());
+  engine.input(tag_hash.as_ref());
+  for part in parts {
+    engine.input(part);
+  }
+  sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+// a hash is below 2^256 < 2n so one
+// subtraction is enough to reduce it
+fn sec8_scalar_mod_n(mut bytes: [u8; 32]) -> Scalar {
+  if let core::result::Result::Ok(x) = Scalar::from_be_bytes(bytes) {
+    return x;
+  }
+  let mut borrow = 0i16;
+  for i in (0..32).rev() {
+    let mut d = bytes[i] as i16 - CURVE_ORDER[i] as i16 - borrow;
+    borrow = if d < 0 { d += 256; 1 } else { 0 };
+    bytes[i] = d as u8;
+  }
+  Scalar::from_be_bytes(bytes).expect("reduced below curve order")
+}
+
+fn sec8_mul_point(point: &PublicKey, tweak: &Scalar) -> Result<PublicKey> {
+  Ok(point.mul_tweak(&Secp256k1::verification_only(), tweak)?)
+}
+
+/// MuSig2 aggregate of a set of signer keys, signatures
+/// from the whole group verify as one plain bip-340
+/// signature against `x_only()`
+#[derive(Debug, Clone)]
+pub struct MusigKeyAgg {
+  pub keys: Vec<PublicKey>,
+  pub coefficients: Vec<Scalar>,
+  pub agg_key: PublicKey,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MusigPubNonce {
+  pub r1: PublicKey,
+  pub r2: PublicKey,
+}
+
+// secret half of a nonce, used for exactly one
+// signature and consumed by sec8_musig_partial_sign
+pub struct MusigSecNonce {
+  k1: SecretKey,
+  k2: SecretKey,
+}
+
+impl Drop for MusigSecNonce {
+  fn drop(&mut self) {
+    self.k1.non_secure_erase();
+    self.k2.non_secure_erase();
+  }
+}
+
+/// Everything signers agree on before producing
+/// partial signatures for one message
+#[derive(Debug, Clone)]
+pub struct MusigSession {
+  pub agg_nonce: MusigPubNonce,
+  pub b: Scalar,
+  pub e: Scalar,
+  pub r: PublicKey,
+  pub msg: Message,
+}
+
+impl MusigKeyAgg {
+  pub fn x_only(&self) -> XOnlyPublicKey {
+    self.agg_key.x_only_public_key().0
+  }
+
+  fn parity(&self) -> Parity {
+    self.agg_key.x_only_public_key().1
+  }
+
+  fn coefficient(&self, public: &PublicKey) -> Result<Scalar> {
+    self
+      .keys
+      .iter()
+      .position(|k| k == public)
+      .map(|i| self.coefficients[i])
+      .ok_or_else(|| anyhow!("{} is not part of this key aggregate", public))
+  }
+}
+
+// keys are sorted first so every signer
+// gets the same aggregate whatever the order
+pub fn sec8_musig_key_agg(keys: &[PublicKey]) -> Result<MusigKeyAgg> {
+  if keys.is_empty() {
+    bail!("musig needs at least one key");
+  }
+  let mut keys = keys.to_vec();
+  keys.sort();
+  keys.dedup();
+  let serialised: Vec<[u8; 33]> = keys.iter().map(|k| k.serialize()).collect();
+  let list: Vec<&[u8]> = serialised.iter().map(|k| &k[..]).collect();
+  let l = sec8_tagged_hash("KeyAgg list", &list);
+  let mut coefficients = Vec::with_capacity(keys.len());
+  let mut weighted = Vec::with_capacity(keys.len());
+  for (k, ser) in keys.iter().zip(serialised.iter()) {
+    let a = sec8_scalar_mod_n(sec8_tagged_hash("KeyAgg coefficient", &[&l, ser]));
+    weighted.push(sec8_mul_point(k, &a)?);
+    coefficients.push(a);
+  }
+  let refs: Vec<&PublicKey> = weighted.iter().collect();
+  let agg_key = PublicKey::combine_keys(&refs)?;
+  Ok(MusigKeyAgg { keys, coefficients, agg_key })
+}
+
+pub fn sec8_musig_nonce() -> (MusigSecNonce, MusigPubNonce) {
+  let secp = Secp256k1::signing_only();
+  let k1 = SecretKey::new(&mut OsRng);
+  let k2 = SecretKey::new(&mut OsRng);
+  let public = MusigPubNonce {
+    r1: PublicKey::from_secret_key(&secp, &k1),
+    r2: PublicKey::from_secret_key(&secp, &k2),
+  };
+  (MusigSecNonce { k1, k2 }, public)
+}
+
+pub fn sec8_musig_session(agg: &MusigKeyAgg, nonces: &[MusigPubNonce], msg: &[u8]) -> Result<MusigSession> {
+  if nonces.len() != agg.keys.len() {
+    bail!("expected {} nonces got {}", agg.keys.len(), nonces.len());
+  }
+  let r1s: Vec<&PublicKey> = nonces.iter().map(|n| &n.r1).collect();
+  let r2s: Vec<&PublicKey> = nonces.iter().map(|n| &n.r2).collect();
+  let agg_nonce = MusigPubNonce {
+    r1: PublicKey::combine_keys(&r1s)?,
+    r2: PublicKey::combine_keys(&r2s)?,
+  };
+  let msg = sec8_schnorr_digest(msg);
+  let qx = agg.x_only().serialize();
+  let b = sec8_scalar_mod_n(sec8_tagged_hash(
+    "MuSig/noncecoef",
+    &[&agg_nonce.r1.serialize(), &agg_nonce.r2.serialize(), &qx, msg.as_ref()],
+  ));
+  let r = agg_nonce.r1.combine(&sec8_mul_point(&agg_nonce.r2, &b)?)?;
+  let rx = r.x_only_public_key().0.serialize();
+  let e = sec8_scalar_mod_n(sec8_tagged_hash("BIP0340/challenge", &[&rx, &qx, msg.as_ref()]));
+  Ok(MusigSession { agg_nonce, b, e, r, msg })
+}
+
+// s_i = k1 + b*k2 + e*a_i*x_i with k negated when R has an
+// odd y and x negated when the aggregate key has an odd y
+pub fn sec8_musig_partial_sign(
+  agg: &MusigKeyAgg,
+  session: &MusigSession,
+  secnonce: MusigSecNonce,
+  secret: &SecretKey,
+) -> Result<Scalar> {
+  let secp = Secp256k1::signing_only();
+  let a = agg.coefficient(&PublicKey::from_secret_key(&secp, secret))?;
+  let (mut k1, mut k2) = (secnonce.k1, secnonce.k2);
+  if session.r.x_only_public_key().1 == Parity::Odd {
+    k1 = k1.negate();
+    k2 = k2.negate();
+  }
+  let mut d = *secret;
+  if agg.parity() == Parity::Odd {
+    d = d.negate();
+  }
+  let bk2 = k2.mul_tweak(&session.b)?;
+  let ead = d.mul_tweak(&a)?.mul_tweak(&session.e)?;
+  let s = k1.add_tweak(&Scalar::from(bk2))?.add_tweak(&Scalar::from(ead))?;
+  Ok(Scalar::from(s))
+}
+
+// lets the aggregator blame a signer whose share is bad
+pub fn sec8_musig_partial_verify(
+  agg: &MusigKeyAgg,
+  session: &MusigSession,
+  nonce: &MusigPubNonce,
+  public: &PublicKey,
+  partial: &Scalar,
+) -> Result<()> {
+  let secp = Secp256k1::new();
+  let a = agg.coefficient(public)?;
+  let mut r = nonce.r1.combine(&sec8_mul_point(&nonce.r2, &session.b)?)?;
+  if session.r.x_only_public_key().1 == Parity::Odd {
+    r = r.negate(&secp);
+  }
+  let mut p = *public;
+  if agg.parity() == Parity::Odd {
+    p = p.negate(&secp);
+  }
+  let ea = SecretKey::from_slice(&a.to_be_bytes())?.mul_tweak(&session.e)?;
+  let expected = r.combine(&sec8_mul_point(&p, &Scalar::from(ea))?)?;
+  let lhs = PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&partial.to_be_bytes())?);
+  if lhs != expected {
+    bail!("invalid musig partial signature from {}", public);
+  }
+  Ok(())
+}
+
+pub fn sec8_musig_aggregate(session: &MusigSession, partials: &[Scalar]) -> Result<SchnorrSignature> {
+  let (first, rest) = partials.split_first().ok_or_else(|| anyhow!("no partial signatures"))?;
+  let mut s = SecretKey::from_slice(&first.to_be_bytes())?;
+  for p in rest {
+    s = s.add_tweak(p)?;
+  }
+  let mut sig = [0u8; 64];
+  sig[..32].copy_from_slice(&session.r.x_only_public_key().0.serialize());
+  sig[32..].copy_from_slice(&s.secret_bytes());
+  Ok(SchnorrSignature::from_slice(&sig)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hex32(s: &str) -> [u8; 32] {
+    hex::decode(s).unwrap().try_into().unwrap()
+  }
+
+  // (secret, public, aux, msg, sig) from the bip-340 test vectors
+  const BIP340_VECTORS: [(&str, &str, &str, &str, &str); 2] = [
+    (
+      "0000000000000000000000000000000000000000000000000000000000000003",
+      "F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+      "0000000000000000000000000000000000000000000000000000000000000000",
+      "0000000000000000000000000000000000000000000000000000000000000000",
+      "E907831F80848D1069A5371B402410364BDF1C5F8307B0084C55F1CE2DCA821525F66A4A85EA8B71E482A74F382D2CE5EBEEE8FDB2172F477DF4900D310536C0",
+    ),
+    (
+      "B7E151628AED2A6ABF7158809CF4F3C762E7160F38B4DA56A784D9045190CFEF",
+      "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+      "0000000000000000000000000000000000000000000000000000000000000001",
+      "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+      "6896BD60EEAE296DB48A229FF71DFE071BDE413E6D43F917DC8DCF8C78DE33418906D11AC976ABCCB20B091292BFF4EA897EFCB639EA871CFA95F6DE339E4B0A",
+    ),
+  ];
+
+  #[test]
+  fn schnorr_matches_the_bip340_vectors() {
+    let secp = Secp256k1::new();
+    for (secret, public, aux, msg, sig) in BIP340_VECTORS {
+      let keypair = Keypair::from_seckey_slice(&secp, &hex::decode(secret).unwrap()).unwrap();
+      let public = XOnlyPublicKey::from_slice(&hex::decode(public).unwrap()).unwrap();
+      assert_eq!(keypair.x_only_public_key().0, public);
+      let msg = Message::from_digest(hex32(msg));
+      let signed = secp.sign_schnorr_with_aux_rand(&msg, &keypair, &hex32(aux));
+      assert_eq!(hex::encode_upper(signed.as_ref()), sig);
+      secp.verify_schnorr(&signed, &msg, &public).unwrap();
+    }
+  }
+
+  #[test]
+  fn schnorr_signs_and_verifies_any_message() {
+    let keypair = sec8_schnorr_keypair().unwrap();
+    let public = keypair.x_only_public_key().0;
+    let sig = sec8_schnorr_sign(&keypair, b"block data");
+    sec8_schnorr_verify(&public, b"block data", &sig).unwrap();
+    assert!(sec8_schnorr_verify(&public, b"other data", &sig).is_err());
+  }
+
+  #[test]
+  fn verify_all_reports_the_first_bad_signature() {
+    let keypair = sec8_schnorr_keypair().unwrap();
+    let public = keypair.x_only_public_key().0;
+    // big enough to take the threaded path
+    let mut batch: Vec<_> = (0..SEC8_PARALLEL_MIN * 2)
+      .map(|i| {
+        let msg = i.to_be_bytes().to_vec();
+        let sig = sec8_schnorr_sign(&keypair, &msg);
+        (public, msg, sig)
+      })
+      .collect();
+    sec8_schnorr_verify_all(&batch).unwrap();
+    sec8_schnorr_verify_all(&batch[..3]).unwrap();
+    batch[100].1 = b"tampered".to_vec();
+    batch[120].1 = b"tampered".to_vec();
+    let err = sec8_schnorr_verify_all(&batch).unwrap_err();
+    assert_eq!(err.to_string(), "invalid schnorr signature at index 100");
+  }
+
+  #[test]
+  fn one_bad_signature_fails_the_whole_combination() {
+    let keypairs: Vec<Keypair> = (0..4).map(|_| sec8_schnorr_keypair().unwrap()).collect();
+    let mut batch: Vec<_> = (0..SEC8_PARALLEL_MIN * 4)
+      .map(|i| {
+        let keypair = &keypairs[i % keypairs.len()];
+        let msg = i.to_be_bytes().to_vec();
+        let sig = sec8_schnorr_sign(keypair, &msg);
+        (keypair.x_only_public_key().0, msg, sig)
+      })
+      .collect();
+    assert!(sec8_schnorr_batch_check(&batch).unwrap());
+    // a signature under the wrong key
+    batch[201].0 = keypairs[0].x_only_public_key().0;
+    assert!(!sec8_schnorr_batch_check(&batch).unwrap());
+    let err = sec8_schnorr_verify_all(&batch).unwrap_err();
+    assert_eq!(err.to_string(), "invalid schnorr signature at index 201");
+  }
+
+  fn signers(n: usize) -> (Vec<SecretKey>, MusigKeyAgg) {
+    let secp = Secp256k1::new();
+    let secrets: Vec<SecretKey> = (0..n).map(|_| SecretKey::new(&mut OsRng)).collect();
+    let keys: Vec<PublicKey> = secrets.iter().map(|s| PublicKey::from_secret_key(&secp, s)).collect();
+    let agg = sec8_musig_key_agg(&keys).unwrap();
+    (secrets, agg)
+  }
+
+  #[test]
+  fn musig_signatures_verify_as_plain_schnorr() {
+    let secp = Secp256k1::new();
+    let (secrets, agg) = signers(3);
+    let (secnonces, nonces): (Vec<_>, Vec<_>) = secrets.iter().map(|_| sec8_musig_nonce()).unzip();
+    let session = sec8_musig_session(&agg, &nonces, b"spend").unwrap();
+    let partials: Vec<Scalar> = secrets
+      .iter()
+      .zip(secnonces)
+      .zip(&nonces)
+      .map(|((secret, secnonce), nonce)| {
+        let partial = sec8_musig_partial_sign(&agg, &session, secnonce, secret).unwrap();
+        let public = PublicKey::from_secret_key(&secp, secret);
+        sec8_musig_partial_verify(&agg, &session, nonce, &public, &partial).unwrap();
+        partial
+      })
+      .collect();
+    let sig = sec8_musig_aggregate(&session, &partials).unwrap();
+    sec8_schnorr_verify(&agg.x_only(), b"spend", &sig).unwrap();
+    assert!(sec8_schnorr_verify(&agg.x_only(), b"other", &sig).is_err());
+  }
+
+  #[test]
+  fn musig_blames_a_bad_partial() {
+    let secp = Secp256k1::new();
+    let (secrets, agg) = signers(2);
+    let (mut secnonces, nonces): (Vec<_>, Vec<_>) = secrets.iter().map(|_| sec8_musig_nonce()).unzip();
+    let session = sec8_musig_session(&agg, &nonces, b"spend").unwrap();
+    let second = secnonces.pop().unwrap();
+    let first = secnonces.pop().unwrap();
+    let good = sec8_musig_partial_sign(&agg, &session, first, &secrets[0]).unwrap();
+    // signer 1 signs with signer 0's nonce slot
+    let bad = sec8_musig_partial_sign(&agg, &session, second, &secrets[1]).unwrap();
+    let public = PublicKey::from_secret_key(&secp, &secrets[0]);
+    assert!(sec8_musig_partial_verify(&agg, &session, &nonces[0], &public, &bad).is_err());
+    sec8_musig_partial_verify(&agg, &session, &nonces[0], &public, &good).unwrap();
+    let outsider = PublicKey::from_secret_key(&secp, &SecretKey::new(&mut OsRng));
+    assert!(sec8_musig_partial_verify(&agg, &session, &nonces[0], &outsider, &good).is_err());
+    let sig = sec8_musig_aggregate(&session, &[good, good]).unwrap();
+    assert!(sec8_schnorr_verify(&agg.x_only(), b"spend", &sig).is_err());
+  }
+
+  #[test]
+  fn musig_key_agg_ignores_key_order() {
+    let (_, agg) = signers(3);
+    let mut reversed = agg.keys.clone();
+    reversed.reverse();
+    assert_eq!(sec8_musig_key_agg(&reversed).unwrap().agg_key, agg.agg_key);
+    assert!(sec8_musig_key_agg(&[]).is_err());
+  }
+
+  #[test]
+  fn block_data_opens_for_each_recipient_only() {
+    let secp = Secp256k1::new();
+    let (alice, bob, eve) = (SecretKey::new(&mut OsRng), SecretKey::new(&mut OsRng), SecretKey::new(&mut OsRng));
+    let recipients = [PublicKey::from_secret_key(&secp, &alice), PublicKey::from_secret_key(&secp, &bob)];
+    let envelope = sec8_encrypt_block_data(&recipients, b"private transfer").unwrap();
+    assert_eq!(sec8_decrypt_block_data(&alice, &envelope).unwrap(), b"private transfer");
+    assert_eq!(sec8_decrypt_block_data(&bob, &envelope).unwrap(), b"private transfer");
+    assert!(sec8_decrypt_block_data(&eve, &envelope).is_err());
+  }
+
+  #[test]
+  fn a_tampered_envelope_does_not_open() {
+    let secp = Secp256k1::new();
+    let (alice, bob) = (SecretKey::new(&mut OsRng), SecretKey::new(&mut OsRng));
+    let recipients = [PublicKey::from_secret_key(&secp, &alice), PublicKey::from_secret_key(&secp, &bob)];
+    let envelope = sec8_encrypt_block_data(&recipients, b"private transfer").unwrap();
+    let mut flipped = envelope.clone();
+    let mut ciphertext = hex::decode(&flipped.ciphertext).unwrap();
+    ciphertext[0] ^= 1;
+    flipped.ciphertext = hex::encode(ciphertext);
+    assert!(sec8_decrypt_block_data(&alice, &flipped).is_err());
+    // bob's wrapped key relabelled as alice's
+    let mut moved = envelope.clone();
+    moved.recipients[1].public = moved.recipients[0].public.clone();
+    moved.recipients.swap(0, 1);
+    assert!(sec8_decrypt_block_data(&alice, &moved).is_err());
+    let mut future = envelope;
+    future.version += 1;
+    assert!(sec8_decrypt_block_data(&alice, &future).is_err());
+    assert!(sec8_encrypt_block_data(&[], b"nobody").is_err());
+  }
 }
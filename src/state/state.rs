@@ -0,0 +1,158 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::address::address::Address;
+use crate::block::block::Block;
+use crate::chainspec::chainspec::ChainSpec;
+use crate::multisig::multisig::MultisigAccount;
+use crate::transaction::transaction::{BlockEntry, Transaction};
+
+/// Account balances and nonces, keyed by address
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct State {
+    pub balances: HashMap<Address, u32>,
+    pub nonces: HashMap<Address, u64>,
+    // multisig accounts registered on chain
+    #[serde(default)]
+    pub multisig: HashMap<Address, MultisigAccount>,
+}
+
+impl State {
+    pub fn new() -> Self {
+        State::default()
+    }
+
+    pub fn genesis(spec: &ChainSpec) -> Result<State> {
+        let mut state = State::default();
+        for (addr, amount) in &spec.allocations {
+            state.credit(addr, *amount)?;
+        }
+        Ok(state)
+    }
+
+    pub fn balance(&self, addr: &Address) -> u32 {
+        self.balances.get(addr).copied().unwrap_or(0)
+    }
+
+    pub fn nonce(&self, addr: &Address) -> u64 {
+        self.nonces.get(addr).copied().unwrap_or(0)
+    }
+
+    pub fn credit(&mut self, addr: &Address, amount: u32) -> Result<()> {
+        let bal = self.balances.entry(*addr).or_insert(0);
+        *bal = match bal.checked_add(amount) {
+            Some(x) => x,
+            None => bail!("balance overflow for {}", addr),
+        };
+        Ok(())
+    }
+
+    // moves funds and bumps the sender nonce, nothing
+    // changes if the sender can't cover the amount
+    pub fn transfer(&mut self, from: &Address, to: &Address, amount: u32) -> Result<()> {
+        let from_bal = self.balance(from);
+        if from_bal < amount {
+            bail!("insufficient balance: {} has {} needs {}", from, from_bal, amount);
+        }
+        if from != to {
+            self.balance(to)
+                .checked_add(amount)
+                .ok_or_else(|| anyhow::anyhow!("balance overflow for {}", to))?;
+            self.balances.insert(*from, from_bal - amount);
+            self.credit(to, amount)?;
+        }
+        *self.nonces.entry(*from).or_insert(0) += 1;
+        Ok(())
+    }
+
+    // a signed transfer only applies at the
+    // sender's next nonce, replays are rejected
+    // a multisig sender has no key of its own, its
+    // members sign instead
+    pub fn verify_transaction(&self, tx: &Transaction) -> Result<()> {
+        match self.multisig.get(&tx.tx_from) {
+            Some(ms) => ms.verify_transaction(tx),
+            None => tx.verify(),
+        }
+    }
+
+    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<()> {
+        self.verify_transaction(tx)?;
+        let expected = self.nonce(&tx.tx_from);
+        if tx.tx_nonce != expected {
+            bail!("bad nonce for {}: expected {} got {}", tx.tx_from, expected, tx.tx_nonce);
+        }
+        self.transfer(&tx.tx_from, &tx.tx_to, tx.tx_amount)
+    }
+
+    pub fn register_multisig(&mut self, ms: &MultisigAccount) -> Result<Address> {
+        ms.check()?;
+        let addr = ms.address();
+        if self.multisig.contains_key(&addr) {
+            bail!("multisig {} already registered", addr);
+        }
+        self.multisig.insert(addr, ms.clone());
+        Ok(addr)
+    }
+
+    pub fn apply_entry(&mut self, entry: &BlockEntry) -> Result<()> {
+        match entry {
+            BlockEntry::Transfer(tx) => self.apply_transaction(tx),
+            BlockEntry::RegisterMultisig(ms) => self.register_multisig(ms).map(|_| ()),
+            BlockEntry::Private(_) | BlockEntry::Record { .. } => Ok(()),
+        }
+    }
+
+    // all or nothing
+    pub fn apply_block(&mut self, block: &Block) -> Result<()> {
+        let mut next = self.clone();
+        for entry in &block.block_data {
+            next.apply_entry(entry)?;
+        }
+        *self = next;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::account::Account;
+    use crate::multisig::multisig::PartiallySignedTx;
+    use crate::transaction::transaction::TransactionPool;
+
+    #[test]
+    fn admits_multisig_txs_signed_by_their_members() {
+        let (a, b) = (Account::create().unwrap(), Account::create().unwrap());
+        let to = Account::create().unwrap().address();
+        let ms = MultisigAccount::new(2, &[a.acc_public, b.acc_public]).unwrap();
+        let mut state = State::new();
+        let addr = state.register_multisig(&ms).unwrap();
+        state.credit(&addr, 10).unwrap();
+
+        let mut psbt = PartiallySignedTx::new(ms, Transaction::unsigned(addr, to, String::new(), 4, 0)).unwrap();
+        psbt.sign(&a).unwrap();
+        psbt.sign(&b).unwrap();
+        let tx = psbt.finalize().unwrap();
+        // no key of its own, a plain check can't pass
+        assert!(tx.verify().is_err());
+        state.verify_transaction(&tx).unwrap();
+        let mut pool = TransactionPool::default();
+        pool.admit(&state, tx.clone()).unwrap();
+        state.apply_transaction(&tx).unwrap();
+        assert_eq!(state.balance(&to), 4);
+        assert_eq!(state.nonce(&addr), 1);
+    }
+
+    #[test]
+    fn rejects_a_tx_signed_by_someone_else() {
+        let (alice, mallory) = (Account::create().unwrap(), Account::create().unwrap());
+        let mut state = State::new();
+        state.credit(&alice.address(), 10).unwrap();
+        let mut tx = Transaction::new(&mallory, mallory.address(), String::new(), 4, 0).unwrap();
+        tx.tx_from = alice.address();
+        assert!(state.verify_transaction(&tx).is_err());
+        assert!(TransactionPool::default().admit(&state, tx).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::Result;
+use std::fs;
+use std::io::{Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// Held by whoever writes to a data dir, a running node
+/// keeps it until it has flushed, the OS lets go of it
+/// when the process dies
+#[derive(Debug)]
+pub struct DirLock {
+    file: fs::File,
+}
+
+impl DirLock {
+    // None when someone else holds it
+    pub fn try_acquire<P: AsRef<Path>>(path: P) -> Result<Option<DirLock>> {
+        let file = fs::File::options()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(DirLock { file })),
+            Err(fs::TryLockError::WouldBlock) => Ok(None),
+            Err(fs::TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+
+    // what the holder wants others to know, like where
+    // to reach it, readable while the lock is held
+    pub fn record(&mut self, info: &str) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        self.file.write_all(info.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+// written next to the target, synced and renamed over
+// it so a crash leaves either the old file or the new one
+pub fn write_atomic<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    let tmp = temp_path(path);
+    let written = fs::File::create(&tmp).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    sync_dir(path)
+}
+
+// unique per writer, two processes saving the same file
+// or files sharing a stem never share a temp file
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = format!(".{}.{}.{:016x}.tmp", name, std::process::id(), rand::random::<u64>());
+    path.with_file_name(tmp)
+}
+
+// the rename is only durable once the directory
+// holding it has been synced as well
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("essex-storage-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn replaces_the_file_and_leaves_no_temp_behind() {
+        let dir = scratch_dir();
+        let path = dir.join("mempool.json");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        let left: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(left.len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_dir_lock_has_one_holder_at_a_time() {
+        let dir = scratch_dir();
+        let path = dir.join("LOCK");
+        let mut lock = DirLock::try_acquire(&path).unwrap().unwrap();
+        assert!(DirLock::try_acquire(&path).unwrap().is_none());
+        lock.record("{\"pid\":1}").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"pid\":1}");
+        drop(lock);
+        assert!(DirLock::try_acquire(&path).unwrap().is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn temp_names_are_unique_per_write() {
+        let path = Path::new("/data/mempool.json");
+        let (a, b) = (temp_path(path), temp_path(path));
+        assert_ne!(a, b);
+        assert_eq!(a.parent(), path.parent());
+        // a file sharing the stem gets a different name
+        assert!(!temp_path(Path::new("/data/mempool.tmp")).ends_with("mempool.tmp"));
+    }
+}
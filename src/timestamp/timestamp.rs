@@ -0,0 +1,228 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::block::block::Block;
+use crate::chainspec::chainspec::ChainSpec;
+
+// fewer peer samples than this and the local clock is used as is
+pub const MIN_CLOCK_SAMPLES: usize = 5;
+// peers claiming our clock is further off than this are
+// more likely wrong than we are, the offset is ignored
+pub const MAX_CLOCK_ADJUST_MS: i64 = 70 * 60 * 1000;
+// oldest samples are dropped past this many peers
+pub const MAX_CLOCK_SAMPLES: usize = 200;
+
+/// Consensus time, milliseconds since the unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    // local wall clock, a clock set before 1970
+    // reads as the epoch instead of panicking
+    pub fn now() -> Timestamp {
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Timestamp(since.as_millis() as u64)
+    }
+
+    pub fn from_millis(ms: u64) -> Timestamp {
+        Timestamp(ms)
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.0
+    }
+
+    pub fn saturating_add(&self, ms: u64) -> Timestamp {
+        Timestamp(self.0.saturating_add(ms))
+    }
+
+    pub fn offset_by(&self, ms: i64) -> Timestamp {
+        Timestamp(self.0.saturating_add_signed(ms))
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}ms", self.0)
+    }
+}
+
+// median of the last `span` block timestamps, a new
+// block has to be later than this so a single validator
+// can't drag the chain time backwards
+pub fn median_time_past(chain: &[Block], span: usize) -> Option<Timestamp> {
+    if chain.is_empty() || span == 0 {
+        return None;
+    }
+    let start = chain.len().saturating_sub(span);
+    let mut times: Vec<Timestamp> = chain[start..].iter().map(|b| b.timestamp).collect();
+    times.sort();
+    Some(times[times.len() / 2])
+}
+
+// a block must be after the median time past of the
+// blocks before it and not too far ahead of `now`
+pub fn check_block_time(block: &Block, ancestors: &[Block], now: Timestamp, spec: &ChainSpec) -> Result<()> {
+    check_median_time(block, ancestors, spec)?;
+    check_future_drift(block, now, spec)
+}
+
+pub fn check_median_time(block: &Block, ancestors: &[Block], spec: &ChainSpec) -> Result<()> {
+    if let Some(mtp) = median_time_past(ancestors, spec.median_time_span) {
+        if block.timestamp <= mtp {
+            bail!("block time {} not after median time past {}", block.timestamp, mtp);
+        }
+    }
+    Ok(())
+}
+
+// `now` should be network time, see NetworkClock
+pub fn check_future_drift(block: &Block, now: Timestamp, spec: &ChainSpec) -> Result<()> {
+    let limit = now.saturating_add(spec.max_future_drift_ms);
+    if block.timestamp > limit {
+        bail!("block time {} too far in the future (limit {})", block.timestamp, limit);
+    }
+    Ok(())
+}
+
+/// Local clock corrected by the median offset peers report,
+/// so a node with a skewed clock still agrees with the
+/// network on which blocks are from the future
+#[derive(Debug, Default)]
+pub struct NetworkClock {
+    offsets: HashMap<String, i64>,
+    order: Vec<String>,
+}
+
+impl NetworkClock {
+    pub fn new() -> Self {
+        NetworkClock::default()
+    }
+
+    // one sample per peer, a peer sending again replaces its old one,
+    // peer times come off the wire so anything out of adjusting
+    // range is dropped before it can skew the median
+    pub fn add_sample(&mut self, peer: &str, peer_time: Timestamp) {
+        let offset = peer_time.0 as i128 - Timestamp::now().0 as i128;
+        if offset.abs() > MAX_CLOCK_ADJUST_MS as i128 {
+            log::debug!("dropping clock sample {} from {}", peer_time, peer);
+            return;
+        }
+        let offset = offset as i64;
+        if self.offsets.insert(peer.to_string(), offset).is_none() {
+            self.order.push(peer.to_string());
+            if self.order.len() > MAX_CLOCK_SAMPLES {
+                let oldest = self.order.remove(0);
+                self.offsets.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn remove_peer(&mut self, peer: &str) {
+        self.offsets.remove(peer);
+        self.order.retain(|x| x != peer);
+    }
+
+    pub fn offset(&self) -> i64 {
+        if self.offsets.len() < MIN_CLOCK_SAMPLES {
+            return 0;
+        }
+        let mut offsets: Vec<i64> = self.offsets.values().copied().collect();
+        offsets.sort();
+        let median = offsets[offsets.len() / 2];
+        if median.abs() > MAX_CLOCK_ADJUST_MS {
+            log::warn!("peers report clock offset of {}ms, check the local clock", median);
+            return 0;
+        }
+        median
+    }
+
+    pub fn now(&self) -> Timestamp {
+        Timestamp::now().offset_by(self.offset())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_at(ms: u64) -> Block {
+        Block {
+            timestamp: Timestamp::from_millis(ms),
+            ..Block::default()
+        }
+    }
+
+    #[test]
+    fn median_time_past_uses_the_last_span_blocks() {
+        let chain: Vec<Block> = [10, 50, 20, 40, 30].iter().map(|x| block_at(*x)).collect();
+        assert_eq!(median_time_past(&chain, 5), Some(Timestamp(30)));
+        assert_eq!(median_time_past(&chain, 2), Some(Timestamp(40)));
+        assert_eq!(median_time_past(&[], 5), None);
+    }
+
+    #[test]
+    fn block_time_is_checked_against_the_given_now() {
+        let spec = ChainSpec::default();
+        let ancestors = vec![block_at(1_000), block_at(2_000), block_at(3_000)];
+        assert!(check_median_time(&block_at(2_000), &ancestors, &spec).is_err());
+        assert!(check_median_time(&block_at(2_001), &ancestors, &spec).is_ok());
+        // a block far ahead of the local clock is fine when
+        // network time says it isn't from the future
+        let ahead = Timestamp::now().saturating_add(spec.max_future_drift_ms * 3);
+        let blk = block_at(ahead.as_millis());
+        assert!(check_block_time(&blk, &ancestors, Timestamp::now(), &spec).is_err());
+        let network = Timestamp::now().saturating_add(spec.max_future_drift_ms * 2);
+        assert!(check_block_time(&blk, &ancestors, network, &spec).is_ok());
+    }
+
+    #[test]
+    fn network_clock_takes_the_median_peer_offset() {
+        let mut clock = NetworkClock::new();
+        let now = Timestamp::now();
+        for (i, offset) in [1_000i64, 2_000, 3_000, 4_000].iter().enumerate() {
+            clock.add_sample(&format!("peer{}", i), now.offset_by(*offset));
+        }
+        // too few samples to trust
+        assert_eq!(clock.offset(), 0);
+        clock.add_sample("peer4", now.offset_by(5_000));
+        let offset = clock.offset();
+        assert!((2_900..=3_100).contains(&offset), "offset {}", offset);
+        // a peer sending again replaces its sample
+        clock.add_sample("peer4", now.offset_by(5_000));
+        assert_eq!(clock.offsets.len(), 5);
+        clock.remove_peer("peer4");
+        assert_eq!(clock.offset(), 0);
+    }
+
+    #[test]
+    fn network_clock_ignores_an_implausible_offset() {
+        let mut clock = NetworkClock::new();
+        let now = Timestamp::now();
+        for i in 0..MIN_CLOCK_SAMPLES {
+            clock.add_sample(&format!("peer{}", i), now.offset_by(MAX_CLOCK_ADJUST_MS * 2));
+        }
+        assert_eq!(clock.offset(), 0);
+    }
+
+    #[test]
+    fn out_of_range_peer_times_are_dropped() {
+        let mut clock = NetworkClock::new();
+        clock.add_sample("far", Timestamp(u64::MAX));
+        clock.add_sample("early", Timestamp(0));
+        assert!(clock.offsets.is_empty());
+        let now = Timestamp::now();
+        for i in 0..MIN_CLOCK_SAMPLES {
+            clock.add_sample(&format!("peer{}", i), now.offset_by(1_000));
+        }
+        clock.add_sample("far", Timestamp(u64::MAX));
+        assert_eq!(clock.offsets.len(), MIN_CLOCK_SAMPLES);
+        assert!((900..=1_100).contains(&clock.offset()));
+    }
+}
@@ -1,62 +1,187 @@
-use anyhow::{Ok, Result};
-use rsa::{RsaPrivateKey, RsaPublicKey};
-use secp256k1::{PublicKey, SecretKey};
-use std::time::SystemTime;
+use anyhow::{bail, Ok, Result};
+use secp256k1::hashes::{sha256, Hash};
+use secp256k1::{ecdsa::Signature, PublicKey};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 
-use crate::{account::account::Account, block::block::Block};
+use crate::{
+    account::account::{Account, TX_DOMAIN},
+    address::address::Address,
+    codec::codec::{Encoder, CODEC_VERSION},
+    multisig::multisig::{MultisigAccount, PartialSignature},
+    payload::payload::PrivatePayload,
+    state::state::State,
+    storage::storage,
+    timestamp::timestamp::Timestamp,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TxHeader {
-    pub transaction_id: RsaPrivateKey,
-    pub transaction_hash: RsaPublicKey,
+    // sha256 of the signed payload
+    pub transaction_id: String,
+    // sender signature over the payload
+    pub transaction_signature: String,
     pub transaction_valid: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
-    pub tx_user: Account,
-    pub timestamp: SystemTime,
+    pub tx_from: Address,
+    pub tx_to: Address,
+    // public key behind tx_from
+    pub tx_public: String,
+    pub tx_nonce: u64,
+    pub timestamp: Timestamp,
     pub tx_header: TxHeader,
-    pub tx_block: Block,
+    // the head the sender saw, only its hash is kept
+    pub tx_block_hash: String,
     pub tx_amount: u32,
+    // member signatures when tx_from is a multisig
+    #[serde(default)]
+    pub tx_signatures: Vec<PartialSignature>,
 }
 
-#[derive(Debug)]
+/// What a block_data entry can carry, entries go
+/// through the binary codec so a block commits to the
+/// same bytes on every node, see codec::Encode
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum BlockEntry {
+    Transfer(Box<Transaction>),
+    RegisterMultisig(MultisigAccount),
+    Private(PrivatePayload),
+    // free form data that doesn't touch state
+    Record { data: String },
+}
+
+impl BlockEntry {
+    pub fn record(data: &str) -> BlockEntry {
+        BlockEntry::Record {
+            data: data.to_string(),
+        }
+    }
+}
+
+// mempool bounds unless the config says otherwise
+pub const MAX_POOL_TXS: usize = 4096;
+pub const MAX_POOL_TXS_PER_SENDER: usize = 64;
+
+/// Txs waiting for a block, in the order they came in,
+/// each sender's in nonce order
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct TransactionPool {
     pub transactions: Vec<Transaction>,
+    // what each sender has queued, kept alongside so a new
+    // tx is checked without replaying the sender's queue
+    #[serde(skip)]
+    senders: HashMap<Address, Queued>,
+    #[serde(skip)]
+    ids: HashSet<String>,
+    #[serde(skip)]
+    limits: PoolLimits,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Queued {
+    count: u64,
+    // leaves the sender's balance, txs to itself don't
+    spent: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PoolLimits {
+    max_txs: usize,
+    max_per_sender: usize,
+}
+
+impl Default for PoolLimits {
+    fn default() -> Self {
+        PoolLimits {
+            max_txs: MAX_POOL_TXS,
+            max_per_sender: MAX_POOL_TXS_PER_SENDER,
+        }
+    }
 }
 
 impl Transaction {
     pub fn new(
-        user: Account,
-        user_secret: SecretKey,
-        blockdata: Block,
+        user: &Account,
+        to: Address,
+        block_hash: String,
         amount: u32,
+        nonce: u64,
     ) -> Result<Transaction> {
-        let secp = secp256k1::Secp256k1::new();
-        let user_ref = PublicKey::from_secret_key(&secp, &user_secret);
-        if !user.acc_public.eq(&user_ref) || user.acc_balance.ge(&amount) {
-            log::error!("invalid secret key : {:?}", user_secret);
+        if user.acc_balance < amount {
             log::error!("insufficient balance: {:?}", user.acc_balance);
-            println!("invalid secret key or insufficient balance");
-        }
-        let mut rng = rand::thread_rng();
-        let bit_size = 512;
-        let tx_id = RsaPrivateKey::new(&mut rng, bit_size).unwrap();
-        let tx_hash = RsaPublicKey::from(&tx_id);
-        let txheader = TxHeader {
-            transaction_id: tx_id,
-            transaction_hash: tx_hash,
-            transaction_valid: true,
-        };
-        let newtx = Transaction {
-            tx_user: user,
-            timestamp: SystemTime::now(),
-            tx_header: txheader,
-            tx_block: blockdata,
+        }
+        let mut newtx = Transaction::unsigned(user.address(), to, block_hash, amount, nonce);
+        newtx.tx_public = user.acc_public.to_string();
+        let payload = newtx.signing_payload();
+        newtx.tx_header.transaction_id = sha256::Hash::hash(&payload).to_string();
+        newtx.tx_header.transaction_signature =
+            user.sign_with_domain(TX_DOMAIN, &payload).to_string();
+        Ok(newtx)
+    }
+
+    // a tx with its id set but no signature, multisig
+    // members sign it through PartiallySignedTx
+    pub fn unsigned(
+        from: Address,
+        to: Address,
+        block_hash: String,
+        amount: u32,
+        nonce: u64,
+    ) -> Transaction {
+        let mut newtx = Transaction {
+            tx_from: from,
+            tx_to: to,
+            tx_public: String::new(),
+            tx_nonce: nonce,
+            timestamp: Timestamp::now(),
+            tx_header: TxHeader {
+                transaction_id: String::new(),
+                transaction_signature: String::new(),
+                transaction_valid: true,
+            },
+            tx_block_hash: block_hash,
             tx_amount: amount,
+            tx_signatures: vec![],
         };
-        Ok(newtx)
+        newtx.tx_header.transaction_id = sha256::Hash::hash(&newtx.signing_payload()).to_string();
+        newtx
+    }
+
+    pub fn verify_id(&self) -> Result<()> {
+        if self.tx_header.transaction_id != sha256::Hash::hash(&self.signing_payload()).to_string() {
+            bail!("tx id does not match tx contents");
+        }
+        Ok(())
+    }
+
+    // canonical encoding of everything the sender signs,
+    // the header is derived from it so it stays out
+    pub fn signing_payload(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        enc.put_u8(CODEC_VERSION);
+        enc.put(&self.tx_from);
+        enc.put(&self.tx_to);
+        enc.put(&self.tx_public);
+        enc.put(&self.tx_nonce);
+        enc.put(&self.timestamp);
+        enc.put(&self.tx_block_hash);
+        enc.put(&self.tx_amount);
+        enc.buf
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        let public: PublicKey = self.tx_public.parse()?;
+        if !self.tx_from.matches(&public) {
+            bail!("tx key does not match sender {}", self.tx_from);
+        }
+        self.verify_id()?;
+        let payload = self.signing_payload();
+        let sig: Signature = self.tx_header.transaction_signature.parse()?;
+        Account::verify_with_domain(TX_DOMAIN, &payload, &sig, &public)
     }
 }
 
@@ -65,7 +190,7 @@ impl TransactionPool {
         let alltx = self
             .transactions
             .iter()
-            .map(|f| f.tx_block.clone())
+            .map(|f| f.tx_block_hash.clone())
             .collect::<Vec<_>>();
           log::info!("[txs]:{:?}",alltx);
           for tx in &self.transactions {
@@ -73,7 +198,267 @@ impl TransactionPool {
         }
     }
     pub fn add_to_pool(&mut self, tx:Transaction) ->Result<bool> {
+       let queued = self.senders.entry(tx.tx_from).or_default();
+       queued.count += 1;
+       if tx.tx_to != tx.tx_from {
+           queued.spent += tx.tx_amount as u64;
+       }
+       self.ids.insert(tx.tx_header.transaction_id.clone());
        self.transactions.push(tx);
        Ok(true)
     }
+
+    pub fn with_limits(max_txs: usize, max_per_sender: usize) -> TransactionPool {
+        TransactionPool {
+            limits: PoolLimits {
+                max_txs,
+                max_per_sender,
+            },
+            ..TransactionPool::default()
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Transaction> {
+        if !self.ids.contains(id) {
+            return None;
+        }
+        self.transactions
+            .iter()
+            .find(|x| x.tx_header.transaction_id == id)
+    }
+
+    // the nonce a new tx from `from` should carry, after
+    // the chain state and whatever it already has queued
+    pub fn next_nonce(&self, state: &State, from: &Address) -> u64 {
+        state.nonce(from) + self.queued(from).count
+    }
+
+    fn queued(&self, from: &Address) -> Queued {
+        self.senders.get(from).copied().unwrap_or_default()
+    }
+
+    // queues `tx` if it applies on top of the state and
+    // the sender's txs that are already waiting, a full
+    // pool makes room by evicting, see evict, and hands
+    // back the tx that went
+    pub fn admit(&mut self, state: &State, tx: Transaction) -> Result<Option<Transaction>> {
+        if self.ids.contains(&tx.tx_header.transaction_id) {
+            bail!("tx {} is already pending", tx.tx_header.transaction_id);
+        }
+        state.verify_transaction(&tx)?;
+        self.admit_verified(state, tx)
+    }
+
+    // admit for a tx whose signature is known to be good
+    fn admit_verified(&mut self, state: &State, tx: Transaction) -> Result<Option<Transaction>> {
+        let queued = self.queued(&tx.tx_from);
+        let expected = state.nonce(&tx.tx_from) + queued.count;
+        if tx.tx_nonce != expected {
+            bail!("bad nonce for {}: expected {} got {}", tx.tx_from, expected, tx.tx_nonce);
+        }
+        if queued.count as usize >= self.limits.max_per_sender {
+            bail!("{} already has {} txs pending", tx.tx_from, queued.count);
+        }
+        if tx.tx_to != tx.tx_from {
+            let balance = state.balance(&tx.tx_from) as u64;
+            let needs = queued.spent + tx.tx_amount as u64;
+            if balance < needs {
+                bail!("insufficient balance: {} has {} needs {}", tx.tx_from, balance, needs);
+            }
+            if state.balance(&tx.tx_to).checked_add(tx.tx_amount).is_none() {
+                bail!("balance overflow for {}", tx.tx_to);
+            }
+        }
+        let evicted = match self.transactions.len() >= self.limits.max_txs {
+            true => Some(self.evict(&tx.tx_from)?),
+            false => None,
+        };
+        self.add_to_pool(tx)?;
+        Ok(evicted)
+    }
+
+    // lowest priority goes first, that is the last tx of
+    // whoever has the most queued, one sender filling the
+    // pool can't push out everyone else's txs
+    fn evict(&mut self, incoming: &Address) -> Result<Transaction> {
+        let Some((sender, queued)) = self.senders.iter().max_by_key(|(_, q)| q.count) else {
+            bail!("mempool is full");
+        };
+        let (sender, queued) = (*sender, *queued);
+        // the incoming sender would be the heaviest itself
+        if self.queued(incoming).count + 1 >= queued.count {
+            bail!("mempool is full");
+        }
+        let Some(pos) = self.transactions.iter().rposition(|x| x.tx_from == sender) else {
+            bail!("mempool is full");
+        };
+        let tx = self.transactions.remove(pos);
+        self.forget(&tx);
+        Ok(tx)
+    }
+
+    fn forget(&mut self, tx: &Transaction) {
+        self.ids.remove(&tx.tx_header.transaction_id);
+        if let Some(queued) = self.senders.get_mut(&tx.tx_from) {
+            queued.count -= 1;
+            if tx.tx_to != tx.tx_from {
+                queued.spent -= tx.tx_amount as u64;
+            }
+            if queued.count == 0 {
+                self.senders.remove(&tx.tx_from);
+            }
+        }
+    }
+
+    // drops whatever no longer applies after the state
+    // moved on, like txs a new block already carried,
+    // and hands back what went and why, signatures were
+    // checked on the way in and aren't again
+    pub fn prune(&mut self, state: &State) -> Vec<(Transaction, String)> {
+        let mut dropped = vec![];
+        self.senders.clear();
+        self.ids.clear();
+        for tx in std::mem::take(&mut self.transactions) {
+            if let Err(e) = self.admit_verified(state, tx.clone()) {
+                dropped.push((tx, e.to_string()));
+            }
+        }
+        dropped
+    }
+
+    // a saved pool as it was, nothing is checked, the
+    // node admits each tx again against its own state
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<TransactionPool> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(TransactionPool::default());
+        }
+        let raw = std::fs::read_to_string(path)?;
+        let saved: TransactionPool = serde_json::from_str(&raw)?;
+        let mut pool = TransactionPool::default();
+        for tx in saved.transactions {
+            pool.add_to_pool(tx)?;
+        }
+        Ok(pool)
+    }
+
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        storage::write_atomic(path, serde_json::to_string(self)?.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chainspec::chainspec::ChainSpec;
+
+    fn funded(balance: u32) -> (Account, State) {
+        let acc = Account::create().unwrap();
+        let mut spec = ChainSpec::default();
+        spec.allocations.insert(acc.address(), balance);
+        let state = State::genesis(&spec).unwrap();
+        (acc, state)
+    }
+
+    fn send(from: &Account, amount: u32, nonce: u64) -> Transaction {
+        let to = Account::create().unwrap().address();
+        Transaction::new(from, to, String::new(), amount, nonce).unwrap()
+    }
+
+    #[test]
+    fn a_signed_tx_verifies_until_it_is_touched() {
+        let (acc, _) = funded(0);
+        let tx = send(&acc, 5, 0);
+        tx.verify().unwrap();
+        let mut more = tx.clone();
+        more.tx_amount = 50;
+        assert!(more.verify().is_err());
+        let mut stolen = tx;
+        stolen.tx_from = Account::create().unwrap().address();
+        assert!(stolen.verify().is_err());
+    }
+
+    #[test]
+    fn the_pool_queues_txs_in_nonce_order() {
+        let (acc, state) = funded(10);
+        let mut pool = TransactionPool::default();
+        assert_eq!(pool.next_nonce(&state, &acc.address()), 0);
+        pool.admit(&state, send(&acc, 4, 0)).unwrap();
+        assert_eq!(pool.next_nonce(&state, &acc.address()), 1);
+        // a nonce gap, a reused nonce and an overdraft are refused
+        assert!(pool.admit(&state, send(&acc, 1, 2)).is_err());
+        assert!(pool.admit(&state, send(&acc, 1, 0)).is_err());
+        assert!(pool.admit(&state, send(&acc, 7, 1)).is_err());
+        let next = send(&acc, 6, 1);
+        pool.admit(&state, next.clone()).unwrap();
+        assert!(pool.admit(&state, next).is_err());
+        assert_eq!(pool.transactions.len(), 2);
+    }
+
+    #[test]
+    fn pruning_drops_what_the_chain_already_took() {
+        let (acc, mut state) = funded(10);
+        let mut pool = TransactionPool::default();
+        let (first, second) = (send(&acc, 4, 0), send(&acc, 4, 1));
+        pool.admit(&state, first.clone()).unwrap();
+        pool.admit(&state, second.clone()).unwrap();
+        state.apply_transaction(&first).unwrap();
+        let dropped = pool.prune(&state);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].0.tx_header.transaction_id, first.tx_header.transaction_id);
+        assert_eq!(pool.transactions.len(), 1);
+        assert!(pool.get(&second.tx_header.transaction_id).is_some());
+    }
+
+    #[test]
+    fn the_pool_holds_so_many_txs_per_sender_and_in_all() {
+        let (acc, mut state) = funded(100);
+        let mut pool = TransactionPool::with_limits(4, 3);
+        for nonce in 0..3 {
+            pool.admit(&state, send(&acc, 1, nonce)).unwrap();
+        }
+        let err = pool.admit(&state, send(&acc, 1, 3)).unwrap_err();
+        assert!(err.to_string().contains("already has 3"), "{}", err);
+        // a full pool makes room by dropping the last tx of
+        // whoever has the most queued
+        let others: Vec<Account> = (0..2).map(|_| Account::create().unwrap()).collect();
+        for other in &others {
+            state.credit(&other.address(), 10).unwrap();
+        }
+        assert!(pool.admit(&state, send(&others[0], 1, 0)).unwrap().is_none());
+        let evicted = pool.admit(&state, send(&others[1], 1, 0)).unwrap().unwrap();
+        assert_eq!((evicted.tx_from, evicted.tx_nonce), (acc.address(), 2));
+        assert_eq!(pool.transactions.len(), 4);
+        assert_eq!(pool.next_nonce(&state, &acc.address()), 2);
+        // nobody is ahead by enough to give way
+        assert!(pool.admit(&state, send(&others[0], 1, 1)).is_err());
+        assert_eq!(pool.transactions.len(), 4);
+    }
+
+    #[test]
+    fn queued_spending_counts_against_the_balance() {
+        let (acc, state) = funded(10);
+        let mut pool = TransactionPool::default();
+        pool.admit(&state, send(&acc, 6, 0)).unwrap();
+        assert!(pool.admit(&state, send(&acc, 5, 1)).is_err());
+        // to itself moves nothing
+        let to_self = Transaction::new(&acc, acc.address(), String::new(), 10, 1).unwrap();
+        pool.admit(&state, to_self).unwrap();
+        pool.admit(&state, send(&acc, 4, 2)).unwrap();
+    }
+
+    #[test]
+    fn a_saved_pool_loads_back() {
+        let (acc, state) = funded(10);
+        let mut pool = TransactionPool::default();
+        pool.admit(&state, send(&acc, 4, 0)).unwrap();
+        let name = format!("essex-mempool-{:016x}.json", rand::random::<u64>());
+        let path = std::env::temp_dir().join(name);
+        assert!(TransactionPool::load(&path).unwrap().transactions.is_empty());
+        pool.save(&path).unwrap();
+        let loaded = TransactionPool::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(loaded.transactions.len(), 1);
+        loaded.transactions[0].verify().unwrap();
+    }
 }
@@ -0,0 +1,231 @@
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+use sha2::Sha512;
+use std::{fmt, str::FromStr};
+use zeroize::Zeroize;
+
+type HmacSha512 = Hmac<Sha512>;
+
+// child numbers at or above this are hardened
+pub const HARDENED: u32 = 0x8000_0000;
+// bip-32 master key hmac key
+const MASTER_KEY: &[u8] = b"Bitcoin seed";
+
+/// A path such as m/44'/1'/0'/0/3, every element
+/// is a child number with the hardened bit folded in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivationPath(pub Vec<u32>);
+
+impl FromStr for DerivationPath {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.trim().split('/');
+        if parts.next() != Some("m") {
+            bail!("derivation path must start with m: {}", s);
+        }
+        let mut path = Vec::new();
+        for part in parts {
+            let (num, hardened) = match part.strip_suffix('\'').or_else(|| part.strip_suffix('h')) {
+                Some(n) => (n, true),
+                None => (part, false),
+            };
+            let idx: u32 = num
+                .parse()
+                .with_context(|| format!("invalid path element {}", part))?;
+            if idx >= HARDENED {
+                bail!("path element out of range: {}", part);
+            }
+            path.push(if hardened { idx | HARDENED } else { idx });
+        }
+        Ok(DerivationPath(path))
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m")?;
+        for idx in &self.0 {
+            if idx & HARDENED != 0 {
+                write!(f, "/{}'", idx & !HARDENED)?;
+            } else {
+                write!(f, "/{}", idx)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Extended private key, a secp256k1 secret
+/// plus the chain code needed to derive children
+pub struct ExtendedKey {
+    pub secret: SecretKey,
+    pub chain_code: [u8; 32],
+    pub depth: u8,
+    pub child_number: u32,
+}
+
+impl fmt::Debug for ExtendedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtendedKey")
+            .field("secret", &"<redacted>")
+            .field("depth", &self.depth)
+            .field("child_number", &self.child_number)
+            .finish()
+    }
+}
+
+impl Drop for ExtendedKey {
+    fn drop(&mut self) {
+        self.secret.non_secure_erase();
+        self.chain_code.zeroize();
+    }
+}
+
+fn split_hmac(key: &[u8], data: &[u8]) -> Result<(SecretKey, [u8; 32])> {
+    let mut mac = HmacSha512::new_from_slice(key)?;
+    mac.update(data);
+    let mut out = mac.finalize().into_bytes();
+    let secret = SecretKey::from_slice(&out[..32]);
+    let mut chain_code = [0u8; 32];
+    chain_code.copy_from_slice(&out[32..]);
+    out.zeroize();
+    // an out of range key means this index is unusable,
+    // bip-32 says to move on to the next one
+    Ok((secret.context("derived key out of range")?, chain_code))
+}
+
+impl ExtendedKey {
+    pub fn master(seed: &[u8]) -> Result<ExtendedKey> {
+        if seed.len() < 16 || seed.len() > 64 {
+            bail!("seed must be between 16 and 64 bytes");
+        }
+        let (secret, chain_code) = split_hmac(MASTER_KEY, seed)?;
+        Ok(ExtendedKey {
+            secret,
+            chain_code,
+            depth: 0,
+            child_number: 0,
+        })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from_secret_key(&Secp256k1::new(), &self.secret)
+    }
+
+    pub fn derive_child(&self, index: u32) -> Result<ExtendedKey> {
+        let mut data = Vec::with_capacity(37);
+        if index & HARDENED != 0 {
+            data.push(0u8);
+            data.extend_from_slice(&self.secret.secret_bytes());
+        } else {
+            data.extend_from_slice(&self.public_key().serialize());
+        }
+        data.extend_from_slice(&index.to_be_bytes());
+        let res = split_hmac(&self.chain_code, &data);
+        data.zeroize();
+        let (tweak, chain_code) = res?;
+        let secret = self
+            .secret
+            .add_tweak(&Scalar::from(tweak))
+            .context("derived key out of range")?;
+        Ok(ExtendedKey {
+            secret,
+            chain_code,
+            depth: self.depth.checked_add(1).context("derivation too deep")?,
+            child_number: index,
+        })
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> Result<ExtendedKey> {
+        let mut key = ExtendedKey {
+            secret: self.secret,
+            chain_code: self.chain_code,
+            depth: self.depth,
+            child_number: self.child_number,
+        };
+        for idx in &path.0 {
+            key = key.derive_child(*idx)?;
+        }
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // bip-32 test vector 1, (path, chain code, private key)
+    const VECTOR_1: [(&str, &str, &str); 6] = [
+        (
+            "m",
+            "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508",
+            "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35",
+        ),
+        (
+            "m/0'",
+            "47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141",
+            "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea",
+        ),
+        (
+            "m/0'/1",
+            "2a7857631386ba23dacac34180dd1983734e444fdbf774041578e9b6adb37c19",
+            "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368",
+        ),
+        (
+            "m/0'/1/2'",
+            "04466b9cc8e161e966409ca52986c584f07e9dc81f735db683c3ff6ec7b1503f",
+            "cbce0d719ecf7431d88e6a89fa1483e02e35092af60c042b1df2ff59fa424dca",
+        ),
+        (
+            "m/0'/1/2'/2",
+            "cfb71883f01676f587d023cc53a35bc7f88f724b1f8c2892ac1275ac822a3edd",
+            "0f479245fb19a38a1954c5c7c0ebab2f9bdfd96a17563ef28a6a4b1a2a764ef4",
+        ),
+        (
+            "m/0'/1/2'/2/1000000000",
+            "c783e67b921d2beb8f6b389cc646d7263b4145701dadd2161548a8b078e65e9e",
+            "471b76e389e528d6de6d816857e012c5455051cad6660850e58372a6c3e6e7c8",
+        ),
+    ];
+
+    #[test]
+    fn derives_the_bip32_test_vector() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedKey::master(&seed).unwrap();
+        for (path, chain_code, secret) in VECTOR_1 {
+            let path: DerivationPath = path.parse().unwrap();
+            let key = master.derive_path(&path).unwrap();
+            assert_eq!(
+                hex::encode(key.chain_code),
+                chain_code,
+                "chain code at {}",
+                path
+            );
+            assert_eq!(
+                hex::encode(key.secret.secret_bytes()),
+                secret,
+                "key at {}",
+                path
+            );
+            assert_eq!(key.depth as usize, path.0.len());
+        }
+    }
+
+    #[test]
+    fn paths_round_trip_and_reject_garbage() {
+        let path: DerivationPath = "m/44'/1h/0'/0/3".parse().unwrap();
+        assert_eq!(path.0, vec![44 | HARDENED, 1 | HARDENED, HARDENED, 0, 3]);
+        assert_eq!(path.to_string(), "m/44'/1'/0'/0/3");
+        assert_eq!("m".parse::<DerivationPath>().unwrap().0, Vec::<u32>::new());
+        for bad in ["44'/0", "m/x", "m/2147483648", "m//1"] {
+            assert!(bad.parse::<DerivationPath>().is_err(), "accepted {}", bad);
+        }
+    }
+
+    #[test]
+    fn master_needs_a_sensible_seed() {
+        assert!(ExtendedKey::master(&[0u8; 15]).is_err());
+        assert!(ExtendedKey::master(&[0u8; 65]).is_err());
+    }
+}
@@ -0,0 +1,2 @@
+pub mod hd;
+pub mod wallet;
@@ -0,0 +1,195 @@
+use anyhow::{bail, Result};
+use bip39::Mnemonic;
+use rand::{rngs::OsRng, RngCore};
+use secp256k1::PublicKey;
+use std::{collections::HashSet, fmt};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::account::account::Account;
+use crate::address::address::Address;
+use crate::blockchain::blockchain::Blockchain;
+use crate::transaction::transaction::BlockEntry;
+use crate::wallet::hd::{DerivationPath, ExtendedKey, HARDENED};
+
+// slip-44 testnet coin type until essex has its own
+pub const ESSEX_COIN_TYPE: u32 = 1;
+// how many unused accounts in a row end a chain scan
+pub const GAP_LIMIT: u32 = 20;
+
+/// Deterministic wallet, every account key is derived
+/// from one mnemonic so backing up the phrase is enough
+/// to get back all of them
+pub struct Wallet {
+    mnemonic: Mnemonic,
+    master: ExtendedKey,
+}
+
+impl fmt::Debug for Wallet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Wallet")
+            .field("mnemonic", &"<redacted>")
+            .field("master", &self.master)
+            .finish()
+    }
+}
+
+impl Drop for Wallet {
+    fn drop(&mut self) {
+        self.mnemonic.zeroize();
+    }
+}
+
+impl Wallet {
+    // word_count is 12, 15, 18, 21 or 24
+    pub fn generate(word_count: usize, passphrase: &str) -> Result<Wallet> {
+        if ![12, 15, 18, 21, 24].contains(&word_count) {
+            bail!("invalid mnemonic length {}", word_count);
+        }
+        let mut entropy = Zeroizing::new([0u8; 32]);
+        let len = word_count / 3 * 4;
+        OsRng.fill_bytes(&mut entropy[..len]);
+        let mnemonic = Mnemonic::from_entropy(&entropy[..len])?;
+        Wallet::from_mnemonic(mnemonic, passphrase)
+    }
+
+    pub fn recover(phrase: &str, passphrase: &str) -> Result<Wallet> {
+        let mnemonic = Mnemonic::parse(phrase)?;
+        Wallet::from_mnemonic(mnemonic, passphrase)
+    }
+
+    fn from_mnemonic(mnemonic: Mnemonic, passphrase: &str) -> Result<Wallet> {
+        let seed = Zeroizing::new(mnemonic.to_seed(passphrase));
+        let master = ExtendedKey::master(seed.as_ref())?;
+        Ok(Wallet { mnemonic, master })
+    }
+
+    // the phrase the user has to write down
+    pub fn phrase(&self) -> Zeroizing<String> {
+        Zeroizing::new(self.mnemonic.to_string())
+    }
+
+    // m/44'/coin'/0'/0/index
+    pub fn account_path(index: u32) -> DerivationPath {
+        DerivationPath(vec![
+            44 | HARDENED,
+            ESSEX_COIN_TYPE | HARDENED,
+            HARDENED,
+            0,
+            index,
+        ])
+    }
+
+    pub fn derive_key(&self, path: &DerivationPath) -> Result<ExtendedKey> {
+        self.master.derive_path(path)
+    }
+
+    pub fn derive_account(&self, index: u32) -> Result<Account> {
+        let key = self.derive_key(&Wallet::account_path(index))?;
+        Account::from_secret(key.secret)
+    }
+
+    // walk account indices until GAP_LIMIT of them in a row
+    // never showed up on the chain, returns the used ones
+    pub fn scan(&self, chain: &Blockchain) -> Result<Vec<u32>> {
+        let seen: HashSet<Address> = chain
+            .chain
+            .iter()
+            .flat_map(|b| std::iter::once(b.validator).chain(b.block_data.iter().flat_map(entry_addresses)))
+            .collect();
+        let mut used = Vec::new();
+        let mut gap = 0;
+        let mut index = 0u32;
+        while gap < GAP_LIMIT && index < HARDENED {
+            let public = self.derive_key(&Wallet::account_path(index))?.public_key();
+            if seen.contains(&Address::from_public_key(&public)) {
+                used.push(index);
+                gap = 0;
+            } else {
+                gap += 1;
+            }
+            index += 1;
+        }
+        Ok(used)
+    }
+}
+
+// the addresses an entry shows to be in use, the
+// members of a multisig by their own keys
+fn entry_addresses(entry: &BlockEntry) -> Vec<Address> {
+    match entry {
+        BlockEntry::Transfer(tx) => vec![tx.tx_from, tx.tx_to],
+        BlockEntry::RegisterMultisig(ms) => ms
+            .keys
+            .iter()
+            .filter_map(|x| x.parse::<PublicKey>().ok())
+            .map(|x| Address::from_public_key(&x))
+            .collect(),
+        BlockEntry::Private(_) | BlockEntry::Record { .. } => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::block::Block;
+    use crate::multisig::multisig::MultisigAccount;
+    use crate::transaction::transaction::Transaction;
+
+    fn chain_of(entries: Vec<BlockEntry>) -> Blockchain {
+        let blk = Block {
+            block_data: entries,
+            ..Block::default()
+        };
+        Blockchain {
+            chain: vec![blk],
+            ..Blockchain::default()
+        }
+    }
+
+    #[test]
+    fn the_phrase_brings_back_the_same_accounts() {
+        let wallet = Wallet::generate(24, "extra").unwrap();
+        assert_eq!(wallet.phrase().split(' ').count(), 24);
+        let again = Wallet::recover(&wallet.phrase(), "extra").unwrap();
+        let other = Wallet::recover(&wallet.phrase(), "").unwrap();
+        let acc = wallet.derive_account(3).unwrap();
+        assert_eq!(again.derive_account(3).unwrap().acc_public, acc.acc_public);
+        assert_ne!(other.derive_account(3).unwrap().acc_public, acc.acc_public);
+        assert!(Wallet::generate(13, "").is_err());
+        assert!(Wallet::recover("not a real mnemonic phrase", "").is_err());
+    }
+
+    #[test]
+    fn scan_finds_accounts_in_transfers_and_multisigs() {
+        let wallet = Wallet::generate(12, "").unwrap();
+        let stranger = Account::create().unwrap();
+        let to_first = wallet.derive_account(0).unwrap();
+        let from_fifth = wallet.derive_account(5).unwrap();
+        let member = wallet.derive_account(9).unwrap();
+        let ms = MultisigAccount::new(1, &[member.acc_public, stranger.acc_public]).unwrap();
+        let chain = chain_of(vec![
+            BlockEntry::Transfer(Box::new(
+                Transaction::new(&stranger, to_first.address(), String::new(), 1, 0).unwrap(),
+            )),
+            BlockEntry::Transfer(Box::new(
+                Transaction::new(&from_fifth, stranger.address(), String::new(), 1, 0).unwrap(),
+            )),
+            BlockEntry::RegisterMultisig(ms),
+            // an address written into a record isn't a use
+            BlockEntry::record(&wallet.derive_account(1).unwrap().address().to_string()),
+        ]);
+        assert_eq!(wallet.scan(&chain).unwrap(), vec![0, 5, 9]);
+    }
+
+    #[test]
+    fn scan_stops_after_the_gap_limit() {
+        let wallet = Wallet::generate(12, "").unwrap();
+        let near = wallet.derive_account(GAP_LIMIT - 1).unwrap();
+        let far = wallet.derive_account(2 * GAP_LIMIT).unwrap();
+        let chain = chain_of(vec![
+            BlockEntry::Transfer(Box::new(Transaction::new(&near, near.address(), String::new(), 1, 0).unwrap())),
+            BlockEntry::Transfer(Box::new(Transaction::new(&far, far.address(), String::new(), 1, 0).unwrap())),
+        ]);
+        assert_eq!(wallet.scan(&chain).unwrap(), vec![GAP_LIMIT - 1]);
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::{bail, Result};
+
+use crate::codec::codec::{Encode, Encoder};
+use crate::transaction::transaction::{BlockEntry, Transaction};
+
+// fixed cost every block entry pays
+// regardless of how much data it carries
+pub const TX_BASE_WEIGHT: u64 = 400;
+// cost for each byte of an entry
+pub const TX_BYTE_WEIGHT: u64 = 4;
+// default block limit, chain spec can raise or
+// lower this from a given height onwards
+pub const MAX_BLOCK_WEIGHT: u64 = 4_000_000;
+
+/// Anything that takes up room in a block
+/// has a weight, blocks are bounded by the
+/// sum of the weights of their entries
+pub trait Weighted {
+    fn weight(&self) -> u64;
+}
+
+// what `v` takes up in a block, the codec version
+// byte is paid once per block and not per entry
+fn encoded_len<T: Encode + ?Sized>(v: &T) -> u64 {
+    let mut enc = Encoder::new();
+    enc.put(v);
+    enc.buf.len() as u64
+}
+
+fn weigh_bytes(len: u64) -> u64 {
+    TX_BASE_WEIGHT.saturating_add(len.saturating_mul(TX_BYTE_WEIGHT))
+}
+
+impl Weighted for BlockEntry {
+    fn weight(&self) -> u64 {
+        weigh_bytes(encoded_len(self))
+    }
+}
+
+impl Weighted for Transaction {
+    // the same as the entry carrying it, the tag byte
+    // in front of a transfer included
+    fn weight(&self) -> u64 {
+        weigh_bytes(encoded_len(self).saturating_add(1))
+    }
+}
+
+pub fn block_data_weight(block_data: &[BlockEntry]) -> u64 {
+    block_data
+        .iter()
+        .fold(0u64, |acc, x| acc.saturating_add(x.weight()))
+}
+
+pub fn check_block_weight(block_data: &[BlockEntry], limit: u64) -> Result<u64> {
+    let weight = block_data_weight(block_data);
+    if weight > limit {
+        bail!("block weight {} exceeds limit {}", weight, limit);
+    }
+    Ok(weight)
+}
+
+// a tx heavier than a block can hold would never leave
+// the mempool, it is turned away before it gets there
+pub fn check_tx_weight(tx: &Transaction, limit: u64) -> Result<u64> {
+    let weight = tx.weight();
+    if weight > limit {
+        bail!("tx weight {} exceeds block limit {}", weight, limit);
+    }
+    Ok(weight)
+}
+
+// take entries off the front of the pending queue until
+// the next one no longer fits, an entry heavier than the
+// whole block can never be included and is dropped
+pub fn pack_block_data(pending: &mut Vec<BlockEntry>, limit: u64) -> Vec<BlockEntry> {
+    let mut used = 0u64;
+    // whether each entry of the prefix goes in the block
+    let mut fits = Vec::new();
+    for entry in pending.iter() {
+        let w = entry.weight();
+        if w > limit {
+            log::error!("dropping entry of weight {} above block limit {}", w, limit);
+            fits.push(false);
+            continue;
+        }
+        if used + w > limit {
+            break;
+        }
+        used += w;
+        fits.push(true);
+    }
+    let n = fits.len();
+    pending
+        .drain(..n)
+        .zip(fits)
+        .filter_map(|(entry, fit)| fit.then_some(entry))
+        .collect()
+}
+
+// largest payload a peer could send us for a block
+// that still fits the limit, used to bound the decoder
+pub fn max_encoded_block_size(limit: u64) -> usize {
+    // every encoded entry byte costs TX_BYTE_WEIGHT, the
+    // header fields get a fixed allowance
+    ((limit / TX_BYTE_WEIGHT) as usize).saturating_add(64 * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::account::Account;
+    use crate::block::block::Block;
+    use crate::codec::codec;
+
+    fn record(len: usize) -> BlockEntry {
+        BlockEntry::record(&"x".repeat(len))
+    }
+
+    #[test]
+    fn a_transfer_weighs_the_same_as_its_entry() {
+        let alice = Account::create().unwrap();
+        let tx = Transaction::new(&alice, alice.address(), "head".to_string(), 1, 0).unwrap();
+        let entry = BlockEntry::Transfer(Box::new(tx.clone()));
+        assert_eq!(tx.weight(), entry.weight());
+        // a longer head hash is charged by the byte, not
+        // by anything the head block carries, unsigned so
+        // signature lengths don't get in the way
+        let short = Transaction::unsigned(alice.address(), alice.address(), "head".to_string(), 1, 0);
+        let longer = Transaction::unsigned(alice.address(), alice.address(), "head2".to_string(), 1, 0);
+        assert_eq!(longer.weight(), short.weight() + TX_BYTE_WEIGHT);
+        assert!(check_tx_weight(&tx, tx.weight()).is_ok());
+        assert!(check_tx_weight(&tx, tx.weight() - 1).is_err());
+    }
+
+    #[test]
+    fn packs_a_prefix_and_leaves_the_rest_in_order() {
+        let w = record(10).weight();
+        let mut pending: Vec<BlockEntry> = (0..5).map(|_| record(10)).collect();
+        pending.push(record(11));
+        let packed = pack_block_data(&mut pending, w * 3);
+        assert_eq!(packed.len(), 3);
+        assert_eq!(pending.len(), 3);
+        assert!(check_block_weight(&packed, w * 3).is_ok());
+        assert!(check_block_weight(&packed, w * 3 - 1).is_err());
+        assert!(matches!(pending.last(), Some(BlockEntry::Record { data }) if data.len() == 11));
+    }
+
+    #[test]
+    fn drops_entries_no_block_can_hold() {
+        let w = record(10).weight();
+        let mut pending = vec![record(10), record(1000), record(10)];
+        let packed = pack_block_data(&mut pending, w * 2);
+        assert_eq!(packed.len(), 2);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn a_full_block_fits_the_decoder_bound() {
+        let limit = 100_000;
+        let mut pending: Vec<BlockEntry> = (0..1000).map(|_| record(100)).collect();
+        let mut blk = Block {
+            block_data: pack_block_data(&mut pending, limit),
+            ..Block::default()
+        };
+        blk.sign(&Account::create().unwrap());
+        assert!(codec::to_bytes(&blk).len() <= max_encoded_block_size(limit));
+    }
+}